@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE session_revocations;
+DROP TABLE revoked_tokens;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+
+CREATE TABLE consumed_tokens (
+    jti VARCHAR(255) PRIMARY KEY,
+    purpose VARCHAR(255) NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    consumed_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE login_attempts;
+
+ALTER TABLE users
+    DROP COLUMN failed_login_count,
+    DROP COLUMN locked_until;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE privacy_settings (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    show_name BOOLEAN NOT NULL DEFAULT TRUE,
+    show_city BOOLEAN NOT NULL DEFAULT TRUE,
+    show_state BOOLEAN NOT NULL DEFAULT TRUE,
+    show_country BOOLEAN NOT NULL DEFAULT TRUE,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+
+CREATE TABLE audit_events (
+    id SERIAL PRIMARY KEY,
+    actor_id INTEGER NULL REFERENCES users(id) ON DELETE SET NULL,
+    user_id INTEGER NULL REFERENCES users(id) ON DELETE SET NULL,
+    action VARCHAR(255) NOT NULL,
+    details TEXT NULL,
+    ip VARCHAR(255) NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_events_actor_id_idx ON audit_events (actor_id);
+CREATE INDEX audit_events_user_id_idx ON audit_events (user_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE invitations;
+DROP TABLE memberships;
+DROP TABLE organizations;
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE sms_codes;
+
+DROP INDEX users_verified_phone_idx;
+
+-- email_verified stays, databases created before this migration already had it
+ALTER TABLE users
+    DROP COLUMN phone,
+    DROP COLUMN phone_verified;
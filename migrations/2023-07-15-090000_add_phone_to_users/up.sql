@@ -0,0 +1,24 @@
+-- Your SQL goes here
+
+-- email_verified was read by the code long before any migration created it
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS phone VARCHAR(32) NULL,
+    ADD COLUMN IF NOT EXISTS phone_verified BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- a number belongs to one account once it is verified
+CREATE UNIQUE INDEX users_verified_phone_idx ON users (phone) WHERE phone_verified;
+
+CREATE TABLE sms_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    phone VARCHAR(32) NOT NULL,
+    purpose VARCHAR(32) NOT NULL,
+    code_hash VARCHAR(255) NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP NOT NULL,
+    consumed_at TIMESTAMP NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX sms_codes_user_idx ON sms_codes (user_id, purpose);
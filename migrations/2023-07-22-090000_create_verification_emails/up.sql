@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+-- every verification email asked for, by address, whether or not an account uses it
+CREATE TABLE verification_emails (
+    id SERIAL PRIMARY KEY,
+    email VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX verification_emails_email_idx ON verification_emails (email, created_at);
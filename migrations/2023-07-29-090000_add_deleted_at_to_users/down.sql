@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_deleted_at_idx;
+
+ALTER TABLE users
+    DROP COLUMN deleted_at,
+    DROP COLUMN purged_at;
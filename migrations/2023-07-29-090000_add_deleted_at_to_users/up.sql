@@ -0,0 +1,8 @@
+-- Your SQL goes here
+
+-- deleted accounts are anonymized once the grace period after deleted_at is over
+ALTER TABLE users
+    ADD COLUMN deleted_at TIMESTAMP NULL,
+    ADD COLUMN purged_at TIMESTAMP NULL;
+
+CREATE INDEX users_deleted_at_idx ON users (deleted_at) WHERE deleted AND purged_at IS NULL;
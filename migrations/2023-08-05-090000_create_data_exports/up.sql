@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+CREATE TABLE data_exports (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    requested_by INTEGER NULL REFERENCES users(id) ON DELETE SET NULL,
+    zipped BOOLEAN NOT NULL DEFAULT FALSE,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    file_path VARCHAR(255) NULL,
+    token_hash VARCHAR(255) NULL UNIQUE,
+    expires_at TIMESTAMP NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    completed_at TIMESTAMP NULL
+);
+
+CREATE INDEX data_exports_user_idx ON data_exports (user_id, created_at);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+    DROP COLUMN avatar_key,
+    DROP COLUMN avatar_url,
+    DROP COLUMN avatar_thumbnail_url;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+-- avatar_key is the storage key the resized images share, {key}-256.png and {key}-64.png
+ALTER TABLE users
+    ADD COLUMN avatar_key VARCHAR NULL,
+    ADD COLUMN avatar_url VARCHAR NULL,
+    ADD COLUMN avatar_thumbnail_url VARCHAR NULL;
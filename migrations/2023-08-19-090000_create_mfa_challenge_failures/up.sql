@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+-- wrong codes entered against each mfa challenge, the challenge is burned
+-- after a few of them
+CREATE TABLE mfa_challenge_failures (
+    jti VARCHAR PRIMARY KEY,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+-- hash of the nonce cookie set in the browser that started the flow, the
+-- callback is only accepted from that browser. Pending states predate it
+DELETE FROM oauth_states;
+ALTER TABLE oauth_states ADD COLUMN nonce_hash VARCHAR(255) NOT NULL;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+-- every code texted, by number, whether or not an account uses it
+CREATE TABLE sms_sends (
+    id SERIAL PRIMARY KEY,
+    phone VARCHAR(32) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX sms_sends_phone_idx ON sms_sends (phone, created_at);
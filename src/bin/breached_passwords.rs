@@ -0,0 +1,92 @@
+// builds the bloom filter file PASSWORD_BREACHED_FILTER points to
+//
+//   cargo run --release --bin breached_passwords -- pwned-passwords-sha1.txt breached.bloom
+//
+// input lines are either SHA-1 hashes as published by Have I Been Pwned
+// (HASH or HASH:COUNT) or plain text passwords
+use drgz::bloom::{password_digest, BloomFilter};
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process;
+
+const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.001;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: breached_passwords <input> <output> [false positive rate]");
+        process::exit(1);
+    }
+    let false_positive_rate = match args.get(3) {
+        Some(rate) => rate.parse::<f64>().unwrap_or_else(|_| {
+            eprintln!("false positive rate must be a number like 0.001");
+            process::exit(1);
+        }),
+        None => DEFAULT_FALSE_POSITIVE_RATE,
+    };
+
+    // count first so the filter is sized for the list
+    let count = lines(&args[1]).count() as u64;
+    let mut filter = BloomFilter::new(count, false_positive_rate);
+    for line in lines(&args[1]) {
+        filter.insert(&digest(&line));
+    }
+    if let Err(e) = filter.write(&args[2]) {
+        eprintln!("{}: {}", args[2], e);
+        process::exit(1);
+    }
+    println!("Wrote {} passwords to {}", count, args[2]);
+}
+
+fn lines(path: &str) -> impl Iterator<Item = String> {
+    let file = File::open(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .map(|line| line.trim_end_matches(['\r', '\n']).to_string())
+        .filter(|line| !line.is_empty())
+}
+
+fn digest(line: &str) -> [u8; 20] {
+    let hash = line.split(':').next().unwrap_or("");
+    if hash.len() == 40 {
+        if let Ok(bytes) = hex::decode(hash) {
+            let mut digest = [0u8; 20];
+            digest.copy_from_slice(&bytes);
+            return digest;
+        }
+    }
+    password_digest(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::digest;
+    use drgz::bloom::password_digest;
+
+    // SHA-1 of "password"
+    const HASH: &str = "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8";
+
+    #[test]
+    fn reads_hibp_hashes_with_and_without_a_count() {
+        assert_eq!(digest(HASH), password_digest("password"));
+        assert_eq!(
+            digest(&format!("{}:3861493", HASH)),
+            password_digest("password")
+        );
+        assert_eq!(digest(&HASH.to_lowercase()), password_digest("password"));
+    }
+
+    #[test]
+    fn treats_anything_else_as_a_plain_password() {
+        assert_eq!(digest("hunter2"), password_digest("hunter2"));
+        assert_eq!(digest("hunter2:12"), password_digest("hunter2:12"));
+        // 40 characters that are not hex
+        let not_hex = "z".repeat(40);
+        assert_eq!(digest(&not_hex), password_digest(&not_hex));
+    }
+}
@@ -0,0 +1,167 @@
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+// file layout: magic, number of bits (u64 le), number of hashes (u32 le), bits
+const MAGIC: &[u8; 8] = b"DRGZBLM1";
+const HEADER_LEN: u64 = 8 + 8 + 4;
+
+// probabilistic set of SHA-1 password digests, the same digests the Have I
+// Been Pwned password lists are published as
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(expected_items: u64, false_positive_rate: f64) -> BloomFilter {
+        let items = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-items * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(8.0) as u64;
+        let num_hashes = ((num_bits as f64 / items) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(8) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&mut self, digest: &[u8; 20]) {
+        for index in self.indexes(digest) {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn contains(&self, digest: &[u8; 20]) -> bool {
+        self.indexes(digest)
+            .all(|index| self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0)
+    }
+
+    pub fn contains_password(&self, password: &str) -> bool {
+        self.contains(&password_digest(password))
+    }
+
+    // the digest is already uniformly distributed, so two halves of it give
+    // every index through double hashing
+    fn indexes(&self, digest: &[u8; 20]) -> impl Iterator<Item = u64> {
+        let h1 = u64::from_le_bytes(digest[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    pub fn read(path: &str) -> io::Result<BloomFilter> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a breached password filter",
+            ));
+        }
+        let mut num_bits = [0u8; 8];
+        file.read_exact(&mut num_bits)?;
+        let mut num_hashes = [0u8; 4];
+        file.read_exact(&mut num_hashes)?;
+        let num_bits = u64::from_le_bytes(num_bits);
+        let num_hashes = u32::from_le_bytes(num_hashes);
+
+        if num_bits == 0 || num_hashes == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "empty breached password filter",
+            ));
+        }
+        // the header decides how much is allocated, so it has to match the
+        // file before anything is
+        if HEADER_LEN.checked_add(num_bits.div_ceil(8)) != Some(file_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "breached password filter does not match its header",
+            ));
+        }
+
+        let mut bits = vec![0; num_bits.div_ceil(8) as usize];
+        file.read_exact(&mut bits)?;
+        Ok(BloomFilter {
+            bits,
+            num_bits,
+            num_hashes,
+        })
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&self.num_bits.to_le_bytes())?;
+        file.write_all(&self.num_hashes.to_le_bytes())?;
+        file.write_all(&self.bits)?;
+        file.flush()
+    }
+}
+
+pub fn password_digest(password: &str) -> [u8; 20] {
+    Sha1::digest(password.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BloomFilter, HEADER_LEN};
+    use std::fs;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("drgz-{}-{}.bloom", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn written_filters_read_back_the_same() {
+        let mut filter = BloomFilter::new(100, 0.001);
+        filter.insert(&super::password_digest("hunter2"));
+        let path = temp_path("round-trip");
+        filter.write(&path).unwrap();
+
+        let read = BloomFilter::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.num_bits, filter.num_bits);
+        assert_eq!(read.num_hashes, filter.num_hashes);
+        assert_eq!(read.bits, filter.bits);
+        assert!(read.contains_password("hunter2"));
+        assert!(!read.contains_password("correct horse battery staple"));
+    }
+
+    #[test]
+    fn refuses_a_file_shorter_than_its_header_says() {
+        let filter = BloomFilter::new(100, 0.001);
+        let path = temp_path("truncated");
+        filter.write(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+
+        let e = BloomFilter::read(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_a_header_claiming_more_bits_than_fit_in_memory() {
+        let path = temp_path("huge");
+        let mut bytes = super::MAGIC.to_vec();
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        assert_eq!(bytes.len() as u64, HEADER_LEN);
+        fs::write(&path, &bytes).unwrap();
+
+        let e = BloomFilter::read(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -4,6 +4,9 @@ use dotenvy::dotenv;
 use r2d2::Pool;
 use std::env;
 
+// sql lower(), for matching addresses in whatever case they were stored
+diesel::sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
 pub fn establish_connection() -> Pool<ConnectionManager<PgConnection>> {
     dotenv().ok();
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -13,3 +16,30 @@ pub fn establish_connection() -> Pool<ConnectionManager<PgConnection>> {
         .build(migr)
         .expect("Failed to create pool.")
 }
+
+// a pool over a single connection that never commits, for tests against the
+// database in DATABASE_URL with all migrations applied
+#[cfg(test)]
+pub fn test_pool() -> std::sync::Arc<Pool<ConnectionManager<PgConnection>>> {
+    use diesel::Connection;
+
+    #[derive(Debug)]
+    struct TestTransaction;
+
+    impl r2d2::CustomizeConnection<PgConnection, diesel::r2d2::Error> for TestTransaction {
+        fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+            connection
+                .begin_test_transaction()
+                .map_err(diesel::r2d2::Error::QueryError)
+        }
+    }
+
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(TestTransaction))
+        .build(ConnectionManager::<PgConnection>::new(database_url))
+        .expect("Failed to create pool.");
+    std::sync::Arc::new(pool)
+}
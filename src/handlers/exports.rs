@@ -0,0 +1,36 @@
+use crate::repositories::data_export::DataExportRepository;
+use actix_web::{http::header, web, HttpResponse};
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use r2d2::Pool;
+use std::fs;
+
+// the emailed link of a finished data export
+pub async fn download(
+    token: web::Path<String>,
+    pool: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> HttpResponse {
+    let repository = DataExportRepository::new(pool.into_inner());
+    let export = match repository.download(&token).await {
+        Ok(Some(export)) => export,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_e) => return HttpResponse::InternalServerError().finish(),
+    };
+    let body = match export.file_path.as_ref().map(fs::read) {
+        Some(Ok(body)) => body,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+
+    let (content_type, file_name) = if export.zipped {
+        ("application/zip", "data-export.zip")
+    } else {
+        ("application/json", "data-export.json")
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .append_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        ))
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .body(body)
+}
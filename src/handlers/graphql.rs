@@ -1,10 +1,42 @@
-use crate::middlewares::auth::AuthenticationToken;
-use crate::repositories::user::{LoginResponse, SuccessMessage, UserRepository};
-use crate::utils::extract_email;
-use std::sync::Arc;
+use crate::middlewares::auth::{
+    AuthenticationToken, ACCESS_TOKEN_COOKIE, CSRF_COOKIE, REFRESH_TOKEN_COOKIE,
+};
+use crate::models::api_keys::{ApiKey, CreatedApiKey, NewApiKey};
+use crate::models::audit_events::AuditEvent;
+use crate::models::data_exports::DataExport;
+use crate::models::identities::Identity;
+use crate::models::mfa::{MfaEnrollment, MfaRecoveryCodes};
+use crate::models::organizations::{
+    org_roles, Invitation, Member, Membership, Organization, OrganizationMembership,
+};
+use crate::models::privacy::{PrivacySettings, UpdatePrivacySettings};
+use crate::models::roles::{permissions, Role, SUPERUSER_ROLE};
+use crate::handlers::upload::{Upload, UploadedFile};
+use crate::repositories::api_key::ApiKeyRepository;
+use crate::repositories::audit::AuditRepository;
+use crate::repositories::avatar::AvatarRepository;
+use crate::repositories::data_export::DataExportRepository;
+use crate::repositories::deletion::DeletionRepository;
+use crate::repositories::identity::IdentityRepository;
+use crate::repositories::login_attempt::LoginAttemptRepository;
+use crate::repositories::mfa::MfaRepository;
+use crate::repositories::organization::OrganizationRepository;
+use crate::repositories::phone::PhoneRepository;
+use crate::repositories::privacy::PrivacyRepository;
+use crate::repositories::role::RoleRepository;
+use crate::repositories::token::{ImpersonationResponse, TokenRepository};
+use crate::repositories::user::{LoginResponse, LoginResult, SuccessMessage, UserRepository};
+use crate::utils::{
+    access_token_ttl, generate_refresh_token, hash_token, refresh_token_ttl, TokenPurpose,
+};
+use actix_web::cookie::{time, Cookie, SameSite};
+use dotenvy::dotenv;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
 
-use crate::models::users::User;
-use crate::models::users::{ChangePassword, UserLogin, UserRegister};
+use crate::models::users::{User, UserProfile};
+use crate::models::users::{ChangePassword, UpdateProfile, UserLogin, UserRegister};
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool};
 use juniper::{graphql_value, EmptySubscription, FieldError, RootNode};
@@ -14,14 +46,280 @@ pub struct Context {
     pub pool: Arc<Pool<ConnectionManager<PgConnection>>>,
     pub token_auth: AuthenticationToken,
     pub tera: Arc<Tera>,
+    pub cookies: Cookies,
+    pub client_ip: Option<String>,
+    // active organization, picked by the client with the X-Organization-Id header
+    pub organization_id: Option<i32>,
+    // files of a multipart request, by the name of their field
+    pub uploads: Arc<Mutex<HashMap<String, UploadedFile>>>,
 }
 
 impl juniper::Context for Context {}
 
+const LOGIN_LINK_COOKIE: &str = "login_link_nonce";
+
+// cookies sent with the request, and the ones resolvers want set on the response
+#[derive(Clone, Default)]
+pub struct Cookies {
+    request: HashMap<String, String>,
+    response: Arc<Mutex<Vec<Cookie<'static>>>>,
+}
+
+impl Cookies {
+    pub fn new(request: HashMap<String, String>) -> Cookies {
+        Cookies {
+            request,
+            response: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.request.get(name).cloned()
+    }
+
+    pub fn set(&self, cookie: Cookie<'static>) {
+        self.response.lock().unwrap().push(cookie);
+    }
+
+    pub fn take(&self) -> Vec<Cookie<'static>> {
+        std::mem::take(&mut *self.response.lock().unwrap())
+    }
+}
+
+// http only cookie, COOKIE_SECURE=false allows it over plain http
+pub fn build_cookie(name: &str, value: String, max_age: time::Duration) -> Cookie<'static> {
+    dotenv().ok();
+    let secure = env::var("COOKIE_SECURE")
+        .map(|secure| secure != "false")
+        .unwrap_or(true);
+    Cookie::build(name.to_string(), value)
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .finish()
+}
+
+// AUTH_COOKIES=true also hands out the tokens of every login as cookies, so
+// browser apps never have to keep them in javascript reachable storage
+fn auth_cookies_enabled() -> bool {
+    dotenv().ok();
+    env::var("AUTH_COOKIES").is_ok_and(|enabled| enabled == "true")
+}
+
 impl Context {
     pub fn user_repository(&self) -> UserRepository {
         UserRepository::new(self.pool.clone())
     }
+
+    pub fn token_repository(&self) -> TokenRepository {
+        TokenRepository::new(self.pool.clone())
+    }
+
+    pub fn identity_repository(&self) -> IdentityRepository {
+        IdentityRepository::new(self.pool.clone())
+    }
+
+    pub fn mfa_repository(&self) -> MfaRepository {
+        MfaRepository::new(self.pool.clone())
+    }
+
+    pub fn role_repository(&self) -> RoleRepository {
+        RoleRepository::new(self.pool.clone())
+    }
+
+    pub fn api_key_repository(&self) -> ApiKeyRepository {
+        ApiKeyRepository::new(self.pool.clone())
+    }
+
+    pub fn login_attempt_repository(&self) -> LoginAttemptRepository {
+        LoginAttemptRepository::new(self.pool.clone())
+    }
+
+    pub fn privacy_repository(&self) -> PrivacyRepository {
+        PrivacyRepository::new(self.pool.clone())
+    }
+
+    pub fn audit_repository(&self) -> AuditRepository {
+        AuditRepository::new(self.pool.clone())
+    }
+
+    pub fn data_export_repository(&self) -> DataExportRepository {
+        DataExportRepository::new(self.pool.clone())
+    }
+
+    pub fn deletion_repository(&self) -> DeletionRepository {
+        DeletionRepository::new(self.pool.clone())
+    }
+
+    pub fn organization_repository(&self) -> OrganizationRepository {
+        OrganizationRepository::new(self.pool.clone())
+    }
+
+    pub fn phone_repository(&self) -> PhoneRepository {
+        PhoneRepository::new(self.pool.clone())
+    }
+
+    pub fn avatar_repository(&self) -> AvatarRepository {
+        AvatarRepository::new(self.pool.clone())
+    }
+
+    // the file sent for an Upload argument, each file can be used once
+    pub fn take_upload(&self, upload: &Upload) -> Result<UploadedFile, FieldError> {
+        self.uploads.lock().unwrap().remove(&upload.0).ok_or_else(|| {
+            FieldError::new(
+                "No file was sent for this upload",
+                graphql_value!("upload_missing".to_string()),
+            )
+        })
+    }
+
+    // access and refresh token cookies plus a fresh csrf token that scripts
+    // can read and send back in the X-CSRF-Token header
+    fn set_session_cookies(&self, response: &LoginResponse) {
+        if !auth_cookies_enabled() {
+            return;
+        }
+        let access_ttl = time::Duration::seconds(access_token_ttl().num_seconds());
+        let refresh_ttl = time::Duration::seconds(refresh_token_ttl().num_seconds());
+        self.cookies.set(build_cookie(
+            ACCESS_TOKEN_COOKIE,
+            response.token.clone(),
+            access_ttl,
+        ));
+        self.cookies.set(build_cookie(
+            REFRESH_TOKEN_COOKIE,
+            response.refresh_token.clone(),
+            refresh_ttl,
+        ));
+        let mut csrf = build_cookie(CSRF_COOKIE, generate_refresh_token(), refresh_ttl);
+        csrf.set_http_only(false);
+        self.cookies.set(csrf);
+    }
+
+    fn clear_session_cookies(&self) {
+        for name in [ACCESS_TOKEN_COOKIE, REFRESH_TOKEN_COOKIE, CSRF_COOKIE] {
+            if self.cookies.get(name).is_some() {
+                self.cookies
+                    .set(build_cookie(name, String::new(), time::Duration::ZERO));
+            }
+        }
+    }
+
+    fn set_login_cookies(&self, result: &LoginResult) {
+        if let LoginResult::Success(response) = result {
+            self.set_session_cookies(response);
+        }
+    }
+
+    // id of the authenticated user, or why there is none
+    pub fn require_authenticated(&self) -> Result<i32, FieldError> {
+        match self.token_auth.id {
+            Some(id) if self.token_auth.authenticated => Ok(id),
+            _ => match self.token_auth.error {
+                Some(error) => Err(error.into_field_error()),
+                None => Err(FieldError::new(
+                    "Authentication required",
+                    graphql_value!("unauthenticated".to_string()),
+                )),
+            },
+        }
+    }
+
+    // credentials and sessions can only be managed by a signed in user, not
+    // through an api key
+    pub fn require_user_session(&self) -> Result<i32, FieldError> {
+        let id = self.require_authenticated()?;
+        if self.token_auth.api_key_id.is_some() {
+            return Err(FieldError::new(
+                "Not allowed with an API key",
+                graphql_value!("api_key_not_allowed".to_string()),
+            ));
+        }
+        Ok(id)
+    }
+
+    // the staff member really making the request when it carries an
+    // impersonation token
+    pub fn actor_id(&self) -> Option<i32> {
+        self.token_auth.actor_id
+    }
+
+    pub fn forbid_impersonation(&self) -> Result<(), FieldError> {
+        match self.actor_id() {
+            Some(_) => Err(FieldError::new(
+                "Not allowed while impersonating",
+                graphql_value!("impersonation_not_allowed".to_string()),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // a session of the user themselves, neither an api key nor a staff
+    // member impersonating them
+    pub fn require_own_session(&self) -> Result<i32, FieldError> {
+        let id = self.require_user_session()?;
+        self.forbid_impersonation()?;
+        Ok(id)
+    }
+
+    pub fn require_superuser(&self) -> Result<i32, FieldError> {
+        let id = self.require_own_session()?;
+        if self.has_role(SUPERUSER_ROLE) {
+            Ok(id)
+        } else {
+            Err(forbidden())
+        }
+    }
+
+    // membership of the user in the active organization with at least `role`,
+    // everything scoped to an organization goes through here
+    pub async fn require_org_role(&self, role: &str) -> Result<Membership, FieldError> {
+        let id = self.require_authenticated()?;
+        let organization_id = self.organization_id.ok_or_else(|| {
+            FieldError::new(
+                "No active organization, send the X-Organization-Id header",
+                graphql_value!("organization_required".to_string()),
+            )
+        })?;
+        let membership = self
+            .organization_repository()
+            .membership(organization_id, id)
+            .await?
+            .ok_or_else(|| {
+                FieldError::new(
+                    "Not a member of this organization",
+                    graphql_value!("not_a_member".to_string()),
+                )
+            })?;
+        if !org_roles::at_least(&membership.role, role) {
+            return Err(forbidden());
+        }
+        Ok(membership)
+    }
+
+    pub fn require_permission(&self, permission: &str) -> Result<i32, FieldError> {
+        let id = self.require_authenticated()?;
+        if self.has_permission(permission) {
+            Ok(id)
+        } else {
+            Err(forbidden())
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.token_auth.roles.iter().any(|r| r == role)
+    }
+
+    // superusers hold every permission
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.has_role(SUPERUSER_ROLE) || self.token_auth.permissions.iter().any(|p| p == permission)
+    }
+}
+
+pub fn forbidden() -> FieldError {
+    FieldError::new("Forbidden", graphql_value!("forbidden".to_string()))
 }
 
 pub struct Query;
@@ -32,22 +330,118 @@ impl Query {
         "1.0"
     }
 
-    pub async fn users(context: &Context) -> Result<Vec<User>, FieldError> {
-        context.user_repository().all_users().await
+    // public profiles, holders of users:read count as staff and see accounts in full
+    pub async fn users(context: &Context) -> Result<Vec<UserProfile>, FieldError> {
+        let id = context.require_authenticated()?;
+        let users = context.user_repository().all_users().await?;
+        context
+            .privacy_repository()
+            .profiles(users, id, context.has_permission(permissions::USERS_READ))
+            .await
+    }
+
+    pub async fn user(context: &Context, id: i32) -> Result<UserProfile, FieldError> {
+        let viewer = context.require_authenticated()?;
+        let user = context.user_repository().get(id).await?;
+        let staff = context.has_permission(permissions::USERS_READ);
+        let mut profiles = context
+            .privacy_repository()
+            .profiles(vec![user], viewer, staff)
+            .await?;
+        profiles.pop().ok_or_else(|| {
+            FieldError::new("User not found", graphql_value!("user_not_found".to_string()))
+        })
+    }
+
+    // accounts that can still be restored
+    pub async fn deleted_users(context: &Context) -> Result<Vec<User>, FieldError> {
+        context.require_permission(permissions::USERS_READ)?;
+        context.deletion_repository().deleted_users().await
     }
 
     pub async fn me(context: &Context) -> Result<User, FieldError> {
-        // get authtoken id
-        let id = context.token_auth.id;
-        if !context.token_auth.authenticated {
-            return Err(FieldError::new(
-                "User not found",
-                graphql_value!("internal_error".to_string()),
-            ));
-        }
-        let id = id.unwrap();
+        let id = context.require_authenticated()?;
         context.user_repository().get(id).await
     }
+
+    pub async fn my_data_exports(context: &Context) -> Result<Vec<DataExport>, FieldError> {
+        let id = context.require_authenticated()?;
+        context.data_export_repository().exports(id).await
+    }
+
+    pub async fn privacy_settings(context: &Context) -> Result<PrivacySettings, FieldError> {
+        let id = context.require_authenticated()?;
+        context.privacy_repository().settings(id).await
+    }
+
+    pub async fn oauth_providers() -> Vec<String> {
+        crate::oauth::provider_names()
+    }
+
+    pub async fn my_identities(context: &Context) -> Result<Vec<Identity>, FieldError> {
+        let id = context.require_authenticated()?;
+        context.identity_repository().identities(id).await
+    }
+
+    pub async fn api_keys(context: &Context) -> Result<Vec<ApiKey>, FieldError> {
+        let id = context.require_own_session()?;
+        context.api_key_repository().api_keys(id).await
+    }
+
+    pub async fn audit_events(
+        context: &Context,
+        user_id: Option<i32>,
+    ) -> Result<Vec<AuditEvent>, FieldError> {
+        context.require_superuser()?;
+        context.audit_repository().events(user_id).await
+    }
+
+    pub async fn my_organizations(
+        context: &Context,
+    ) -> Result<Vec<OrganizationMembership>, FieldError> {
+        let id = context.require_authenticated()?;
+        context.organization_repository().my_organizations(id).await
+    }
+
+    // the active organization
+    pub async fn organization(context: &Context) -> Result<Organization, FieldError> {
+        let membership = context.require_org_role(org_roles::MEMBER).await?;
+        context
+            .organization_repository()
+            .get(membership.organization_id)
+            .await
+    }
+
+    pub async fn members(context: &Context) -> Result<Vec<Member>, FieldError> {
+        let membership = context.require_org_role(org_roles::MEMBER).await?;
+        context
+            .organization_repository()
+            .members(&membership)
+            .await
+    }
+
+    pub async fn invitations(context: &Context) -> Result<Vec<Invitation>, FieldError> {
+        let membership = context.require_org_role(org_roles::ADMIN).await?;
+        context
+            .organization_repository()
+            .invitations(membership.organization_id)
+            .await
+    }
+
+    pub async fn my_invitations(context: &Context) -> Result<Vec<Invitation>, FieldError> {
+        let id = context.require_authenticated()?;
+        context.organization_repository().my_invitations(id).await
+    }
+
+    pub async fn roles(context: &Context) -> Result<Vec<Role>, FieldError> {
+        context.require_permission(permissions::ROLES_MANAGE)?;
+        context.role_repository().all_roles().await
+    }
+
+    pub async fn user_roles(context: &Context, user_id: i32) -> Result<Vec<Role>, FieldError> {
+        context.require_permission(permissions::ROLES_MANAGE)?;
+        context.role_repository().user_roles(user_id).await
+    }
 }
 
 pub struct Mutation;
@@ -60,15 +454,440 @@ impl Mutation {
         let tera = context.tera.clone();
         context.user_repository().register(input, tera).await
     }
-    pub async fn login(context: &Context, input: UserLogin) -> Result<LoginResponse, FieldError> {
-        context.user_repository().login(input).await
+    pub async fn login(context: &Context, input: UserLogin) -> Result<LoginResult, FieldError> {
+        let tera = context.tera.clone();
+        let result = context
+            .user_repository()
+            .login(input, context.client_ip.clone(), tera)
+            .await?;
+        context.set_login_cookies(&result);
+        Ok(result)
+    }
+
+    pub async fn request_account_unlock(
+        context: &Context,
+        email: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let tera = context.tera.clone();
+        context
+            .login_attempt_repository()
+            .request_unlock(email, tera)
+            .await
+    }
+
+    pub async fn unlock_account(
+        context: &Context,
+        token: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        context
+            .login_attempt_repository()
+            .unlock_with_token(token)
+            .await
+    }
+
+    pub async fn unlock_user(context: &Context, user_id: i32) -> Result<SuccessMessage, FieldError> {
+        context.require_permission(permissions::USERS_WRITE)?;
+        context.login_attempt_repository().unlock_user(user_id).await
+    }
+    pub async fn verify_mfa_login(
+        context: &Context,
+        challenge_token: String,
+        code: String,
+    ) -> Result<LoginResponse, FieldError> {
+        let response = context
+            .mfa_repository()
+            .verify_login(
+                challenge_token,
+                code,
+                context.client_ip.clone(),
+                context.tera.clone(),
+            )
+            .await?;
+        context.set_session_cookies(&response);
+        Ok(response)
+    }
+
+    // falls back to the refresh_token cookie
+    pub async fn refresh_token(
+        context: &Context,
+        refresh_token: Option<String>,
+    ) -> Result<LoginResponse, FieldError> {
+        let refresh_token = refresh_token
+            .or_else(|| context.cookies.get(REFRESH_TOKEN_COOKIE))
+            .unwrap_or_default();
+        let response = context.token_repository().rotate(refresh_token).await?;
+        context.set_session_cookies(&response);
+        Ok(response)
+    }
+
+    pub async fn logout(
+        context: &Context,
+        refresh_token: Option<String>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_user_session()?;
+        let token_auth = &context.token_auth;
+        let refresh_token = refresh_token.or_else(|| context.cookies.get(REFRESH_TOKEN_COOKIE));
+        let result = context
+            .token_repository()
+            .logout(
+                id,
+                token_auth.jti.clone().unwrap_or_default(),
+                token_auth.expires_at.unwrap_or_default(),
+                refresh_token,
+            )
+            .await?;
+        context.clear_session_cookies();
+        Ok(result)
+    }
+
+    pub async fn logout_all_sessions(context: &Context) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_own_session()?;
+        let result = context.token_repository().logout_all(id).await?;
+        context.clear_session_cookies();
+        Ok(result)
+    }
+
+    // builds a copy of everything stored about the user in the background
+    // and emails them a download link
+    pub async fn export_my_data(
+        context: &Context,
+        zipped: Option<bool>,
+    ) -> Result<DataExport, FieldError> {
+        let id = context.require_own_session()?;
+        let tera = context.tera.clone();
+        context
+            .data_export_repository()
+            .request(id, id, zipped.unwrap_or(false), tera)
+            .await
+    }
+
+    // the link still goes to the user, staff only see the export's status
+    pub async fn export_user_data(
+        context: &Context,
+        user_id: i32,
+        zipped: Option<bool>,
+    ) -> Result<DataExport, FieldError> {
+        let actor_id = context.require_permission(permissions::USERS_READ)?;
+        let tera = context.tera.clone();
+        context
+            .data_export_repository()
+            .request(user_id, actor_id, zipped.unwrap_or(false), tera)
+            .await
+    }
+
+    // signs the user out everywhere, the account can be restored by staff
+    // until it is purged
+    pub async fn delete_my_account(
+        context: &Context,
+        password: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_own_session()?;
+        let tera = context.tera.clone();
+        let result = context
+            .deletion_repository()
+            .delete_own(id, password, context.client_ip.clone(), tera)
+            .await?;
+        context.clear_session_cookies();
+        Ok(result)
+    }
+
+    pub async fn delete_user(
+        context: &Context,
+        user_id: i32,
+    ) -> Result<SuccessMessage, FieldError> {
+        let actor_id = context.require_permission(permissions::USERS_WRITE)?;
+        context.forbid_impersonation()?;
+        let tera = context.tera.clone();
+        context
+            .deletion_repository()
+            .delete(
+                actor_id,
+                context.has_role(SUPERUSER_ROLE),
+                user_id,
+                context.client_ip.clone(),
+                tera,
+            )
+            .await
+    }
+
+    pub async fn restore_user(context: &Context, user_id: i32) -> Result<User, FieldError> {
+        let actor_id = context.require_permission(permissions::USERS_WRITE)?;
+        context.forbid_impersonation()?;
+        context
+            .deletion_repository()
+            .restore(actor_id, user_id, context.client_ip.clone())
+            .await
+    }
+
+    pub async fn complete_oauth_login(
+        context: &Context,
+        code: String,
+    ) -> Result<LoginResult, FieldError> {
+        let result = context.identity_repository().complete_login(code).await?;
+        context.set_login_cookies(&result);
+        Ok(result)
+    }
+
+    // authorization url that links the provider account to the current user
+    // the nonce cookie set here has to come back with the provider's
+    // redirect, so only this browser can finish the link
+    pub async fn link_identity(context: &Context, provider: String) -> Result<String, FieldError> {
+        let id = context.require_own_session()?;
+        let (nonce, cookie) = super::oauth::oauth_nonce();
+        let url = context
+            .identity_repository()
+            .authorize_url(&provider, Some(id), &nonce)
+            .await?;
+        context.cookies.set(cookie);
+        Ok(url)
+    }
+
+    pub async fn unlink_identity(
+        context: &Context,
+        provider: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_own_session()?;
+        context.identity_repository().unlink(id, provider).await
+    }
+
+    pub async fn enroll_mfa(context: &Context) -> Result<MfaEnrollment, FieldError> {
+        let id = context.require_own_session()?;
+        context.mfa_repository().enroll(id).await
+    }
+
+    pub async fn confirm_mfa(
+        context: &Context,
+        code: String,
+    ) -> Result<MfaRecoveryCodes, FieldError> {
+        let id = context.require_own_session()?;
+        context.mfa_repository().confirm(id, code).await
+    }
+
+    pub async fn disable_mfa(
+        context: &Context,
+        password: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_own_session()?;
+        context.mfa_repository().disable(id, password).await
+    }
+
+    pub async fn regenerate_recovery_codes(
+        context: &Context,
+        password: String,
+    ) -> Result<MfaRecoveryCodes, FieldError> {
+        let id = context.require_own_session()?;
+        context
+            .mfa_repository()
+            .regenerate_recovery_codes(id, password)
+            .await
+    }
+
+    pub async fn create_api_key(
+        context: &Context,
+        input: NewApiKey,
+    ) -> Result<CreatedApiKey, FieldError> {
+        let id = context.require_own_session()?;
+        context.api_key_repository().create(id, input).await
+    }
+
+    pub async fn revoke_api_key(context: &Context, id: i32) -> Result<SuccessMessage, FieldError> {
+        let user_id = context.require_own_session()?;
+        context.api_key_repository().revoke(user_id, id).await
+    }
+
+    pub async fn update_privacy_settings(
+        context: &Context,
+        input: UpdatePrivacySettings,
+    ) -> Result<PrivacySettings, FieldError> {
+        let id = context.require_authenticated()?;
+        context.privacy_repository().update(id, input).await
+    }
+
+    pub async fn assign_role(
+        context: &Context,
+        user_id: i32,
+        role: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        context.require_permission(permissions::ROLES_MANAGE)?;
+        context.role_repository().assign_role(user_id, role).await
+    }
+
+    pub async fn remove_role(
+        context: &Context,
+        user_id: i32,
+        role: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        context.require_permission(permissions::ROLES_MANAGE)?;
+        context.role_repository().remove_role(user_id, role).await
+    }
+
+    pub async fn create_organization(
+        context: &Context,
+        name: String,
+    ) -> Result<OrganizationMembership, FieldError> {
+        let id = context.require_authenticated()?;
+        context.organization_repository().create(id, name).await
+    }
+
+    // invite someone to the active organization, role defaults to member
+    pub async fn invite_member(
+        context: &Context,
+        email: String,
+        role: Option<String>,
+    ) -> Result<Invitation, FieldError> {
+        let membership = context.require_org_role(org_roles::ADMIN).await?;
+        let role = role.unwrap_or_else(|| org_roles::MEMBER.to_string());
+        let tera = context.tera.clone();
+        context
+            .organization_repository()
+            .invite(&membership, email, role, tera)
+            .await
+    }
+
+    pub async fn revoke_invitation(
+        context: &Context,
+        id: i32,
+    ) -> Result<SuccessMessage, FieldError> {
+        let membership = context.require_org_role(org_roles::ADMIN).await?;
+        context
+            .organization_repository()
+            .revoke_invitation(membership.organization_id, id)
+            .await
+    }
+
+    pub async fn accept_invitation(
+        context: &Context,
+        token: String,
+    ) -> Result<OrganizationMembership, FieldError> {
+        let id = context.require_authenticated()?;
+        context.organization_repository().accept(id, token).await
+    }
+
+    pub async fn decline_invitation(
+        context: &Context,
+        token: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_authenticated()?;
+        context.organization_repository().decline(id, token).await
+    }
+
+    pub async fn update_member_role(
+        context: &Context,
+        user_id: i32,
+        role: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let membership = context.require_org_role(org_roles::OWNER).await?;
+        context
+            .organization_repository()
+            .update_member_role(&membership, user_id, role)
+            .await
+    }
+
+    // also used to leave the active organization
+    pub async fn remove_member(
+        context: &Context,
+        user_id: i32,
+    ) -> Result<SuccessMessage, FieldError> {
+        let membership = context.require_org_role(org_roles::MEMBER).await?;
+        context
+            .organization_repository()
+            .remove_member(&membership, user_id)
+            .await
+    }
+
+    // short lived token acting as the user, for superusers only
+    pub async fn impersonate(
+        context: &Context,
+        user_id: i32,
+    ) -> Result<ImpersonationResponse, FieldError> {
+        let actor_id = context.require_superuser()?;
+        context
+            .token_repository()
+            .impersonate(actor_id, user_id, context.client_ip.clone())
+            .await
+    }
+
+    pub async fn stop_impersonating(context: &Context) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_authenticated()?;
+        let actor_id = context.actor_id().ok_or_else(|| {
+            FieldError::new(
+                "Not impersonating",
+                graphql_value!("not_impersonating".to_string()),
+            )
+        })?;
+        let token_auth = &context.token_auth;
+        context
+            .token_repository()
+            .stop_impersonation(
+                actor_id,
+                id,
+                token_auth.jti.clone().unwrap_or_default(),
+                token_auth.expires_at.unwrap_or_default(),
+                context.client_ip.clone(),
+            )
+            .await
+    }
+
+    // bind_to_browser makes the link only work in the browser that asked for it
+    pub async fn request_login_link(
+        context: &Context,
+        email: String,
+        bind_to_browser: Option<bool>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let browser_binding = if bind_to_browser.unwrap_or(false) {
+            let nonce = generate_refresh_token();
+            let ttl = TokenPurpose::LoginLink.ttl().num_seconds();
+            context.cookies.set(build_cookie(
+                LOGIN_LINK_COOKIE,
+                nonce.clone(),
+                time::Duration::seconds(ttl),
+            ));
+            Some(hash_token(&nonce))
+        } else {
+            None
+        };
+        let tera = context.tera.clone();
+        context
+            .user_repository()
+            .request_login_link(email, browser_binding, tera)
+            .await
+    }
+
+    pub async fn consume_login_link(
+        context: &Context,
+        token: String,
+    ) -> Result<LoginResult, FieldError> {
+        let result = context
+            .user_repository()
+            .consume_login_link(token, context.cookies.get(LOGIN_LINK_COOKIE))
+            .await?;
+        if context.cookies.get(LOGIN_LINK_COOKIE).is_some() {
+            context.cookies.set(build_cookie(
+                LOGIN_LINK_COOKIE,
+                String::new(),
+                time::Duration::ZERO,
+            ));
+        }
+        context.set_login_cookies(&result);
+        Ok(result)
     }
+
+    pub async fn resend_verification_email(
+        context: &Context,
+        email: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let tera = context.tera.clone();
+        context
+            .user_repository()
+            .resend_verification_email(email, tera)
+            .await
+    }
+
     pub async fn verify_email(
         context: &Context,
         token: String,
     ) -> Result<SuccessMessage, FieldError> {
-        let email = extract_email(&token);
-        context.user_repository().verify_email(email).await
+        context.user_repository().verify_email(token).await
     }
 
     pub async fn request_password_reset(
@@ -86,8 +905,85 @@ impl Mutation {
         context: &Context,
         input: ChangePassword,
     ) -> Result<SuccessMessage, FieldError> {
+        context.forbid_impersonation()?;
         context.user_repository().change_password(input).await
     }
+
+    pub async fn request_email_change(
+        context: &Context,
+        new_email: String,
+        current_password: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_own_session()?;
+        let tera = context.tera.clone();
+        context
+            .user_repository()
+            .request_email_change(id, new_email, current_password, tera)
+            .await
+    }
+
+    pub async fn confirm_email_change(context: &Context, token: String) -> Result<User, FieldError> {
+        context.forbid_impersonation()?;
+        context.user_repository().confirm_email_change(token).await
+    }
+
+    // only the fields sent are changed, null clears a field
+    pub async fn update_profile(
+        context: &Context,
+        input: UpdateProfile,
+    ) -> Result<User, FieldError> {
+        let id = context.require_authenticated()?;
+        context.user_repository().update_profile(id, input).await
+    }
+
+    // the image is cropped to a square and stored in each avatar size
+    pub async fn upload_avatar(context: &Context, file: Upload) -> Result<User, FieldError> {
+        let id = context.require_authenticated()?;
+        let file = context.take_upload(&file)?;
+        context.avatar_repository().upload(id, file).await
+    }
+
+    pub async fn remove_avatar(context: &Context) -> Result<User, FieldError> {
+        let id = context.require_authenticated()?;
+        context.avatar_repository().remove(id).await
+    }
+
+    // texts a code to the number, it replaces the current one once verified
+    pub async fn set_phone(context: &Context, phone: String) -> Result<SuccessMessage, FieldError> {
+        let id = context.require_own_session()?;
+        context.phone_repository().set_phone(id, phone).await
+    }
+
+    pub async fn verify_phone(context: &Context, code: String) -> Result<User, FieldError> {
+        let id = context.require_own_session()?;
+        context.phone_repository().verify_phone(id, code).await
+    }
+
+    pub async fn remove_phone(context: &Context) -> Result<User, FieldError> {
+        let id = context.require_own_session()?;
+        context.phone_repository().remove_phone(id).await
+    }
+
+    pub async fn request_phone_login(
+        context: &Context,
+        phone: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        context.phone_repository().request_login_code(phone).await
+    }
+
+    pub async fn phone_login(
+        context: &Context,
+        phone: String,
+        code: String,
+    ) -> Result<LoginResult, FieldError> {
+        let tera = context.tera.clone();
+        let result = context
+            .phone_repository()
+            .login_with_code(phone, code, context.client_ip.clone(), tera)
+            .await?;
+        context.set_login_cookies(&result);
+        Ok(result)
+    }
 }
 
 pub type Schema = RootNode<'static, Query, Mutation, EmptySubscription<Context>>;
@@ -0,0 +1,22 @@
+use actix_web::{http::header, web, HttpResponse};
+use drgz::storage::{content_type_for, storage, valid_key};
+
+// files kept by the local storage backend, the S3 backend hands out its
+// own urls
+pub async fn serve(key: web::Path<String>) -> HttpResponse {
+    if !valid_key(&key) {
+        return HttpResponse::NotFound().finish();
+    }
+    match storage().get(&key).await {
+        Ok(Some(body)) => HttpResponse::Ok()
+            .content_type(content_type_for(&key))
+            .append_header((header::CACHE_CONTROL, "public, max-age=86400"))
+            .append_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .body(body),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Error reading {} from storage: {}", key, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -1,25 +1,65 @@
-mod graphql;
+mod exports;
+pub mod graphql;
+mod media;
+mod oauth;
+pub mod upload;
 
+use actix_multipart::Multipart;
 use actix_web::{
+    guard::{self, GuardContext},
+    http::header,
     web::{self, Data},
-    HttpResponse,
+    HttpRequest, HttpResponse,
 };
 use diesel::{r2d2::ConnectionManager, PgConnection};
-use graphql::{create_schema, Context, Schema};
+use crate::keyring::keyring;
+use crate::middlewares::csrf::Csrf;
+use crate::middlewares::rate_limit::RateLimit;
+use crate::middlewares::UploadLimits;
+use graphql::{create_schema, Context, Cookies, Schema};
 use juniper::http::{graphiql::graphiql_source, GraphQLRequest};
 use r2d2::Pool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tera::Tera;
+use upload::UploadedFile;
+
+// selects the active organization of a request
+pub const ORGANIZATION_HEADER: &str = "x-organization-id";
 
 async fn health() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+async fn jwks() -> HttpResponse {
+    HttpResponse::Ok().json(keyring().jwks())
+}
+
+fn is_multipart(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.to_lowercase().starts_with("multipart/form-data"))
+}
+
 pub fn app_config(config: &mut web::ServiceConfig) {
     let schema = Data::new(create_schema());
     config
         .app_data(schema)
-        .service(web::resource("/graphql").route(web::post().to(graphql)))
+        .service(
+            web::resource("/graphql")
+                .wrap(Csrf)
+                .wrap(RateLimit)
+                .route(web::post().guard(guard::fn_guard(is_multipart)).to(graphql_multipart))
+                .route(web::post().to(graphql)),
+        )
         .service(web::resource("/graphiql").route(web::get().to(graphiql)))
+        .service(web::resource("/exports/{token}").route(web::get().to(exports::download)))
+        .service(web::resource("/media/{key:.*}").route(web::get().to(media::serve)))
+        .service(web::resource("/.well-known/jwks.json").route(web::get().to(jwks)))
+        .service(web::resource("/auth/{provider}/start").route(web::get().to(oauth::start)))
+        .service(web::resource("/auth/{provider}/callback").route(web::get().to(oauth::callback)))
         .service(web::resource("/").route(web::get().to(health)));
 }
 
@@ -31,17 +71,67 @@ async fn graphiql() -> HttpResponse {
 }
 
 async fn graphql(
+    req: HttpRequest,
     data: web::Json<GraphQLRequest>,
     schema: web::Data<Schema>,
     pool: web::Data<Pool<ConnectionManager<PgConnection>>>,
     token_auth: crate::middlewares::auth::AuthenticationToken,
     tera: web::Data<Tera>,
+) -> HttpResponse {
+    execute(req, data.into_inner(), HashMap::new(), schema, pool, token_auth, tera).await
+}
+
+// a request following the GraphQL multipart request spec, for mutations
+// taking files
+async fn graphql_multipart(
+    req: HttpRequest,
+    payload: Multipart,
+    schema: web::Data<Schema>,
+    pool: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    token_auth: crate::middlewares::auth::AuthenticationToken,
+    tera: web::Data<Tera>,
+) -> HttpResponse {
+    let (data, uploads) = match upload::read_multipart(payload, &UploadLimits::from_env()).await {
+        Ok(request) => request,
+        Err(e) => return e.response(),
+    };
+    execute(req, data, uploads, schema, pool, token_auth, tera).await
+}
+
+async fn execute(
+    req: HttpRequest,
+    data: GraphQLRequest,
+    uploads: HashMap<String, UploadedFile>,
+    schema: web::Data<Schema>,
+    pool: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    token_auth: crate::middlewares::auth::AuthenticationToken,
+    tera: web::Data<Tera>,
 ) -> HttpResponse {
     let pool = pool.into_inner();
+    let cookies = req
+        .cookies()
+        .map(|cookies| {
+            cookies
+                .iter()
+                .map(|c| (c.name().to_string(), c.value().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let organization_id = req
+        .headers()
+        .get(ORGANIZATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<i32>().ok());
     let ctx = Context { pool,  token_auth, 
-        tera: tera.into_inner() };
+        tera: tera.into_inner(), cookies: Cookies::new(cookies),
+        client_ip: crate::middlewares::client_ip(&req.connection_info()),
+        organization_id, uploads: Arc::new(Mutex::new(uploads)) };
     let value = data.execute(&schema, &ctx).await;
-    HttpResponse::Ok()
+    let mut response = HttpResponse::Ok();
+    for cookie in ctx.cookies.take() {
+        response.cookie(cookie);
+    }
+    response
         .content_type("application/json")
         .json(value)
 }
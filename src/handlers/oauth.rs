@@ -0,0 +1,126 @@
+use super::graphql::build_cookie;
+use crate::repositories::identity::{IdentityRepository, OAUTH_STATE_TTL_SECONDS};
+use crate::utils::{generate_refresh_token, generate_user_action_token, TokenPurpose};
+use actix_web::cookie::{time, Cookie};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use juniper::FieldError;
+use r2d2::Pool;
+use serde::Deserialize;
+use std::env;
+
+// ties a sign in or link to the browser that started it, so a url handed
+// to someone else cannot be completed in their browser
+pub const OAUTH_NONCE_COOKIE: &str = "oauth_nonce";
+
+// a fresh nonce for a flow about to start, with the cookie carrying it
+pub fn oauth_nonce() -> (String, Cookie<'static>) {
+    let nonce = generate_refresh_token();
+    let cookie = build_cookie(
+        OAUTH_NONCE_COOKIE,
+        nonce.clone(),
+        time::Duration::seconds(OAUTH_STATE_TTL_SECONDS),
+    );
+    (nonce, cookie)
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+// browser entry point, redirects to the provider's consent screen
+pub async fn start(
+    provider: web::Path<String>,
+    pool: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> HttpResponse {
+    let repository = IdentityRepository::new(pool.into_inner());
+    let (nonce, cookie) = oauth_nonce();
+    match repository.authorize_url(&provider, None, &nonce).await {
+        Ok(url) => {
+            let mut response = redirect(&url);
+            let _ = response.add_cookie(&cookie);
+            response
+        }
+        Err(e) => redirect_to_frontend("error", &error_code(&e)),
+    }
+}
+
+// the provider sends the browser back here, the frontend then exchanges the
+// one-time code with the completeOauthLogin mutation
+pub async fn callback(
+    req: HttpRequest,
+    provider: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+    pool: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> HttpResponse {
+    let mut response = complete(&req, &provider, &query, pool).await;
+    // the nonce is only good for one flow
+    if req.cookie(OAUTH_NONCE_COOKIE).is_some() {
+        let _ = response.add_cookie(&build_cookie(
+            OAUTH_NONCE_COOKIE,
+            String::new(),
+            time::Duration::ZERO,
+        ));
+    }
+    response
+}
+
+async fn complete(
+    req: &HttpRequest,
+    provider: &str,
+    query: &CallbackQuery,
+    pool: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> HttpResponse {
+    if let Some(error) = &query.error {
+        return redirect_to_frontend("error", error);
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return redirect_to_frontend("error", "invalid_oauth_state"),
+    };
+    let nonce = req.cookie(OAUTH_NONCE_COOKIE);
+
+    let repository = IdentityRepository::new(pool.into_inner());
+    match repository
+        .callback(
+            provider,
+            code,
+            state,
+            nonce.as_ref().map(|cookie| cookie.value()),
+        )
+        .await
+    {
+        Ok(user) => redirect_to_frontend(
+            "code",
+            &generate_user_action_token(user.id, &user.email, TokenPurpose::OAuthLogin),
+        ),
+        Err(e) => redirect_to_frontend("error", &error_code(&e)),
+    }
+}
+
+fn redirect(url: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header((header::LOCATION, url))
+        .finish()
+}
+
+fn redirect_to_frontend(key: &str, value: &str) -> HttpResponse {
+    dotenv().ok();
+    let complete_url = env::var("OAUTH_COMPLETE_URL")
+        .unwrap_or_else(|_| "http://localhost:3000/oauth/complete".to_string());
+    match url::Url::parse_with_params(&complete_url, &[(key, value)]) {
+        Ok(url) => redirect(url.as_str()),
+        Err(_e) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+fn error_code(e: &FieldError) -> String {
+    e.extensions()
+        .as_string_value()
+        .unwrap_or("oauth_error")
+        .to_string()
+}
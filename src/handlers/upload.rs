@@ -0,0 +1,338 @@
+use crate::middlewares::UploadLimits;
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, HttpResponse};
+use futures::StreamExt;
+use juniper::http::GraphQLRequest;
+use juniper::parser::{ParseError, ScalarToken, Token};
+use juniper::{InputValue, ParseScalarResult, Value};
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+
+// a file sent with the GraphQL multipart request spec
+// (https://github.com/jaydenseric/graphql-multipart-request-spec). The
+// variable holds the name of the multipart field carrying the file, the
+// resolver takes the file itself from the context
+#[derive(Clone, Debug)]
+pub struct Upload(pub String);
+
+#[juniper::graphql_scalar(description = "A file sent as part of a multipart request")]
+impl<S> GraphQLScalar for Upload
+where
+    S: ScalarValue,
+{
+    fn resolve(&self) -> Value {
+        Value::scalar(self.0.clone())
+    }
+
+    fn from_input_value(v: &InputValue) -> Option<Upload> {
+        match *v {
+            InputValue::Scalar(ref s) => s.as_string().map(Upload),
+            _ => None,
+        }
+    }
+
+    fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+        match value {
+            ScalarToken::String(value) => Ok(S::from(value.to_owned())),
+            _ => Err(ParseError::UnexpectedToken(Token::Scalar(value))),
+        }
+    }
+}
+
+pub struct UploadedFile {
+    // as sent by the client, not checked against the bytes
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+pub struct UploadError {
+    status: StatusCode,
+    message: String,
+    code: &'static str,
+}
+
+impl UploadError {
+    fn bad_request(message: &str) -> UploadError {
+        UploadError {
+            status: StatusCode::BAD_REQUEST,
+            message: message.to_string(),
+            code: "upload_invalid",
+        }
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(json!({
+            "data": null,
+            "errors": [{
+                "message": self.message,
+                "extensions": self.code,
+            }],
+        }))
+    }
+}
+
+// read a multipart graphql request: the `operations` field, the `map` field
+// telling which variables each file goes to, then the files. Each variable
+// named in the map is set to the name of its file field. The spec fixes
+// that order, and the operation middleware relies on `operations` coming
+// first to see what the request runs
+pub async fn read_multipart(
+    mut payload: Multipart,
+    limits: &UploadLimits,
+) -> Result<(GraphQLRequest, HashMap<String, UploadedFile>), UploadError> {
+    let mut operations: Option<JsonValue> = None;
+    let mut map: Option<HashMap<String, Vec<String>>> = None;
+    let mut files = HashMap::new();
+    let max_bytes = limits.max_bytes;
+    let max_files = limits.max_files;
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| UploadError::bad_request(&e.to_string()))?;
+        let name = field.name().unwrap_or_default().to_string();
+        let required = match (&operations, &map) {
+            (None, _) => Some("operations"),
+            (Some(_), None) => Some("map"),
+            _ => None,
+        };
+        if let Some(required) = required {
+            if name != required {
+                return Err(UploadError::bad_request(&format!(
+                    "Expected the {} field, got {}",
+                    required, name
+                )));
+            }
+        }
+        match name.as_str() {
+            // a second operations or map field
+            "operations" | "map" if required.is_none() => {
+                return Err(UploadError::bad_request(&format!(
+                    "Unexpected multipart field {}",
+                    name
+                )));
+            }
+            "operations" | "map" => {
+                let body = field
+                    .bytes(1024 * 1024)
+                    .await
+                    .map_err(|_e| {
+                        UploadError::bad_request("The operations or map field is too large")
+                    })?
+                    .map_err(|e| UploadError::bad_request(&e.to_string()))?;
+                if name == "operations" {
+                    operations = Some(serde_json::from_slice(&body).map_err(|_e| {
+                        UploadError::bad_request("The operations field is not valid JSON")
+                    })?);
+                } else {
+                    map = Some(serde_json::from_slice(&body).map_err(|_e| {
+                        UploadError::bad_request("The map field is not valid JSON")
+                    })?);
+                }
+            }
+            _ => {
+                let expected = map.as_ref().is_some_and(|map| map.contains_key(&name));
+                if !expected {
+                    return Err(UploadError::bad_request(&format!(
+                        "Unexpected multipart field {}",
+                        name
+                    )));
+                }
+                if files.len() >= max_files {
+                    return Err(UploadError {
+                        status: StatusCode::PAYLOAD_TOO_LARGE,
+                        message: format!("At most {} files can be uploaded at once", max_files),
+                        code: "upload_too_many_files",
+                    });
+                }
+                let content_type = field
+                    .content_type()
+                    .map(|mime| mime.essence_str().to_lowercase())
+                    .unwrap_or_default();
+                if !limits.allowed_types.contains(&content_type) {
+                    return Err(UploadError {
+                        status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        message: format!("Files of type {} are not accepted", content_type),
+                        code: "upload_type_not_allowed",
+                    });
+                }
+                let bytes = field
+                    .bytes(max_bytes)
+                    .await
+                    .map_err(|_e| UploadError {
+                        status: StatusCode::PAYLOAD_TOO_LARGE,
+                        message: format!("Files can be at most {} bytes", max_bytes),
+                        code: "upload_too_large",
+                    })?
+                    .map_err(|e| UploadError::bad_request(&e.to_string()))?;
+                files.insert(
+                    name,
+                    UploadedFile {
+                        content_type,
+                        bytes: bytes.to_vec(),
+                    },
+                );
+            }
+        }
+    }
+
+    let mut operations =
+        operations.ok_or_else(|| UploadError::bad_request("The operations field is missing"))?;
+    let map = map.ok_or_else(|| UploadError::bad_request("The map field is missing"))?;
+    for (name, paths) in &map {
+        if !files.contains_key(name) {
+            return Err(UploadError::bad_request(&format!(
+                "File {} is missing",
+                name
+            )));
+        }
+        for path in paths {
+            let slot = path
+                .split('.')
+                .try_fold(&mut operations, |value, part| match value {
+                    JsonValue::Object(object) => object.get_mut(part),
+                    JsonValue::Array(array) => part
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|index| array.get_mut(index)),
+                    _ => None,
+                })
+                .ok_or_else(|| UploadError::bad_request(&format!("Invalid map path {}", path)))?;
+            *slot = JsonValue::String(name.clone());
+        }
+    }
+
+    // batched operations are not supported, same as the JSON endpoint
+    let request = serde_json::from_value::<GraphQLRequest>(operations)
+        .map_err(|_e| UploadError::bad_request("The operations field is not a GraphQL request"))?;
+    Ok((request, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_multipart;
+    use crate::middlewares::UploadLimits;
+    use actix_multipart::Multipart;
+    use actix_web::error::PayloadError;
+    use actix_web::http::header::{self, HeaderMap, HeaderValue};
+    use actix_web::web::Bytes;
+
+    const BOUNDARY: &str = "boundary";
+
+    fn limits() -> UploadLimits {
+        UploadLimits {
+            max_bytes: 8,
+            max_files: 1,
+            allowed_types: vec!["image/png".to_string()],
+        }
+    }
+
+    // a multipart request uploading the given files to $file
+    fn multipart(files: &[(&str, &str, &[u8])]) -> Multipart {
+        multipart_in_order(files, false)
+    }
+
+    // `map_first` sends the map before the operations, against the spec
+    fn multipart_in_order(files: &[(&str, &str, &[u8])], map_first: bool) -> Multipart {
+        let mut body = Vec::new();
+        let map = files
+            .iter()
+            .map(|(name, _, _)| format!("\"{}\": [\"variables.file\"]", name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut fields = [
+            (
+                "operations",
+                r#"{"query": "mutation ($file: Upload!) { uploadAvatar(file: $file) { id } }", "variables": {"file": null}}"#.to_string(),
+            ),
+            ("map", format!("{{{}}}", map)),
+        ];
+        if map_first {
+            fields.reverse();
+        }
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    BOUNDARY, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        for (name, content_type, bytes) in files {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"a\"\r\nContent-Type: {}\r\n\r\n",
+                    BOUNDARY, name, content_type
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(bytes);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/form-data; boundary={}", BOUNDARY)).unwrap(),
+        );
+        let stream = futures::stream::once(async move { Ok::<_, PayloadError>(Bytes::from(body)) });
+        Multipart::new(&headers, stream)
+    }
+
+    #[actix_web::test]
+    async fn sets_the_variable_to_the_file_field() {
+        let (request, files) = read_multipart(multipart(&[("0", "image/png", b"png")]), &limits())
+            .await
+            .ok()
+            .unwrap();
+        let variables = serde_json::to_value(&request).unwrap()["variables"].clone();
+        assert_eq!(variables, serde_json::json!({"file": "0"}));
+        assert_eq!(files["0"].content_type, "image/png");
+        assert_eq!(files["0"].bytes, b"png");
+    }
+
+    #[actix_web::test]
+    async fn rejects_the_map_before_the_operations() {
+        let e = read_multipart(
+            multipart_in_order(&[("0", "image/png", b"png")], true),
+            &limits(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(e.code, "upload_invalid");
+        assert_eq!(e.status, 400);
+    }
+
+    #[actix_web::test]
+    async fn rejects_files_over_the_size_limit() {
+        let e = read_multipart(multipart(&[("0", "image/png", b"too large")]), &limits())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.code, "upload_too_large");
+        assert_eq!(e.status, 413);
+    }
+
+    #[actix_web::test]
+    async fn rejects_types_not_allowed() {
+        let e = read_multipart(multipart(&[("0", "text/html", b"<p>")]), &limits())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.code, "upload_type_not_allowed");
+        assert_eq!(e.status, 415);
+    }
+
+    #[actix_web::test]
+    async fn rejects_more_files_than_allowed() {
+        let e = read_multipart(
+            multipart(&[("0", "image/png", b"a"), ("1", "image/png", b"b")]),
+            &limits(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(e.code, "upload_too_many_files");
+    }
+}
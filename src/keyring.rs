@@ -0,0 +1,233 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use dotenvy::dotenv;
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, TokenData,
+    Validation,
+};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{env, fs, sync::OnceLock};
+
+// JWT_KEYRING points to a json manifest like
+//
+// {
+//   "active": "2023-06",
+//   "grace_period": 86400,
+//   "keys": [
+//     { "kid": "2023-06", "alg": "EdDSA", "private_key": "keys/2023-06.pem", "public_key": "keys/2023-06.pub.pem" },
+//     { "kid": "2023-01", "alg": "RS256", "public_key": "keys/2023-01.pub.pem", "retired_at": "2023-06-01T00:00:00Z" }
+//   ]
+// }
+//
+// new tokens are signed with the active key, retired keys keep verifying
+// tokens for `grace_period` seconds after `retired_at`. Without a manifest
+// tokens are HS256 signed with SECRET_KEY.
+#[derive(Deserialize)]
+struct KeyRingManifest {
+    active: String,
+    #[serde(default = "default_grace_period")]
+    grace_period: i64,
+    keys: Vec<KeyManifest>,
+}
+
+#[derive(Deserialize)]
+struct KeyManifest {
+    kid: String,
+    alg: Algorithm,
+    private_key: Option<String>,
+    public_key: String,
+    retired_at: Option<DateTime<Utc>>,
+}
+
+fn default_grace_period() -> i64 {
+    60 * 60 * 24
+}
+
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    jwk: Option<Value>,
+    retired_at: Option<DateTime<Utc>>,
+}
+
+pub struct KeyRing {
+    active: usize,
+    keys: Vec<Key>,
+    grace_period: Duration,
+}
+
+#[derive(Serialize)]
+pub struct Jwks {
+    keys: Vec<Value>,
+}
+
+static KEYRING: OnceLock<KeyRing> = OnceLock::new();
+
+pub fn keyring() -> &'static KeyRing {
+    KEYRING.get_or_init(|| KeyRing::from_env().expect("JWT_KEYRING is invalid"))
+}
+
+impl KeyRing {
+    fn from_env() -> Result<KeyRing, String> {
+        dotenv().ok();
+        match env::var("JWT_KEYRING") {
+            Ok(path) => KeyRing::from_manifest(&path),
+            Err(_e) => {
+                let secret = env::var("SECRET_KEY").expect("JWT_SECRET must be set");
+                Ok(KeyRing {
+                    active: 0,
+                    keys: vec![Key {
+                        kid: None,
+                        algorithm: Algorithm::HS256,
+                        encoding: Some(EncodingKey::from_secret(secret.as_ref())),
+                        decoding: DecodingKey::from_secret(secret.as_ref()),
+                        jwk: None,
+                        retired_at: None,
+                    }],
+                    grace_period: Duration::zero(),
+                })
+            }
+        }
+    }
+
+    fn from_manifest(path: &str) -> Result<KeyRing, String> {
+        let manifest = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let manifest: KeyRingManifest =
+            serde_json::from_str(&manifest).map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut keys = Vec::new();
+        for key in manifest.keys {
+            keys.push(Key::load(key)?);
+        }
+
+        let active = keys
+            .iter()
+            .position(|key| key.kid.as_deref() == Some(manifest.active.as_str()))
+            .ok_or(format!("active key {} is not in the key ring", manifest.active))?;
+        if keys[active].encoding.is_none() || keys[active].retired_at.is_some() {
+            return Err(format!(
+                "active key {} needs a private key and cannot be retired",
+                manifest.active
+            ));
+        }
+
+        Ok(KeyRing {
+            active,
+            keys,
+            grace_period: Duration::seconds(manifest.grace_period),
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, JwtError> {
+        let key = &self.keys[self.active];
+        let mut header = Header::new(key.algorithm);
+        header.kid = key.kid.clone();
+        // the active key always carries a private key, see from_manifest
+        encode(&header, claims, key.encoding.as_ref().unwrap())
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<TokenData<T>, JwtError> {
+        let header = decode_header(token)?;
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.kid == header.kid && self.is_usable(key))
+            .ok_or_else(|| JwtError::from(ErrorKind::InvalidToken))?;
+        decode::<T>(token, &key.decoding, &Validation::new(key.algorithm))
+    }
+
+    // public keys other services need to verify our tokens offline
+    pub fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: self
+                .keys
+                .iter()
+                .filter(|key| self.is_usable(key))
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+
+    fn is_usable(&self, key: &Key) -> bool {
+        match key.retired_at {
+            Some(retired_at) => Utc::now() < retired_at + self.grace_period,
+            None => true,
+        }
+    }
+}
+
+impl Key {
+    fn load(manifest: KeyManifest) -> Result<Key, String> {
+        let public_pem =
+            fs::read(&manifest.public_key).map_err(|e| format!("{}: {}", manifest.public_key, e))?;
+        let private_pem = match &manifest.private_key {
+            Some(path) => Some(fs::read(path).map_err(|e| format!("{}: {}", path, e))?),
+            None => None,
+        };
+        let invalid = |e: JwtError| format!("key {}: {}", manifest.kid, e);
+
+        let (encoding, decoding, jwk) = match manifest.alg {
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => {
+                let encoding = match &private_pem {
+                    Some(pem) => Some(EncodingKey::from_rsa_pem(pem).map_err(invalid)?),
+                    None => None,
+                };
+                let decoding = DecodingKey::from_rsa_pem(&public_pem).map_err(invalid)?;
+                let public_key = std::str::from_utf8(&public_pem)
+                    .ok()
+                    .and_then(|pem| RsaPublicKey::from_public_key_pem(pem).ok())
+                    .ok_or(format!("key {}: invalid RSA public key", manifest.kid))?;
+                let jwk = json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": manifest.alg,
+                    "kid": manifest.kid,
+                    "n": URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+                    "e": URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+                });
+                (encoding, decoding, jwk)
+            }
+            Algorithm::EdDSA => {
+                let encoding = match &private_pem {
+                    Some(pem) => Some(EncodingKey::from_ed_pem(pem).map_err(invalid)?),
+                    None => None,
+                };
+                let decoding = DecodingKey::from_ed_pem(&public_pem).map_err(invalid)?;
+                // an Ed25519 SubjectPublicKeyInfo ends with the 32 byte public key
+                let der = pem::parse(&public_pem)
+                    .map_err(|e| format!("key {}: {}", manifest.kid, e))?
+                    .contents;
+                if der.len() < 32 {
+                    return Err(format!("key {}: invalid Ed25519 public key", manifest.kid));
+                }
+                let jwk = json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "use": "sig",
+                    "alg": "EdDSA",
+                    "kid": manifest.kid,
+                    "x": URL_SAFE_NO_PAD.encode(&der[der.len() - 32..]),
+                });
+                (encoding, decoding, jwk)
+            }
+            alg => return Err(format!("key {}: unsupported algorithm {:?}", manifest.kid, alg)),
+        };
+
+        Ok(Key {
+            kid: Some(manifest.kid),
+            algorithm: manifest.alg,
+            encoding,
+            decoding,
+            jwk: Some(jwk),
+            retired_at: manifest.retired_at,
+        })
+    }
+}
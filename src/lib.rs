@@ -3,8 +3,12 @@ use diesel::prelude::*;
 use dotenvy::dotenv;
 use std::env;
 
+pub mod bloom;
 pub mod models;
+pub mod password_policy;
 pub mod schema;
+pub mod sms;
+pub mod storage;
 mod mailer;
 
 pub fn establish_connection() -> PgConnection {
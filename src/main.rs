@@ -1,16 +1,27 @@
 extern crate diesel;
 mod db;
 mod handlers;
+mod keyring;
 mod middlewares;
 mod models;
+mod oauth;
 mod repositories;
+mod totp;
 mod utils;
 use dotenvy::dotenv;
 use std::env;
 mod mailer;
 mod schema;
-use crate::handlers::app_config;
+use crate::handlers::{app_config, ORGANIZATION_HEADER};
+use crate::middlewares::auth::CSRF_HEADER;
+use crate::repositories::avatar::AvatarRepository;
+use crate::repositories::data_export::DataExportRepository;
+use crate::repositories::deletion::{purge_interval, DeletionRepository};
+// shared with the breached_passwords binary through the library
+use drgz::password_policy;
+use drgz::storage;
 use actix_cors::Cors;
+use middlewares::rate_limit::RateLimiter;
 use actix_web::{http::header, http::Method, middleware, web::Data, App, HttpServer};
 
 #[actix_web::main] // or #[tokio::main]
@@ -28,6 +39,61 @@ async fn main() -> std::io::Result<()> {
     println!("Starting server at: http://{}", server_addr);
     dotenv().ok();
     let secret_key = env::var("SECRET_KEY").expect("SECRET_KEY must be set");
+    // load signing keys up front so a broken JWT_KEYRING fails at startup
+    keyring::keyring();
+    password_policy::password_policy();
+    utils::check_password_hashing();
+    // a missing S3_* variable or unknown STORAGE_BACKEND fails here rather
+    // than on the first upload
+    storage::storage();
+    // anonymize deleted accounts once their grace period is over and drop
+    // expired data exports
+    let purge_pool = db::establish_connection();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(purge_interval());
+        loop {
+            interval.tick().await;
+            // the purge queries block, keep them off the workers
+            let pool = purge_pool.clone();
+            let purged = actix_web::web::block(move || {
+                pool.get()
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut connection| {
+                        DeletionRepository::purge_expired(&mut connection).map_err(|e| e.to_string())
+                    })
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|purged| purged);
+            match purged {
+                Ok(purged) => {
+                    if purged.count > 0 {
+                        println!("Purged {} deleted accounts", purged.count);
+                    }
+                    for key in purged.avatar_keys {
+                        AvatarRepository::delete_files(&key).await;
+                    }
+                }
+                Err(e) => println!("Error purging deleted accounts: {}", e),
+            }
+            let pool = purge_pool.clone();
+            let removed = actix_web::web::block(move || {
+                pool.get()
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut connection| {
+                        DataExportRepository::remove_expired(&mut connection).map_err(|e| e.to_string())
+                    })
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|removed| removed);
+            if let Err(e) = removed {
+                println!("Error removing expired data exports: {}", e);
+            }
+        }
+    });
+    // shared by all workers so limits count every request
+    let rate_limiter = Data::new(RateLimiter::from_env());
     HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin("http://localhost:8080")
@@ -35,6 +101,8 @@ async fn main() -> std::io::Result<()> {
             .allowed_methods(vec![Method::GET, Method::OPTIONS, Method::POST])
             .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
             .allowed_header(header::CONTENT_TYPE)
+            .allowed_header(header::HeaderName::from_static(CSRF_HEADER))
+            .allowed_header(header::HeaderName::from_static(ORGANIZATION_HEADER))
             .supports_credentials();
 
         App::new()
@@ -43,6 +111,7 @@ async fn main() -> std::io::Result<()> {
             .app_data(Data::new(db::establish_connection()))
             .app_data(Data::new(secret_key.clone()))
             .app_data(Data::new(tera.clone()))
+            .app_data(rate_limiter.clone())
             .configure(app_config)
     })
     .bind(server_addr)?
@@ -1,60 +1,309 @@
 use actix_web::{
-    dev::Payload, http::header::HeaderValue, Error as ActixWebError, FromRequest, HttpRequest,
+    dev::Payload,
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    web::{self, Data},
+    Error as ActixWebError, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
 };
 
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use futures::future::LocalBoxFuture;
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
 use serde::{Deserialize, Serialize};
-use std::future::{ready, Ready};
+use serde_json::json;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
 
-use crate::utils::get_user_id;
+use crate::repositories::api_key::{ApiKeyRepository, API_KEY_PREFIX};
+use crate::repositories::token::TokenRepository;
+use crate::utils::{decode_jwt, TokenError};
+
+// session cookies, set on login when AUTH_COOKIES=true
+pub const ACCESS_TOKEN_COOKIE: &str = "access_token";
+pub const REFRESH_TOKEN_COOKIE: &str = "refresh_token";
+// readable by scripts, echoed back in the X-CSRF-Token header
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// why the credentials sent with a request were not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AuthError {
+    Expired,
+    Invalid,
+    Revoked,
+    // the token could not be checked, e.g. the database is unreachable
+    Internal,
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::Expired => "token_expired",
+            AuthError::Invalid => "invalid_token",
+            AuthError::Revoked => "token_revoked",
+            AuthError::Internal => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            AuthError::Expired => "Token expired",
+            AuthError::Invalid => "Invalid token",
+            AuthError::Revoked => "Token has been revoked",
+            AuthError::Internal => "Could not check the token",
+        }
+    }
+
+    pub fn into_field_error(self) -> FieldError {
+        FieldError::new(self.message(), graphql_value!(self.code().to_string()))
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+// the 401 sent when AUTH_INVALID_TOKEN=reject, shaped like a graphql error
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let AuthError::Internal = self {
+            return HttpResponse::InternalServerError().json(json!({
+                "data": null,
+                "errors": [{
+                    "message": self.message(),
+                    "extensions": self.code(),
+                }],
+            }));
+        }
+        HttpResponse::Unauthorized()
+            .insert_header((
+                header::WWW_AUTHENTICATE,
+                format!(
+                    "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                    self.message()
+                ),
+            ))
+            .json(json!({
+                "data": null,
+                "errors": [{
+                    "message": self.message(),
+                    "extensions": self.code(),
+                }],
+            }))
+    }
+}
+
+// AUTH_INVALID_TOKEN=reject answers requests with bad credentials with a 401,
+// by default they continue anonymously and resolvers needing a user report
+// why the credentials were refused
+fn reject_invalid_tokens() -> bool {
+    dotenv().ok();
+    env::var("AUTH_INVALID_TOKEN").is_ok_and(|policy| policy == "reject")
+}
+
+// the token of an `Authorization: Bearer <token>` header, the scheme is
+// case insensitive and nothing may follow the token
+fn bearer_token(header: &HeaderValue) -> Result<&str, AuthError> {
+    let header = header.to_str().map_err(|_e| AuthError::Invalid)?;
+    let (scheme, token) = header.trim().split_once(' ').ok_or(AuthError::Invalid)?;
+    let token = token.trim_start();
+    if !scheme.eq_ignore_ascii_case("bearer")
+        || token.is_empty()
+        || token.contains(char::is_whitespace)
+    {
+        return Err(AuthError::Invalid);
+    }
+    Ok(token)
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AuthenticationToken {
     pub id: Option<i32>,
     pub authenticated: bool,
+    pub jti: Option<String>,
+    pub expires_at: Option<usize>,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+    // set when the request authenticated with an api key instead of a jwt
+    pub api_key_id: Option<i32>,
+    // staff member behind an impersonation token
+    pub actor_id: Option<i32>,
+    // set when credentials were sent but refused
+    pub error: Option<AuthError>,
+}
+
+impl AuthenticationToken {
+    fn anonymous() -> AuthenticationToken {
+        AuthenticationToken {
+            id: None,
+            authenticated: false,
+            jti: None,
+            expires_at: None,
+            roles: Vec::new(),
+            permissions: Vec::new(),
+            api_key_id: None,
+            actor_id: None,
+            error: None,
+        }
+    }
+
+    async fn authenticate(req: &HttpRequest) -> AuthenticationToken {
+        match AuthenticationToken::verify(req).await {
+            Ok(Some(token)) => token,
+            Ok(None) => AuthenticationToken::anonymous(),
+            Err(error) => AuthenticationToken {
+                error: Some(error),
+                ..AuthenticationToken::anonymous()
+            },
+        }
+    }
+
+    // the Authorization header wins, browsers signed in with session cookies
+    // send the access token as a cookie instead. Ok(None) when the request
+    // carries no credentials at all
+    async fn verify(req: &HttpRequest) -> Result<Option<AuthenticationToken>, AuthError> {
+        let authentication_token = match req.headers().get(header::AUTHORIZATION) {
+            Some(header) if !header.is_empty() => bearer_token(header)?.to_string(),
+            _ => match req.cookie(ACCESS_TOKEN_COOKIE) {
+                Some(cookie) => cookie.value().to_string(),
+                None => return Ok(None),
+            },
+        };
+        let pool = match req.app_data::<Data<Pool<ConnectionManager<PgConnection>>>>() {
+            Some(pool) => pool.clone().into_inner(),
+            None => return Ok(None),
+        };
+        // checking keys and revocations queries the database, keep it off
+        // the workers
+        web::block(move || AuthenticationToken::verify_token(pool, &authentication_token))
+            .await
+            .map_err(|_e| AuthError::Internal)?
+            .map(Some)
+    }
+
+    fn verify_token(
+        pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+        authentication_token: &str,
+    ) -> Result<AuthenticationToken, AuthError> {
+        if authentication_token.starts_with(API_KEY_PREFIX) {
+            let (api_key, permissions) =
+                ApiKeyRepository::new(pool).authenticate(authentication_token)?;
+            return Ok(AuthenticationToken {
+                id: Some(api_key.user_id),
+                authenticated: true,
+                jti: None,
+                expires_at: None,
+                roles: Vec::new(),
+                permissions,
+                api_key_id: Some(api_key.id),
+                actor_id: None,
+                error: None,
+            });
+        }
+
+        let claims = decode_jwt(authentication_token).map_err(|e| match e {
+            TokenError::Expired => AuthError::Expired,
+            _ => AuthError::Invalid,
+        })?;
+        if TokenRepository::new(pool).is_revoked(&claims)? {
+            return Err(AuthError::Revoked);
+        }
+
+        Ok(AuthenticationToken {
+            id: Some(claims.user_id()),
+            authenticated: true,
+            jti: Some(claims.jti),
+            expires_at: Some(claims.exp),
+            roles: claims.roles,
+            permissions: claims.permissions,
+            api_key_id: None,
+            actor_id: claims.actor,
+            error: None,
+        })
+    }
 }
 
 impl FromRequest for AuthenticationToken {
     type Error = ActixWebError;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
+    // the rate limiter authenticates before the handler does, so the
+    // result is kept on the request
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         let req = req.clone();
-        let authorization_header_option: Option<&HeaderValue> =
-            req.headers().get(actix_web::http::header::AUTHORIZATION);
+        Box::pin(async move {
+            let cached = req.extensions().get::<AuthenticationToken>().cloned();
+            let token = match cached {
+                Some(token) => token,
+                None => {
+                    let token = AuthenticationToken::authenticate(&req).await;
+                    req.extensions_mut().insert(token.clone());
+                    token
+                }
+            };
+            match token.error {
+                Some(error) if reject_invalid_tokens() => Err(error.into()),
+                _ => Ok(token),
+            }
+        })
+    }
+}
 
-        if authorization_header_option.is_none() {
-            return ready(Ok(AuthenticationToken {
-                id: None,
-                authenticated: false,
-            }));
-        }
+#[cfg(test)]
+mod tests {
+    use super::{bearer_token, AuthError};
+    use actix_web::http::header::HeaderValue;
 
-        let authentication_token: String = authorization_header_option
-            .unwrap()
-            .to_str()
-            .unwrap_or("")
-            .to_string();
+    fn token(header: &str) -> Result<String, AuthError> {
+        bearer_token(&HeaderValue::from_str(header).unwrap()).map(str::to_string)
+    }
 
-        if authentication_token.is_empty() {
-            return ready(Ok(AuthenticationToken {
-                id: None,
-                authenticated: false,
-            }));
-        }
-        let authentication_token: Vec<&str> = authentication_token.split(" ").collect();
-        let authentication_token = authentication_token[1];
-        let token_result = get_user_id(&authentication_token);
+    #[test]
+    fn reads_the_token_whatever_the_case_of_the_scheme() {
+        assert_eq!(token("Bearer abc.def").unwrap(), "abc.def");
+        assert_eq!(token("bearer abc.def").unwrap(), "abc.def");
+        assert_eq!(token("BEARER abc.def").unwrap(), "abc.def");
+    }
 
-        if token_result == 0 {
-            return ready(Ok(AuthenticationToken {
-                id: None,
-                authenticated: false,
-            }));
-        }
+    #[test]
+    fn ignores_extra_spaces_around_the_token() {
+        assert_eq!(token("Bearer    abc.def").unwrap(), "abc.def");
+        assert_eq!(token("Bearer abc.def   ").unwrap(), "abc.def");
+        assert_eq!(token("   Bearer abc.def").unwrap(), "abc.def");
+    }
 
-        ready(Ok(AuthenticationToken {
-            id: Some(token_result),
-            authenticated: true,
-        }))
+    #[test]
+    fn refuses_anything_after_the_token() {
+        assert_eq!(token("Bearer abc.def ghi"), Err(AuthError::Invalid));
+        assert_eq!(token("Bearer abc.def\tghi"), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn refuses_a_missing_token_or_another_scheme() {
+        assert_eq!(token("Bearer"), Err(AuthError::Invalid));
+        assert_eq!(token("Bearer "), Err(AuthError::Invalid));
+        assert_eq!(token("Bearer    "), Err(AuthError::Invalid));
+        assert_eq!(token("Basic abc.def"), Err(AuthError::Invalid));
+        assert_eq!(token("abc.def"), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn refuses_a_header_that_is_not_ascii() {
+        let header = HeaderValue::from_bytes("Bearer abc\u{e9}".as_bytes()).unwrap();
+        assert_eq!(bearer_token(&header), Err(AuthError::Invalid));
     }
 }
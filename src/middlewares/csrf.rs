@@ -0,0 +1,93 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use serde_json::json;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use crate::middlewares::auth::{
+    ACCESS_TOKEN_COOKIE, CSRF_COOKIE, CSRF_HEADER, REFRESH_TOKEN_COOKIE,
+};
+use crate::middlewares::operation::{read_operation, OperationKind};
+use crate::utils::hash_token;
+
+// double submit check for requests carrying session cookies: anything but a
+// query needs an X-CSRF-Token header matching the csrf_token cookie, which
+// other sites can neither read nor set. Requests without session cookies,
+// e.g. ones using the Authorization header, pass untouched
+pub struct Csrf;
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let has_session_cookie = req.cookie(ACCESS_TOKEN_COOKIE).is_some()
+                || req.cookie(REFRESH_TOKEN_COOKIE).is_some();
+            if !has_session_cookie {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            // only queries are exempt, a body we could not scan is checked too
+            let operation = read_operation(&mut req).await?;
+            if operation.kind != Some(OperationKind::Query) && !csrf_token_matches(&req) {
+                let response = HttpResponse::Forbidden().json(json!({
+                    "data": null,
+                    "errors": [{
+                        "message": "Missing or invalid CSRF token",
+                        "extensions": "csrf_token_invalid",
+                    }],
+                }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
+
+fn csrf_token_matches(req: &ServiceRequest) -> bool {
+    let cookie = match req.cookie(CSRF_COOKIE) {
+        Some(cookie) if !cookie.value().is_empty() => cookie,
+        _ => return false,
+    };
+    let header = match req.headers().get(CSRF_HEADER).and_then(|h| h.to_str().ok()) {
+        Some(header) => header,
+        None => return false,
+    };
+    // compare digests so the time taken says nothing about the token
+    hash_token(cookie.value()) == hash_token(header)
+}
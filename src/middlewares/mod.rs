@@ -1 +1,64 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod csrf;
+pub mod operation;
+pub mod rate_limit;
+
+use actix_web::dev::ConnectionInfo;
+use dotenvy::dotenv;
+use std::env;
+
+// address of the client, only trusting Forwarded / X-Forwarded-For when
+// TRUST_PROXY_HEADERS=true since clients can set those themselves
+pub fn client_ip(info: &ConnectionInfo) -> Option<String> {
+    dotenv().ok();
+    let trust_proxy = env::var("TRUST_PROXY_HEADERS").is_ok_and(|trust| trust == "true");
+    let addr = if trust_proxy {
+        info.realip_remote_addr()
+    } else {
+        info.peer_addr()
+    };
+    addr.map(|addr| addr.to_string())
+}
+
+// what a multipart upload may hold, read by the operation middleware to
+// bound the body and by the handler to check each file
+pub struct UploadLimits {
+    // per file
+    pub max_bytes: usize,
+    // per request
+    pub max_files: usize,
+    pub allowed_types: Vec<String>,
+}
+
+impl UploadLimits {
+    // UPLOAD_MAX_BYTES (default 5MB), UPLOAD_MAX_FILES (default 1) and
+    // UPLOAD_ALLOWED_TYPES, comma separated content types
+    pub fn from_env() -> UploadLimits {
+        dotenv().ok();
+        let max_bytes = env::var("UPLOAD_MAX_BYTES")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(5 * 1024 * 1024);
+        let max_files = env::var("UPLOAD_MAX_FILES")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(1);
+        let allowed_types = env::var("UPLOAD_ALLOWED_TYPES")
+            .unwrap_or_else(|_| "image/png,image/jpeg,image/webp".to_string())
+            .split(',')
+            .map(|content_type| content_type.trim().to_lowercase())
+            .filter(|content_type| !content_type.is_empty())
+            .collect();
+        UploadLimits {
+            max_bytes,
+            max_files,
+            allowed_types,
+        }
+    }
+
+    // the most a multipart request body may hold, the files plus room for
+    // the operations and map fields
+    pub fn body_limit(&self) -> usize {
+        self.max_bytes * self.max_files + 1024 * 1024
+    }
+}
@@ -0,0 +1,289 @@
+use super::UploadLimits;
+use actix_multipart::Multipart;
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::{ErrorPayloadTooLarge, PayloadError},
+    http::header,
+    web::{Bytes, BytesMut},
+    Error, HttpMessage,
+};
+use futures::{Stream, StreamExt};
+use juniper::parser::{Lexer, Token};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+// what a graphql request is about to run, read by middlewares before the
+// handler executes it. `kind` is None when the body could not be scanned,
+// juniper then either rejects it or runs something we could not see
+#[derive(Clone, Default)]
+pub struct Operation {
+    pub kind: Option<OperationKind>,
+    pub root_fields: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct GraphQLBody {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+// scan the graphql body once and keep the result on the request, the body
+// is handed back so the handler can still read it
+pub async fn read_operation(req: &mut ServiceRequest) -> Result<Operation, Error> {
+    if let Some(operation) = req.extensions().get::<Operation>() {
+        return Ok(operation.clone());
+    }
+    let multipart = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.to_lowercase().starts_with("multipart/form-data"));
+    let (body, request) = if multipart {
+        let body = read_body(req, UploadLimits::from_env().body_limit()).await?;
+        let request = operations_field(req, body.clone()).await;
+        (body, request)
+    } else {
+        let body = req.extract::<Bytes>().await?;
+        (body.clone(), Some(body))
+    };
+    let operation = request
+        .and_then(|request| serde_json::from_slice::<GraphQLBody>(&request).ok())
+        .and_then(|request| parse(&request.query, request.operation_name.as_deref()))
+        .unwrap_or_default();
+    req.set_payload(bytes_to_payload(body));
+    req.extensions_mut().insert(operation.clone());
+    Ok(operation)
+}
+
+// the whole body of an upload, which is larger than the JSON extractor allows
+async fn read_body(req: &mut ServiceRequest, limit: usize) -> Result<Bytes, Error> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(ErrorPayloadTooLarge("Request body is too large"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+// the graphql request of a multipart body, the spec sends it first
+async fn operations_field(req: &ServiceRequest, body: Bytes) -> Option<Bytes> {
+    let mut multipart = Multipart::new(req.headers(), bytes_to_payload(body));
+    let mut field = multipart.next().await?.ok()?;
+    if field.name() != Some("operations") {
+        return None;
+    }
+    field.bytes(1024 * 1024).await.ok()?.ok()
+}
+
+fn bytes_to_payload(body: Bytes) -> Payload {
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(futures::stream::once(async move { Ok(body) }));
+    Payload::from(stream)
+}
+
+enum Item<'a> {
+    Field(&'a str),
+    Spread(&'a str),
+}
+
+// the selected operation with the names of the root fields it runs,
+// following fragment spreads. None when the document does not parse
+fn parse(query: &str, operation_name: Option<&str>) -> Option<Operation> {
+    let mut tokens = Vec::new();
+    for token in Lexer::new(query) {
+        match token {
+            Ok(token) if token.item == Token::EndOfFile => break,
+            Ok(token) => tokens.push(token.item),
+            Err(_e) => return None,
+        }
+    }
+
+    let mut operations = Vec::new();
+    let mut fragments = HashMap::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i] {
+            Token::CurlyOpen => {
+                operations.push((OperationKind::Query, None, selection_set(&tokens, &mut i)))
+            }
+            Token::Name(keyword @ ("query" | "mutation" | "subscription")) => {
+                let kind = match keyword {
+                    "mutation" => OperationKind::Mutation,
+                    "subscription" => OperationKind::Subscription,
+                    _ => OperationKind::Query,
+                };
+                i += 1;
+                let name = match tokens.get(i) {
+                    Some(Token::Name(name)) => {
+                        i += 1;
+                        Some(*name)
+                    }
+                    _ => None,
+                };
+                skip_group(&tokens, &mut i);
+                skip_directives(&tokens, &mut i);
+                operations.push((kind, name, selection_set(&tokens, &mut i)));
+            }
+            Token::Name("fragment") => {
+                let name = match tokens.get(i + 1) {
+                    Some(Token::Name(name)) => *name,
+                    _ => return None,
+                };
+                // fragment Name on Type
+                i += 4;
+                skip_directives(&tokens, &mut i);
+                fragments.insert(name, selection_set(&tokens, &mut i));
+            }
+            _ => return None,
+        }
+    }
+
+    let operation = match operation_name {
+        Some(operation_name) => operations
+            .into_iter()
+            .find(|(_, name, _)| *name == Some(operation_name)),
+        None if operations.len() == 1 => operations.pop(),
+        None => None,
+    };
+    let (kind, _, mut pending) = operation?;
+    let mut fields = Vec::new();
+    let mut seen = HashSet::new();
+    while let Some(item) = pending.pop() {
+        match item {
+            Item::Field(name) => fields.push(name.to_string()),
+            Item::Spread(name) => {
+                if seen.insert(name) {
+                    if let Some(items) = fragments.remove(name) {
+                        pending.extend(items);
+                    }
+                }
+            }
+        }
+    }
+    // aliases of the same field are kept, each one runs the resolver
+    fields.sort();
+    Some(Operation {
+        kind: Some(kind),
+        root_fields: fields,
+    })
+}
+
+// items of the selection set starting at tokens[i], inline fragments are
+// flattened since their fields run at the same level
+fn selection_set<'a>(tokens: &[Token<'a>], i: &mut usize) -> Vec<Item<'a>> {
+    let mut items = Vec::new();
+    if tokens.get(*i) != Some(&Token::CurlyOpen) {
+        *i = tokens.len();
+        return items;
+    }
+    *i += 1;
+    while let Some(token) = tokens.get(*i) {
+        match token {
+            Token::CurlyClose => {
+                *i += 1;
+                break;
+            }
+            Token::Ellipsis => {
+                *i += 1;
+                match tokens.get(*i) {
+                    Some(Token::Name("on")) => {
+                        *i += 2;
+                        skip_directives(tokens, i);
+                        items.extend(selection_set(tokens, i));
+                    }
+                    Some(Token::Name(name)) => {
+                        items.push(Item::Spread(name));
+                        *i += 1;
+                        skip_directives(tokens, i);
+                    }
+                    _ => {
+                        skip_directives(tokens, i);
+                        items.extend(selection_set(tokens, i));
+                    }
+                }
+            }
+            Token::Name(name) => {
+                *i += 1;
+                // alias: field
+                let name = if tokens.get(*i) == Some(&Token::Colon) {
+                    *i += 1;
+                    match tokens.get(*i) {
+                        Some(Token::Name(field)) => {
+                            *i += 1;
+                            *field
+                        }
+                        _ => break,
+                    }
+                } else {
+                    *name
+                };
+                items.push(Item::Field(name));
+                skip_group(tokens, i);
+                skip_directives(tokens, i);
+                if tokens.get(*i) == Some(&Token::CurlyOpen) {
+                    selection_set(tokens, i);
+                }
+            }
+            _ => *i += 1,
+        }
+    }
+    items
+}
+
+// skip a parenthesized argument or variable list
+fn skip_group(tokens: &[Token], i: &mut usize) {
+    if tokens.get(*i) != Some(&Token::ParenOpen) {
+        return;
+    }
+    let mut depth = 0;
+    while let Some(token) = tokens.get(*i) {
+        *i += 1;
+        match token {
+            Token::ParenOpen => depth += 1,
+            Token::ParenClose => {
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn skip_directives(tokens: &[Token], i: &mut usize) {
+    while tokens.get(*i) == Some(&Token::At) {
+        *i += 2;
+        skip_group(tokens, i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, OperationKind};
+
+    #[test]
+    fn aliases_are_counted_once_each() {
+        let operation = parse(
+            "mutation { a: verifyMfaLogin(challengeToken: \"t\", code: \"1\") { token } \
+            b: verifyMfaLogin(challengeToken: \"t\", code: \"2\") { token } }",
+            None,
+        )
+        .unwrap();
+        assert!(operation.kind == Some(OperationKind::Mutation));
+        assert_eq!(operation.root_fields, vec!["verifyMfaLogin", "verifyMfaLogin"]);
+    }
+}
@@ -0,0 +1,372 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web::{self, Data},
+    Error, HttpResponse,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use futures::future::LocalBoxFuture;
+use r2d2::Pool;
+use rand::Rng;
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::middlewares::auth::AuthenticationToken;
+use crate::middlewares::client_ip;
+use crate::middlewares::operation::read_operation;
+use crate::schema::rate_limit_buckets;
+
+// a token bucket holding up to `capacity` requests, refilled evenly over
+// `period` seconds. Written as capacity/period, e.g. 120/60
+#[derive(Clone, Copy)]
+pub struct Limit {
+    capacity: f64,
+    refill_per_second: f64,
+}
+
+impl Limit {
+    fn parse(limit: &str) -> Option<Limit> {
+        let (capacity, period) = limit.trim().split_once('/')?;
+        let capacity = capacity.trim().parse::<f64>().ok()?;
+        let period = period.trim().parse::<f64>().ok()?;
+        if capacity < 1.0 || period <= 0.0 {
+            return None;
+        }
+        Some(Limit {
+            capacity,
+            refill_per_second: capacity / period,
+        })
+    }
+
+    // time until a bucket holding `tokens` has a whole token again
+    fn retry_after(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64(((1.0 - tokens) / self.refill_per_second).max(0.0))
+    }
+}
+
+pub trait RateLimitStore: Send + Sync {
+    // take a token from the bucket, or say how long until one is available
+    fn take(&self, key: &str, limit: &Limit) -> Result<(), Duration>;
+}
+
+// the limit is kept with the bucket, eviction checks each bucket against
+// its own limit rather than the one of the request that triggered it
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    limit: Limit,
+}
+
+// buckets kept in process, limits are per server instance
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+const MEMORY_STORE_MAX_BUCKETS: usize = 10_000;
+
+impl RateLimitStore for MemoryStore {
+    fn take(&self, key: &str, limit: &Limit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MEMORY_STORE_MAX_BUCKETS && !buckets.contains_key(key) {
+            // a refilled bucket is the same as no bucket
+            buckets.retain(|_, bucket| {
+                bucket.tokens
+                    + (now - bucket.updated_at).as_secs_f64() * bucket.limit.refill_per_second
+                    < bucket.limit.capacity
+            });
+        }
+        if buckets.len() >= MEMORY_STORE_MAX_BUCKETS && !buckets.contains_key(key) {
+            // every bucket is still draining, give up the one idle longest
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                buckets.remove(&oldest);
+            }
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.capacity,
+            updated_at: now,
+            limit: *limit,
+        });
+        bucket.limit = *limit;
+        let tokens = (bucket.tokens
+            + (now - bucket.updated_at).as_secs_f64() * limit.refill_per_second)
+            .min(limit.capacity);
+        bucket.updated_at = now;
+        if tokens < 1.0 {
+            bucket.tokens = tokens;
+            return Err(limit.retry_after(tokens));
+        }
+        bucket.tokens = tokens - 1.0;
+        Ok(())
+    }
+}
+
+// buckets kept in the rate_limit_buckets table, limits hold across instances
+pub struct PostgresStore {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl PostgresStore {
+    pub fn new(pool: Pool<ConnectionManager<PgConnection>>) -> PostgresStore {
+        PostgresStore { pool }
+    }
+
+    fn try_take(&self, key: &str, limit: &Limit) -> QueryResult<Result<(), Duration>> {
+        let mut connection = self
+            .pool
+            .get()
+            .map_err(|e| diesel::result::Error::QueryBuilderError(Box::new(e)))?;
+        let connection = &mut *connection;
+        let now = Utc::now().naive_utc();
+
+        // refill and take in one statement so concurrent requests cannot
+        // both spend the last token
+        let sql = "INSERT INTO rate_limit_buckets (key, tokens, updated_at) VALUES ($1, $2 - 1, $3) \
+            ON CONFLICT (key) DO UPDATE SET \
+            tokens = LEAST($2, rate_limit_buckets.tokens + EXTRACT(EPOCH FROM ($3 - rate_limit_buckets.updated_at))::float8 * $4) - 1, \
+            updated_at = $3 \
+            WHERE LEAST($2, rate_limit_buckets.tokens + EXTRACT(EPOCH FROM ($3 - rate_limit_buckets.updated_at))::float8 * $4) >= 1";
+        let taken = diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(key)
+            .bind::<diesel::sql_types::Double, _>(limit.capacity)
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .bind::<diesel::sql_types::Double, _>(limit.refill_per_second)
+            .execute(connection)?;
+
+        if rand::thread_rng().gen_range(0..100) == 0 {
+            diesel::sql_query("DELETE FROM rate_limit_buckets WHERE updated_at < $1")
+                .bind::<diesel::sql_types::Timestamp, _>(now - chrono::Duration::days(1))
+                .execute(connection)?;
+        }
+        if taken > 0 {
+            return Ok(Ok(()));
+        }
+
+        let (tokens, updated_at) = rate_limit_buckets::table
+            .filter(rate_limit_buckets::key.eq(key))
+            .select((rate_limit_buckets::tokens, rate_limit_buckets::updated_at))
+            .first::<(f64, NaiveDateTime)>(connection)?;
+        let elapsed = (now - updated_at).num_milliseconds() as f64 / 1000.0;
+        let tokens = (tokens + elapsed * limit.refill_per_second).min(limit.capacity);
+        Ok(Err(limit.retry_after(tokens)))
+    }
+}
+
+impl RateLimitStore for PostgresStore {
+    fn take(&self, key: &str, limit: &Limit) -> Result<(), Duration> {
+        // an unavailable store should not take the api down with it
+        self.try_take(key, limit).unwrap_or_else(|e| {
+            println!("Rate limit store error: {}", e);
+            Ok(())
+        })
+    }
+}
+
+pub struct RateLimiter {
+    default: Limit,
+    // limits for single root fields, e.g. register or requestPasswordReset
+    operations: HashMap<String, Limit>,
+    store: Box<dyn RateLimitStore>,
+}
+
+const DEFAULT_LIMIT: &str = "120/60";
+const DEFAULT_OPERATION_LIMITS: &str = "login=20/300,register=5/3600,requestPasswordReset=5/3600,requestLoginLink=5/3600,requestAccountUnlock=5/3600,requestEmailChange=5/3600,setPhone=5/3600,requestPhoneLogin=5/3600,phoneLogin=20/300,verifyMfaLogin=10/300,resendVerificationEmail=5/3600,exportMyData=3/3600,uploadAvatar=20/3600";
+
+impl RateLimiter {
+    // RATE_LIMIT=120/60
+    // RATE_LIMIT_OPERATIONS=register=5/3600,login=20/300
+    // RATE_LIMIT_STORE=memory|postgres
+    pub fn from_env() -> RateLimiter {
+        dotenv().ok();
+        let default = env::var("RATE_LIMIT").unwrap_or_else(|_| DEFAULT_LIMIT.to_string());
+        let default = Limit::parse(&default).expect("RATE_LIMIT must look like 120/60");
+
+        let operations = env::var("RATE_LIMIT_OPERATIONS")
+            .unwrap_or_else(|_| DEFAULT_OPERATION_LIMITS.to_string());
+        let operations = operations
+            .split(',')
+            .filter(|operation| !operation.trim().is_empty())
+            .map(|operation| {
+                let (name, limit) = operation
+                    .split_once('=')
+                    .and_then(|(name, limit)| Some((name.trim().to_string(), Limit::parse(limit)?)))
+                    .expect("RATE_LIMIT_OPERATIONS must look like register=5/3600,login=20/300");
+                (name, limit)
+            })
+            .collect();
+
+        let store: Box<dyn RateLimitStore> = match env::var("RATE_LIMIT_STORE").as_deref() {
+            Ok("postgres") => Box::new(PostgresStore::new(crate::db::establish_connection())),
+            _ => Box::new(MemoryStore::default()),
+        };
+
+        RateLimiter {
+            default,
+            operations,
+            store,
+        }
+    }
+
+    // charge the client's bucket and the bucket of every limited root field
+    // the request runs, once per alias, returns the longest wait when any is empty
+    fn check(&self, client: &str, fields: &[String]) -> Result<(), Duration> {
+        let mut retry_after = self.store.take(client, &self.default).err();
+        for field in fields {
+            if let Some(limit) = self.operations.get(field) {
+                if let Err(wait) = self.store.take(&format!("{}:{}", client, field), limit) {
+                    retry_after = Some(retry_after.map_or(wait, |longest| longest.max(wait)));
+                }
+            }
+        }
+        match retry_after {
+            Some(retry_after) => Err(retry_after),
+            None => Ok(()),
+        }
+    }
+}
+
+// limits requests to the resource it wraps, using the RateLimiter in app data
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let limiter = match req.app_data::<Data<RateLimiter>>() {
+                Some(limiter) => limiter.clone(),
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            let token_auth = req.extract::<AuthenticationToken>().await?;
+            let client = match (token_auth.api_key_id, token_auth.id) {
+                (Some(api_key_id), _) => format!("key:{}", api_key_id),
+                (None, Some(id)) if token_auth.authenticated => format!("user:{}", id),
+                _ => format!(
+                    "ip:{}",
+                    client_ip(&req.connection_info()).unwrap_or_default()
+                ),
+            };
+
+            let operation = read_operation(&mut req).await?;
+            // the postgres store queries the database, keep it off the workers
+            let checked = web::block(move || limiter.check(&client, &operation.root_fields)).await;
+            // like an unavailable store, a failed check lets the request through
+            if let Ok(Err(retry_after)) = checked {
+                let seconds = retry_after.as_secs() + 1;
+                let response = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, seconds.to_string()))
+                    .json(json!({
+                        "data": null,
+                        "errors": [{
+                            "message": format!("Too many requests, retry in {} seconds", seconds),
+                            "extensions": "rate_limited",
+                        }],
+                    }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limit, MemoryStore, RateLimitStore, MEMORY_STORE_MAX_BUCKETS};
+
+    #[test]
+    fn eviction_uses_each_buckets_own_limit() {
+        let store = MemoryStore::default();
+        let slow = Limit::parse("1/3600").unwrap();
+        let fast = Limit::parse("1/0.001").unwrap();
+        assert!(store.take("fast", &fast).is_ok());
+        for i in 1..MEMORY_STORE_MAX_BUCKETS {
+            assert!(store.take(&format!("slow-{}", i), &slow).is_ok());
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        // the refilled fast bucket makes room, the drained slow buckets stay
+        assert!(store.take("new", &slow).is_ok());
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MEMORY_STORE_MAX_BUCKETS);
+        assert!(!buckets.contains_key("fast"));
+        assert!(buckets.contains_key("slow-1"));
+    }
+
+    #[test]
+    fn a_full_store_drops_the_bucket_idle_longest() {
+        let store = MemoryStore::default();
+        let slow = Limit::parse("1/3600").unwrap();
+        assert!(store.take("slow-0", &slow).is_ok());
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        for i in 1..MEMORY_STORE_MAX_BUCKETS {
+            assert!(store.take(&format!("slow-{}", i), &slow).is_ok());
+        }
+
+        assert!(store.take("new", &slow).is_ok());
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MEMORY_STORE_MAX_BUCKETS);
+        assert!(!buckets.contains_key("slow-0"));
+        assert!(buckets.contains_key("slow-1"));
+    }
+
+    #[test]
+    fn eviction_drops_refilled_buckets() {
+        let store = MemoryStore::default();
+        let fast = Limit::parse("1/0.001").unwrap();
+        for i in 0..MEMORY_STORE_MAX_BUCKETS {
+            assert!(store.take(&format!("fast-{}", i), &fast).is_ok());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        assert!(store.take("new", &fast).is_ok());
+        assert_eq!(store.buckets.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,35 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use juniper::{GraphQLInputObject, GraphQLObject};
+use serde::{Deserialize, Serialize};
+
+// a long lived key for scripts and services, only its hash is stored
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct ApiKey {
+    pub id: i32,
+    #[graphql(skip)]
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    #[graphql(skip)]
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+// the full key is only ever shown once, on creation
+#[derive(GraphQLObject)]
+pub struct CreatedApiKey {
+    pub key: String,
+    pub api_key: ApiKey,
+}
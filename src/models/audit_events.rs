@@ -0,0 +1,16 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use juniper::GraphQLObject;
+use serde::{Deserialize, Serialize};
+
+// something a staff member did to or as a user
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct AuditEvent {
+    pub id: i32,
+    pub actor_id: Option<i32>,
+    pub user_id: Option<i32>,
+    pub action: String,
+    pub details: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+}
@@ -0,0 +1,28 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use juniper::GraphQLObject;
+use serde::{Deserialize, Serialize};
+
+pub mod export_status {
+    pub const PENDING: &str = "pending";
+    pub const READY: &str = "ready";
+    pub const FAILED: &str = "failed";
+}
+
+// a copy of everything stored about a user, built in the background and
+// downloaded through an emailed link until it expires
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct DataExport {
+    pub id: i32,
+    pub user_id: i32,
+    pub requested_by: Option<i32>,
+    pub zipped: bool,
+    pub status: String,
+    #[graphql(skip)]
+    pub file_path: Option<String>,
+    #[graphql(skip)]
+    pub token_hash: Option<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
@@ -0,0 +1,29 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use juniper::GraphQLObject;
+use serde::{Deserialize, Serialize};
+
+// an external account (google, github, ...) linked to a user
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct Identity {
+    pub id: i32,
+    #[graphql(skip)]
+    pub user_id: i32,
+    pub provider: String,
+    #[graphql(skip)]
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Serialize, Deserialize, Queryable)]
+pub struct OAuthState {
+    pub state: String,
+    pub provider: String,
+    pub code_verifier: String,
+    pub link_user_id: Option<i32>,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    #[serde(skip)]
+    pub nonce_hash: String,
+}
@@ -0,0 +1,32 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use juniper::GraphQLObject;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize, Queryable)]
+pub struct UserMfa {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: Option<i64>,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(GraphQLObject)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(GraphQLObject)]
+pub struct MfaRecoveryCodes {
+    pub codes: Vec<String>,
+}
+
+#[derive(GraphQLObject)]
+pub struct MfaChallenge {
+    // pass to verifyMfaLogin together with a TOTP or recovery code
+    pub challenge_token: String,
+    pub expires_in: i32,
+}
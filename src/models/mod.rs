@@ -1,2 +1,12 @@
 
+pub mod api_keys;
+pub mod audit_events;
+pub mod data_exports;
+pub mod identities;
+pub mod mfa;
+pub mod organizations;
+pub mod privacy;
+pub mod refresh_tokens;
+pub mod roles;
+pub mod sms_codes;
 pub mod users;
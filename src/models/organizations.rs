@@ -0,0 +1,90 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use juniper::GraphQLObject;
+use serde::{Deserialize, Serialize};
+
+// roles inside an organization, each one can do everything the ones below it can
+pub mod org_roles {
+    pub const OWNER: &str = "owner";
+    pub const ADMIN: &str = "admin";
+    pub const MEMBER: &str = "member";
+
+    // position of a role, None for unknown roles
+    pub fn rank(role: &str) -> Option<u8> {
+        match role {
+            OWNER => Some(3),
+            ADMIN => Some(2),
+            MEMBER => Some(1),
+            _ => None,
+        }
+    }
+
+    pub fn at_least(role: &str, required: &str) -> bool {
+        match (rank(role), rank(required)) {
+            (Some(role), Some(required)) => role >= required,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct Organization {
+    pub id: i32,
+    pub name: String,
+    pub created_by: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct Membership {
+    pub id: i32,
+    pub organization_id: i32,
+    pub user_id: i32,
+    pub role: String,
+    pub created_at: NaiveDateTime,
+}
+
+// an organization the user belongs to and their role in it
+#[derive(GraphQLObject)]
+pub struct OrganizationMembership {
+    pub organization: Organization,
+    pub role: String,
+}
+
+// a member as listed to the rest of the organization
+#[derive(GraphQLObject)]
+pub struct Member {
+    pub user_id: i32,
+    pub username: String,
+    // only shown to admins and to the member themselves
+    pub email: Option<String>,
+    pub role: String,
+    pub joined_at: NaiveDateTime,
+}
+
+// only the hash of the emailed token is stored
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct Invitation {
+    pub id: i32,
+    pub organization_id: i32,
+    pub email: String,
+    pub role: String,
+    pub invited_by: Option<i32>,
+    #[graphql(skip)]
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+    pub declined_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Invitation {
+    pub fn is_pending(&self, now: NaiveDateTime) -> bool {
+        self.accepted_at.is_none()
+            && self.declined_at.is_none()
+            && self.revoked_at.is_none()
+            && self.expires_at > now
+    }
+}
@@ -0,0 +1,39 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::Queryable;
+use juniper::{GraphQLInputObject, GraphQLObject};
+use serde::{Deserialize, Serialize};
+
+// which profile fields other users may see, users without a row show
+// everything
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct PrivacySettings {
+    #[graphql(skip)]
+    pub user_id: i32,
+    pub show_name: bool,
+    pub show_city: bool,
+    pub show_state: bool,
+    pub show_country: bool,
+    pub updated_at: NaiveDateTime,
+}
+
+impl PrivacySettings {
+    pub fn defaults(user_id: i32) -> PrivacySettings {
+        PrivacySettings {
+            user_id,
+            show_name: true,
+            show_city: true,
+            show_state: true,
+            show_country: true,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+// fields left out keep their current value
+#[derive(GraphQLInputObject)]
+pub struct UpdatePrivacySettings {
+    pub show_name: Option<bool>,
+    pub show_city: Option<bool>,
+    pub show_state: Option<bool>,
+    pub show_country: Option<bool>,
+}
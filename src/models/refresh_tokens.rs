@@ -0,0 +1,15 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize, Queryable)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub family_id: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
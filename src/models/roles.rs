@@ -0,0 +1,22 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use juniper::GraphQLObject;
+use serde::{Deserialize, Serialize};
+
+// roles derived from the user flags rather than the user_roles table
+pub const SUPERUSER_ROLE: &str = "superuser";
+pub const STAFF_ROLE: &str = "staff";
+
+pub mod permissions {
+    pub const USERS_READ: &str = "users:read";
+    pub const USERS_WRITE: &str = "users:write";
+    pub const ROLES_MANAGE: &str = "roles:manage";
+}
+
+#[derive(Clone, Serialize, Deserialize, GraphQLObject, Queryable)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: NaiveDateTime,
+}
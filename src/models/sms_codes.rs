@@ -0,0 +1,17 @@
+use chrono::NaiveDateTime;
+use diesel::Queryable;
+use serde::{Deserialize, Serialize};
+
+// a one-time code texted to a phone, only its hash is stored
+#[derive(Clone, Serialize, Deserialize, Queryable)]
+pub struct SmsCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub phone: String,
+    pub purpose: String,
+    pub code_hash: String,
+    pub attempts: i32,
+    pub expires_at: NaiveDateTime,
+    pub consumed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
@@ -4,6 +4,8 @@ use juniper::{graphql_value, FieldError, GraphQLInputObject, GraphQLObject};
 use serde::{Deserialize, Serialize};
 use tokio_pg_mapper_derive::PostgresMapper;
 
+use crate::models::privacy::PrivacySettings;
+use crate::password_policy::password_policy;
 use crate::schema::users::dsl::*;
 use diesel::prelude::*;
 
@@ -28,6 +30,61 @@ pub struct User {
     pub is_superuser: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    #[graphql(skip)]
+    pub failed_login_count: i32,
+    // set while the account is locked after too many failed logins
+    pub locked_until: Option<NaiveDateTime>,
+    // when the account was deleted, it is anonymized (purged) after a grace period
+    pub deleted_at: Option<NaiveDateTime>,
+    pub purged_at: Option<NaiveDateTime>,
+    // storage key shared by the resized avatar images
+    #[graphql(skip)]
+    pub avatar_key: Option<String>,
+    pub avatar_url: Option<String>,
+    pub avatar_thumbnail_url: Option<String>,
+}
+
+// the public side of an account. Fields the user chose to hide are null and
+// `account`, with contact details and account flags, is only filled in for
+// the user themselves and staff
+#[derive(Clone, GraphQLObject)]
+pub struct UserProfile {
+    pub id: i32,
+    pub username: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub city: Option<String>,
+    pub state: Option<String>,
+    pub country: Option<String>,
+    pub avatar_url: Option<String>,
+    pub avatar_thumbnail_url: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub account: Option<User>,
+}
+
+impl UserProfile {
+    pub fn new(user: User, settings: &PrivacySettings, full_access: bool) -> UserProfile {
+        let visible = |shown: bool, value: &Option<String>| {
+            if full_access || shown {
+                value.clone()
+            } else {
+                None
+            }
+        };
+        UserProfile {
+            id: user.id,
+            username: user.username.clone(),
+            first_name: visible(settings.show_name, &user.first_name),
+            last_name: visible(settings.show_name, &user.last_name),
+            city: visible(settings.show_city, &user.city),
+            state: visible(settings.show_state, &user.state),
+            country: visible(settings.show_country, &user.country),
+            avatar_url: user.avatar_url.clone(),
+            avatar_thumbnail_url: user.avatar_thumbnail_url.clone(),
+            created_at: user.created_at,
+            account: if full_access { Some(user) } else { None },
+        }
+    }
 }
 
 #[derive(GraphQLInputObject)]
@@ -38,6 +95,55 @@ pub struct UserRegister {
     pub password2: String,
 }
 
+// format check for an address, on its own for addresses that need not be
+// free, e.g. invitations
+pub fn validate_email_format(new_email: &str) -> Result<(), FieldError> {
+    // email length
+    if new_email.len() < 5 {
+        return Err(FieldError::new(
+            "Email is too short",
+            graphql_value!("email_too_short".to_string()),
+        ));
+    }
+    // validate email regex
+    let re = regex::Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$").unwrap();
+    if !re.is_match(new_email) {
+        return Err(FieldError::new(
+            "Email is invalid",
+            graphql_value!("email_invalid".to_string()),
+        ));
+    }
+    Ok(())
+}
+
+// format and uniqueness checks for an address a user wants to use, shared by
+// registration and email changes
+pub fn validate_email(conn: &mut PgConnection, new_email: &str) -> Result<(), FieldError> {
+    validate_email_format(new_email)?;
+
+    // // query db for email
+    let result = users
+        .filter(email.eq(new_email))
+        .filter(deleted.eq(false))
+        .first::<User>(conn)
+        .optional()
+        .map_err(|_e| {
+            FieldError::new(
+                "Database error",
+                graphql_value!("internal_error".to_string()),
+            )
+        })?;
+
+    if result.is_some() {
+        return Err(FieldError::new(
+            "user with email already exists",
+            graphql_value!("email_already_exists".to_string()),
+        ));
+    }
+
+    Ok(())
+}
+
 impl UserRegister {
     pub fn validate(&self, conn: &mut PgConnection) -> Result<(), FieldError> {
         // errors arr
@@ -47,59 +153,9 @@ impl UserRegister {
                 graphql_value!("passwords_do_not_match".to_string()),
             ));
         }
-        // password length
-        if self.password1.len() < 5 {
-            return Err(FieldError::new(
-                "Password is too short",
-                graphql_value!("password_too_short".to_string()),
-            ));
-        }
-
-        // validate email too weak
-        let re_alpha = regex::Regex::new(r"[a-zA-Z]").unwrap();
-        let re_digit = regex::Regex::new(r"\d").unwrap();
-
-        if !(re_alpha.find(&self.password1).is_some() && re_digit.find(&self.password1).is_some()) {
-            return Err(FieldError::new(
-                "Password is too weak",
-                graphql_value!("password_too_weak".to_string()),
-            ));
-        }
-
-        // email length
-        if self.email.len() < 5 {
-            return Err(FieldError::new(
-                "Email is too short",
-                graphql_value!("email_too_short".to_string()),
-            ));
-        }
-        // validate email regex
-        let re = regex::Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$").unwrap();
-        if !re.is_match(&self.email) {
-            return Err(FieldError::new(
-                "Email is invalid",
-                graphql_value!("email_invalid".to_string()),
-            ));
-        }
-
-        // // query db for email
-        let result = users
-            .filter(email.eq(&self.email))
-            .first::<User>(conn)
-            .optional()
-            .map_err(|_e| {
-                FieldError::new(
-                    "Database error",
-                    graphql_value!("internal_error".to_string()),
-                )
-            })?;
+        password_policy().validate(&self.password1, &[&self.username, &self.email])?;
 
-        if result.is_some() {
-            return Err(FieldError::new(
-                "user with email already exists",
-                graphql_value!("email_already_exists".to_string()),
-            ));
-        }
+        validate_email(conn, &self.email)?;
 
         // username length
         if self.username.len() < 3 {
@@ -112,6 +168,7 @@ impl UserRegister {
         // // query db for username
         let result = users
             .filter(username.eq(&self.username))
+            .filter(deleted.eq(false))
             .first::<User>(conn)
             .optional()
             .map_err(|_e| {
@@ -146,7 +203,7 @@ pub struct ChangePassword {
 }
 
 impl ChangePassword {
-    pub fn validate(&self) -> Result<(), FieldError> {
+    pub fn validate(&self, user: &User) -> Result<(), FieldError> {
         // errors arr
         if self.password1 != self.password2 {
             return Err(FieldError::new(
@@ -154,25 +211,167 @@ impl ChangePassword {
                 graphql_value!("passwords_do_not_match".to_string()),
             ));
         }
-        // password length
-        if self.password1.len() < 5 {
-            return Err(FieldError::new(
-                "Password is too short",
-                graphql_value!("password_too_short".to_string()),
-            ));
+        password_policy().validate(&self.password1, &[&user.username, &user.email])?;
+
+        Ok(())
+    }
+}
+
+// ISO 3166-1 alpha-2
+const COUNTRY_CODES: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+const PROFILE_FIELD_MAX_LENGTH: usize = 100;
+
+// fields left out are kept, fields sent as null are cleared
+#[derive(GraphQLInputObject)]
+pub struct UpdateProfile {
+    pub first_name: juniper::Nullable<String>,
+    pub last_name: juniper::Nullable<String>,
+    pub city: juniper::Nullable<String>,
+    pub state: juniper::Nullable<String>,
+    // ISO 3166-1 alpha-2 code, e.g. "TH"
+    pub country: juniper::Nullable<String>,
+}
+
+// the columns an UpdateProfile changes, None leaves a column alone
+#[derive(AsChangeset)]
+#[diesel(table_name = crate::schema::users)]
+pub struct ProfileChanges {
+    pub first_name: Option<Option<String>>,
+    pub last_name: Option<Option<String>>,
+    pub city: Option<Option<String>>,
+    pub state: Option<Option<String>>,
+    pub country: Option<Option<String>>,
+    pub updated_at: NaiveDateTime,
+}
+
+// trimmed value of a free text field, blank counts as null
+fn profile_field(
+    field: &str,
+    value: juniper::Nullable<String>,
+) -> Result<Option<Option<String>>, FieldError> {
+    let value = match value.explicit() {
+        Some(Some(value)) => value.trim().to_string(),
+        other => return Ok(other),
+    };
+    if value.is_empty() {
+        return Ok(Some(None));
+    }
+    if value.chars().count() > PROFILE_FIELD_MAX_LENGTH {
+        return Err(FieldError::new(
+            format!(
+                "{} must be at most {} characters",
+                field, PROFILE_FIELD_MAX_LENGTH
+            ),
+            graphql_value!("profile_field_too_long".to_string()),
+        ));
+    }
+    Ok(Some(Some(value)))
+}
+
+impl UpdateProfile {
+    pub fn validate(self, now: NaiveDateTime) -> Result<ProfileChanges, FieldError> {
+        let country_code = profile_field("Country", self.country)?
+            .map(|value| value.map(|value| value.to_uppercase()));
+        if let Some(Some(code)) = &country_code {
+            if !COUNTRY_CODES.contains(&code.as_str()) {
+                return Err(FieldError::new(
+                    "Country must be an ISO 3166-1 alpha-2 code",
+                    graphql_value!("country_invalid".to_string()),
+                ));
+            }
         }
+        Ok(ProfileChanges {
+            first_name: profile_field("First name", self.first_name)?,
+            last_name: profile_field("Last name", self.last_name)?,
+            city: profile_field("City", self.city)?,
+            state: profile_field("State", self.state)?,
+            country: country_code,
+            updated_at: now,
+        })
+    }
+}
 
-        // validate email too weak
-        let re_alpha = regex::Regex::new(r"[a-zA-Z]").unwrap();
-        let re_digit = regex::Regex::new(r"\d").unwrap();
+#[cfg(test)]
+mod tests {
+    use super::UpdateProfile;
+    use chrono::Utc;
+    use juniper::Nullable;
 
-        if !(re_alpha.find(&self.password1).is_some() && re_digit.find(&self.password1).is_some()) {
-            return Err(FieldError::new(
-                "Password is too weak",
-                graphql_value!("password_too_weak".to_string()),
-            ));
+    fn update(country: Nullable<String>) -> UpdateProfile {
+        UpdateProfile {
+            first_name: Nullable::ImplicitNull,
+            last_name: Nullable::ImplicitNull,
+            city: Nullable::ImplicitNull,
+            state: Nullable::ImplicitNull,
+            country,
         }
+    }
 
-        Ok(())
+    fn error_code(e: juniper::FieldError) -> String {
+        e.extensions().as_string_value().unwrap().to_string()
+    }
+
+    #[test]
+    fn leaves_absent_fields_alone() {
+        let changes = update(Nullable::ImplicitNull)
+            .validate(Utc::now().naive_utc())
+            .ok()
+            .unwrap();
+        assert_eq!(changes.first_name, None);
+        assert_eq!(changes.country, None);
+    }
+
+    #[test]
+    fn clears_fields_sent_as_null_or_blank() {
+        let mut input = update(Nullable::ExplicitNull);
+        input.city = Nullable::Some("   ".to_string());
+        let changes = input.validate(Utc::now().naive_utc()).ok().unwrap();
+        assert_eq!(changes.country, Some(None));
+        assert_eq!(changes.city, Some(None));
+        assert_eq!(changes.first_name, None);
+    }
+
+    #[test]
+    fn trims_values_and_uppercases_the_country() {
+        let mut input = update(Nullable::Some(" th ".to_string()));
+        input.first_name = Nullable::Some("  Somchai ".to_string());
+        let changes = input.validate(Utc::now().naive_utc()).ok().unwrap();
+        assert_eq!(changes.country, Some(Some("TH".to_string())));
+        assert_eq!(changes.first_name, Some(Some("Somchai".to_string())));
+    }
+
+    #[test]
+    fn rejects_an_unknown_country() {
+        let e = update(Nullable::Some("XX".to_string()))
+            .validate(Utc::now().naive_utc())
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "country_invalid");
+    }
+
+    #[test]
+    fn rejects_a_field_that_is_too_long() {
+        let mut input = update(Nullable::ImplicitNull);
+        input.last_name = Nullable::Some("x".repeat(101));
+        let e = input.validate(Utc::now().naive_utc()).err().unwrap();
+        assert_eq!(error_code(e), "profile_field_too_long");
     }
 }
@@ -0,0 +1,100 @@
+use crate::oauth::{exchange_code, ClientConfig, ExternalIdentity, OAuthError, OAuthProvider};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+
+const AUTHORIZE_URL: &str = "https://github.com/login/oauth/authorize";
+const TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+const API_URL: &str = "https://api.github.com";
+
+#[derive(Deserialize)]
+struct GithubUser {
+    id: i64,
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+// GitHub is plain OAuth2, the account comes from its REST API
+pub struct GithubProvider {
+    config: ClientConfig,
+}
+
+impl GithubProvider {
+    pub fn new(config: ClientConfig) -> GithubProvider {
+        GithubProvider { config }
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for GithubProvider {
+    async fn authorize_url(
+        &self,
+        state: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> Result<String, OAuthError> {
+        let scopes = self
+            .config
+            .scopes
+            .clone()
+            .unwrap_or_else(|| "read:user user:email".to_string());
+        let url = url::Url::parse_with_params(
+            AUTHORIZE_URL,
+            &[
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", redirect_uri),
+                ("scope", scopes.as_str()),
+                ("state", state),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| OAuthError::InvalidResponse(e.to_string()))?;
+        Ok(url.to_string())
+    }
+
+    async fn fetch_identity(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<ExternalIdentity, OAuthError> {
+        let access_token =
+            exchange_code(TOKEN_URL, &self.config, code, code_verifier, redirect_uri).await?;
+        let client = Client::new();
+
+        let user = client
+            .get(format!("{}/user", API_URL))
+            .bearer_auth(&access_token)
+            .header(reqwest::header::USER_AGENT, "drgz")
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<GithubUser>()
+            .await?;
+
+        let emails = client
+            .get(format!("{}/user/emails", API_URL))
+            .bearer_auth(&access_token)
+            .header(reqwest::header::USER_AGENT, "drgz")
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<GithubEmail>>()
+            .await?;
+        let primary = emails.into_iter().find(|email| email.primary);
+
+        Ok(ExternalIdentity {
+            subject: user.id.to_string(),
+            email_verified: primary.as_ref().is_some_and(|email| email.verified),
+            email: primary.map(|email| email.email),
+            username: Some(user.login),
+        })
+    }
+}
@@ -0,0 +1,85 @@
+use crate::oauth::{oidc::OidcProvider, ClientConfig, OAuthProvider};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::TcpListener;
+
+pub const CLIENT_ID: &str = "drgz-test";
+pub const CLIENT_SECRET: &str = "drgz-test-secret";
+
+// an OpenID Connect issuer on a random local port that accepts any code for
+// CLIENT_ID and answers the userinfo request with `user_info`. Returns a
+// provider pointed at it
+pub fn start_issuer(user_info: Value) -> Box<dyn OAuthProvider> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+    let base = issuer.clone();
+    let server = HttpServer::new(move || {
+        let base = base.clone();
+        let user_info = user_info.clone();
+        App::new()
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(move || {
+                    let base = base.clone();
+                    async move {
+                        HttpResponse::Ok().json(json!({
+                            "issuer": base,
+                            "authorization_endpoint": format!("{}/authorize", base),
+                            "token_endpoint": format!("{}/token", base),
+                            "userinfo_endpoint": format!("{}/userinfo", base),
+                        }))
+                    }
+                }),
+            )
+            .route("/token", web::post().to(token))
+            .route(
+                "/userinfo",
+                web::get().to(move |req: HttpRequest| {
+                    let user_info = user_info.clone();
+                    async move {
+                        let authorized = req
+                            .headers()
+                            .get("authorization")
+                            .and_then(|value| value.to_str().ok())
+                            .is_some_and(|value| value.starts_with("Bearer access-"));
+                        if authorized {
+                            HttpResponse::Ok().json(user_info)
+                        } else {
+                            HttpResponse::Unauthorized().finish()
+                        }
+                    }
+                }),
+            )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    actix_web::rt::spawn(server);
+
+    Box::new(OidcProvider::new(
+        issuer,
+        ClientConfig {
+            client_id: CLIENT_ID.to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            scopes: None,
+        },
+    ))
+}
+
+async fn token(form: web::Form<HashMap<String, String>>) -> HttpResponse {
+    let field = |name: &str| form.get(name).map(String::as_str);
+    let valid = field("grant_type") == Some("authorization_code")
+        && field("client_id") == Some(CLIENT_ID)
+        && field("client_secret") == Some(CLIENT_SECRET)
+        && field("code_verifier").is_some_and(|verifier| !verifier.is_empty());
+    match (valid, field("code")) {
+        (true, Some(code)) => HttpResponse::Ok().json(json!({
+            "access_token": format!("access-{}", code),
+            "token_type": "Bearer",
+        })),
+        _ => HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+    }
+}
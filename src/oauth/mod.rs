@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dotenvy::dotenv;
+use rand::RngCore;
+use reqwest::Client;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::{env, fmt, sync::OnceLock};
+
+mod github;
+#[cfg(test)]
+pub mod mock;
+mod oidc;
+
+// what we learn about the user from a provider
+#[derive(Debug)]
+pub struct ExternalIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OAuthError {
+    Request(String),
+    InvalidResponse(String),
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OAuthError::Request(e) => write!(f, "provider request failed: {}", e),
+            OAuthError::InvalidResponse(e) => write!(f, "invalid provider response: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for OAuthError {
+    fn from(e: reqwest::Error) -> Self {
+        OAuthError::Request(e.to_string())
+    }
+}
+
+#[async_trait]
+pub trait OAuthProvider: Send + Sync {
+    // where to send the browser to start the authorization code flow
+    async fn authorize_url(
+        &self,
+        state: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> Result<String, OAuthError>;
+
+    // exchange the authorization code and look up the account behind it
+    async fn fetch_identity(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<ExternalIdentity, OAuthError>;
+}
+
+pub struct ClientConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: Option<String>,
+}
+
+static PROVIDERS: OnceLock<HashMap<String, Box<dyn OAuthProvider>>> = OnceLock::new();
+
+pub fn provider(name: &str) -> Option<&'static dyn OAuthProvider> {
+    PROVIDERS
+        .get_or_init(load_providers)
+        .get(name)
+        .map(|provider| provider.as_ref())
+}
+
+pub fn provider_names() -> Vec<String> {
+    let mut names: Vec<String> = PROVIDERS
+        .get_or_init(load_providers)
+        .keys()
+        .cloned()
+        .collect();
+    names.sort();
+    names
+}
+
+// OAUTH_PROVIDERS=google,github with OAUTH_<NAME>_CLIENT_ID and
+// OAUTH_<NAME>_CLIENT_SECRET for each. "github" uses the GitHub API, any other
+// name is an OpenID Connect provider discovered from OAUTH_<NAME>_ISSUER.
+fn load_providers() -> HashMap<String, Box<dyn OAuthProvider>> {
+    dotenv().ok();
+    let mut providers: HashMap<String, Box<dyn OAuthProvider>> = HashMap::new();
+    let names = env::var("OAUTH_PROVIDERS").unwrap_or_default();
+
+    for name in names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let prefix = format!("OAUTH_{}", name.to_uppercase());
+        let config = ClientConfig {
+            client_id: env::var(format!("{}_CLIENT_ID", prefix))
+                .unwrap_or_else(|_| panic!("{}_CLIENT_ID must be set", prefix)),
+            client_secret: env::var(format!("{}_CLIENT_SECRET", prefix))
+                .unwrap_or_else(|_| panic!("{}_CLIENT_SECRET must be set", prefix)),
+            scopes: env::var(format!("{}_SCOPES", prefix)).ok(),
+        };
+
+        let provider: Box<dyn OAuthProvider> = if name == "github" {
+            Box::new(github::GithubProvider::new(config))
+        } else {
+            let issuer = env::var(format!("{}_ISSUER", prefix))
+                .unwrap_or_else(|_| panic!("{}_ISSUER must be set", prefix));
+            Box::new(oidc::OidcProvider::new(issuer, config))
+        };
+        providers.insert(name.to_string(), provider);
+    }
+    providers
+}
+
+pub fn redirect_uri(provider: &str) -> String {
+    dotenv().ok();
+    let base =
+        env::var("OAUTH_REDIRECT_BASE").unwrap_or_else(|_| "http://localhost:8080".to_string());
+    format!("{}/auth/{}/callback", base.trim_end_matches('/'), provider)
+}
+
+// PKCE code verifier and its S256 challenge
+pub fn pkce_pair() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let verifier = URL_SAFE_NO_PAD.encode(bytes);
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    (verifier, challenge)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+}
+
+async fn exchange_code(
+    token_url: &str,
+    config: &ClientConfig,
+    code: &str,
+    code_verifier: &str,
+    redirect_uri: &str,
+) -> Result<String, OAuthError> {
+    let response = Client::new()
+        .post(token_url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+            ("code_verifier", code_verifier),
+        ])
+        .send()
+        .await?
+        .json::<TokenResponse>()
+        .await?;
+
+    match response.access_token {
+        Some(access_token) => Ok(access_token),
+        None => Err(OAuthError::InvalidResponse(
+            response
+                .error
+                .unwrap_or_else(|| "missing access_token".to_string()),
+        )),
+    }
+}
@@ -0,0 +1,172 @@
+use crate::oauth::{exchange_code, ClientConfig, ExternalIdentity, OAuthError, OAuthProvider};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::Mutex;
+
+#[derive(Clone, Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    preferred_username: Option<String>,
+}
+
+pub struct OidcProvider {
+    issuer: String,
+    config: ClientConfig,
+    discovery: Mutex<Option<Discovery>>,
+}
+
+impl OidcProvider {
+    pub fn new(issuer: String, config: ClientConfig) -> OidcProvider {
+        OidcProvider {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            config,
+            discovery: Mutex::new(None),
+        }
+    }
+
+    // endpoints from the issuer's openid-configuration, fetched once
+    async fn discovery(&self) -> Result<Discovery, OAuthError> {
+        if let Some(discovery) = self.discovery.lock().unwrap().clone() {
+            return Ok(discovery);
+        }
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let discovery = Client::new()
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Discovery>()
+            .await?;
+        *self.discovery.lock().unwrap() = Some(discovery.clone());
+        Ok(discovery)
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for OidcProvider {
+    async fn authorize_url(
+        &self,
+        state: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> Result<String, OAuthError> {
+        let discovery = self.discovery().await?;
+        let scopes = self
+            .config
+            .scopes
+            .clone()
+            .unwrap_or_else(|| "openid email profile".to_string());
+        let url = url::Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", redirect_uri),
+                ("scope", scopes.as_str()),
+                ("state", state),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| OAuthError::InvalidResponse(e.to_string()))?;
+        Ok(url.to_string())
+    }
+
+    async fn fetch_identity(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<ExternalIdentity, OAuthError> {
+        let discovery = self.discovery().await?;
+        let access_token = exchange_code(
+            &discovery.token_endpoint,
+            &self.config,
+            code,
+            code_verifier,
+            redirect_uri,
+        )
+        .await?;
+
+        let user_info = Client::new()
+            .get(&discovery.userinfo_endpoint)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<UserInfo>()
+            .await?;
+
+        Ok(ExternalIdentity {
+            subject: user_info.sub,
+            email: user_info.email,
+            email_verified: user_info.email_verified,
+            username: user_info.preferred_username,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oauth::mock::{start_issuer, CLIENT_ID};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn discovers_the_issuer_and_fetches_the_identity() {
+        let provider = start_issuer(json!({
+            "sub": "1234",
+            "email": "someone@example.com",
+            "email_verified": true,
+            "preferred_username": "someone",
+        }));
+
+        let url = provider
+            .authorize_url("state", "challenge", "http://localhost/callback")
+            .await
+            .unwrap();
+        let url = url::Url::parse(&url).unwrap();
+        let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert!(url.path().ends_with("/authorize"));
+        for (name, value) in [
+            ("client_id", CLIENT_ID),
+            ("state", "state"),
+            ("code_challenge", "challenge"),
+            ("code_challenge_method", "S256"),
+        ] {
+            assert!(params.contains(&(name.to_string(), value.to_string())));
+        }
+
+        let identity = provider
+            .fetch_identity("code", "verifier", "http://localhost/callback")
+            .await
+            .unwrap();
+        assert_eq!(identity.subject, "1234");
+        assert_eq!(identity.email.as_deref(), Some("someone@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.username.as_deref(), Some("someone"));
+    }
+
+    #[actix_web::test]
+    async fn treats_a_missing_email_verified_claim_as_unverified() {
+        let provider = start_issuer(json!({
+            "sub": "1234",
+            "email": "someone@example.com",
+        }));
+        let identity = provider
+            .fetch_identity("code", "verifier", "http://localhost/callback")
+            .await
+            .unwrap();
+        assert!(!identity.email_verified);
+    }
+}
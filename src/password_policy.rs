@@ -0,0 +1,230 @@
+use crate::bloom::BloomFilter;
+use dotenvy::dotenv;
+use juniper::{FieldError, Value};
+use std::{env, sync::OnceLock};
+
+// rules every new password has to pass, configured with
+//
+// PASSWORD_MIN_LENGTH=8
+// PASSWORD_MAX_LENGTH=128
+// PASSWORD_MIN_SCORE=2                  zxcvbn strength from 0 to 4
+// PASSWORD_BREACHED_FILTER=breached.bloom   built with the breached_passwords binary
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    min_score: u8,
+    breached: Option<BloomFilter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PasswordViolation {
+    TooShort(usize),
+    TooLong(usize),
+    TooWeak,
+    ContainsPersonalInfo,
+    Breached,
+}
+
+impl PasswordViolation {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PasswordViolation::TooShort(_) => "password_too_short",
+            PasswordViolation::TooLong(_) => "password_too_long",
+            PasswordViolation::TooWeak => "password_too_weak",
+            PasswordViolation::ContainsPersonalInfo => "password_contains_personal_info",
+            PasswordViolation::Breached => "password_breached",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            PasswordViolation::TooShort(min) => {
+                format!("Password must be at least {} characters", min)
+            }
+            PasswordViolation::TooLong(max) => {
+                format!("Password must be at most {} characters", max)
+            }
+            PasswordViolation::TooWeak => "Password is too weak".to_string(),
+            PasswordViolation::ContainsPersonalInfo => {
+                "Password must not contain your username or email".to_string()
+            }
+            PasswordViolation::Breached => {
+                "Password has appeared in a data breach, choose another one".to_string()
+            }
+        }
+    }
+}
+
+static POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+
+pub fn password_policy() -> &'static PasswordPolicy {
+    POLICY.get_or_init(|| PasswordPolicy::from_env().expect("PASSWORD_BREACHED_FILTER is invalid"))
+}
+
+impl PasswordPolicy {
+    fn from_env() -> Result<PasswordPolicy, String> {
+        dotenv().ok();
+        let var = |name: &str, default: usize| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                .unwrap_or(default)
+        };
+        let breached = match env::var("PASSWORD_BREACHED_FILTER") {
+            Ok(path) => Some(BloomFilter::read(&path).map_err(|e| format!("{}: {}", path, e))?),
+            Err(_e) => None,
+        };
+        Ok(PasswordPolicy {
+            min_length: var("PASSWORD_MIN_LENGTH", 8),
+            max_length: var("PASSWORD_MAX_LENGTH", 128),
+            min_score: var("PASSWORD_MIN_SCORE", 2).min(4) as u8,
+            breached,
+        })
+    }
+
+    // every rule the password breaks, `personal` holds the username, email
+    // and anything else the password should not be built from
+    pub fn violations(&self, password: &str, personal: &[&str]) -> Vec<PasswordViolation> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(PasswordViolation::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            // skip the expensive checks for absurdly long input
+            violations.push(PasswordViolation::TooLong(self.max_length));
+            return violations;
+        }
+
+        let lowercase = password.to_lowercase();
+        let personal: Vec<String> = personal
+            .iter()
+            .flat_map(|value| {
+                let value = value.to_lowercase();
+                // the local part of an email is as guessable as the whole address
+                match value.split_once('@') {
+                    Some((local, _)) => vec![local.to_string(), value],
+                    None => vec![value],
+                }
+            })
+            .filter(|value| value.chars().count() >= 3)
+            .collect();
+        if personal
+            .iter()
+            .any(|value| lowercase.contains(value.as_str()))
+        {
+            violations.push(PasswordViolation::ContainsPersonalInfo);
+        }
+
+        let inputs: Vec<&str> = personal.iter().map(|value| value.as_str()).collect();
+        let score = zxcvbn::zxcvbn(password, &inputs)
+            .map(|entropy| entropy.score())
+            .unwrap_or(0);
+        if score < self.min_score {
+            violations.push(PasswordViolation::TooWeak);
+        }
+
+        if let Some(breached) = &self.breached {
+            if breached.contains_password(password) {
+                violations.push(PasswordViolation::Breached);
+            }
+        }
+        violations
+    }
+
+    // one error listing every violation, the extension holds all their codes
+    pub fn validate(&self, password: &str, personal: &[&str]) -> Result<(), FieldError> {
+        let violations = self.violations(password, personal);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        let message = violations
+            .iter()
+            .map(|violation| violation.message())
+            .collect::<Vec<String>>()
+            .join(". ");
+        let codes = violations
+            .iter()
+            .map(|violation| Value::scalar(violation.code().to_string()))
+            .collect();
+        Err(FieldError::new(message, Value::list(codes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PasswordPolicy, PasswordViolation};
+    use crate::bloom::{password_digest, BloomFilter};
+    use juniper::Value;
+
+    const STRONG: &str = "plinth-marigold-47-quarry";
+
+    fn policy(breached: Option<BloomFilter>) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 128,
+            min_score: 2,
+            breached,
+        }
+    }
+
+    #[test]
+    fn accepts_a_strong_password() {
+        assert_eq!(policy(None).violations(STRONG, &["alice"]), vec![]);
+    }
+
+    #[test]
+    fn reports_a_short_password() {
+        let violations = policy(None).violations("k9#Qz", &[]);
+        assert!(violations.contains(&PasswordViolation::TooShort(8)));
+    }
+
+    #[test]
+    fn reports_a_long_password_without_scoring_it() {
+        let password = "a".repeat(129);
+        assert_eq!(
+            policy(None).violations(&password, &[]),
+            vec![PasswordViolation::TooLong(128)]
+        );
+    }
+
+    #[test]
+    fn reports_a_weak_password() {
+        assert_eq!(
+            policy(None).violations("password1", &[]),
+            vec![PasswordViolation::TooWeak]
+        );
+    }
+
+    #[test]
+    fn reports_personal_info_including_the_local_part_of_the_email() {
+        let password = format!("{}-alice", STRONG);
+        let violations = policy(None).violations(&password, &["alice@example.com"]);
+        assert!(violations.contains(&PasswordViolation::ContainsPersonalInfo));
+        assert!(policy(None)
+            .violations(&password, &["bob@example.com"])
+            .is_empty());
+    }
+
+    #[test]
+    fn reports_a_breached_password() {
+        let mut breached = BloomFilter::new(10, 0.001);
+        breached.insert(&password_digest(STRONG));
+        assert_eq!(
+            policy(Some(breached)).violations(STRONG, &[]),
+            vec![PasswordViolation::Breached]
+        );
+    }
+
+    #[test]
+    fn lists_every_code_in_the_error_extension() {
+        let e = policy(None).validate("pass", &[]).err().unwrap();
+        assert_eq!(
+            e.extensions(),
+            &Value::list(vec![
+                Value::scalar("password_too_short".to_string()),
+                Value::scalar("password_too_weak".to_string()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::middlewares::auth::AuthError;
+use crate::models::api_keys::{ApiKey, CreatedApiKey, NewApiKey};
+use crate::models::roles::SUPERUSER_ROLE;
+use crate::models::users::User;
+use crate::repositories::role::RoleRepository;
+use crate::repositories::user::SuccessMessage;
+use crate::schema::{api_keys, permissions, users};
+use crate::utils::{generate_refresh_token, hash_token};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use std::sync::Arc;
+
+// keys look like drgz_<prefix>_<secret>, the prefix stays visible so users
+// can tell their keys apart
+pub const API_KEY_PREFIX: &str = "drgz_";
+
+pub struct ApiKeyRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl ApiKeyRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> ApiKeyRepository {
+        ApiKeyRepository { pool }
+    }
+
+    pub async fn create(
+        &self,
+        user_id: i32,
+        input: NewApiKey,
+    ) -> Result<CreatedApiKey, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let name = input.name.trim().to_string();
+        if name.is_empty() {
+            return Err(FieldError::new(
+                "Name is required",
+                graphql_value!("invalid_name".to_string()),
+            ));
+        }
+        if input
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now().naive_utc())
+        {
+            return Err(FieldError::new(
+                "Expiry must be in the future",
+                graphql_value!("invalid_expiry".to_string()),
+            ));
+        }
+
+        // a key can only carry permissions its owner holds
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .first::<User>(connection)?;
+        let grants = RoleRepository::grants(connection, &user)?;
+        let available = if grants.roles.iter().any(|r| r == SUPERUSER_ROLE) {
+            permissions::table
+                .select(permissions::name)
+                .load::<String>(connection)?
+        } else {
+            grants.permissions
+        };
+        let mut scopes = input.scopes;
+        scopes.sort();
+        scopes.dedup();
+        if let Some(scope) = scopes.iter().find(|scope| !available.contains(scope)) {
+            return Err(FieldError::new(
+                format!("Scope {} is not available", scope),
+                graphql_value!("invalid_scope".to_string()),
+            ));
+        }
+
+        let secret = generate_refresh_token();
+        let prefix = format!("{}{}", API_KEY_PREFIX, &secret[..8]);
+        let key = format!("{}_{}", prefix, &secret[8..]);
+
+        let sql = "INSERT INTO api_keys (user_id, name, prefix, key_hash, scopes, expires_at) VALUES ($1, $2, $3, $4, $5, $6)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(&name)
+            .bind::<diesel::sql_types::Text, _>(&prefix)
+            .bind::<diesel::sql_types::Text, _>(hash_token(&key))
+            .bind::<diesel::sql_types::Array<diesel::sql_types::Text>, _>(&scopes)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Timestamp>, _>(input.expires_at)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        let api_key = api_keys::table
+            .filter(api_keys::prefix.eq(&prefix))
+            .first::<ApiKey>(connection)?;
+        Ok(CreatedApiKey { key, api_key })
+    }
+
+    pub async fn api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, FieldError> {
+        let conn = &mut *self.pool.get()?;
+        let keys = api_keys::table
+            .filter(api_keys::user_id.eq(user_id))
+            .order(api_keys::created_at.desc())
+            .load::<ApiKey>(conn)?;
+        Ok(keys)
+    }
+
+    pub async fn revoke(&self, user_id: i32, id: i32) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let sql = "UPDATE api_keys SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL";
+        let revoked = diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Timestamp, _>(Utc::now().naive_utc())
+            .bind::<diesel::sql_types::Integer, _>(id)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        if revoked == 0 {
+            return Err(FieldError::new(
+                "API key not found",
+                graphql_value!("api_key_not_found".to_string()),
+            ));
+        }
+        Ok(SuccessMessage {
+            message: "API key revoked".to_string(),
+            success: true,
+        })
+    }
+
+    // the key behind a presented secret and the permissions it grants right
+    // now, scopes the owner has since lost are dropped
+    pub fn authenticate(&self, key: &str) -> Result<(ApiKey, Vec<String>), AuthError> {
+        if !key.starts_with(API_KEY_PREFIX) {
+            return Err(AuthError::Invalid);
+        }
+        let mut connection = self.pool.get().map_err(|_e| AuthError::Internal)?;
+        let connection = &mut *connection;
+        let now = Utc::now().naive_utc();
+
+        let api_key = api_keys::table
+            .filter(api_keys::key_hash.eq(hash_token(key)))
+            .first::<ApiKey>(connection)
+            .map_err(|_e| AuthError::Invalid)?;
+        if api_key.revoked_at.is_some() {
+            return Err(AuthError::Revoked);
+        }
+        if api_key
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+        {
+            return Err(AuthError::Expired);
+        }
+        let user = users::table
+            .filter(users::id.eq(api_key.user_id))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .map_err(|_e| AuthError::Revoked)?;
+        let grants = RoleRepository::grants(connection, &user).map_err(|_e| AuthError::Invalid)?;
+        let superuser = grants.roles.iter().any(|r| r == SUPERUSER_ROLE);
+        let permissions = api_key
+            .scopes
+            .iter()
+            .filter(|scope| superuser || grants.permissions.contains(scope))
+            .cloned()
+            .collect();
+
+        // only write last use once a minute for busy keys
+        let sql = "UPDATE api_keys SET last_used_at = $1 WHERE id = $2 AND (last_used_at IS NULL OR last_used_at < $3)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .bind::<diesel::sql_types::Integer, _>(api_key.id)
+            .bind::<diesel::sql_types::Timestamp, _>(now - Duration::minutes(1))
+            .execute(connection)
+            .map_err(|_e| AuthError::Invalid)?;
+        Ok((api_key, permissions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiKeyRepository;
+    use crate::db::test_pool;
+    use crate::middlewares::auth::AuthError;
+    use crate::models::api_keys::NewApiKey;
+    use crate::schema::users;
+    use diesel::prelude::*;
+
+    fn insert_user(repository: &ApiKeyRepository, username: &str) -> i32 {
+        let connection = &mut *repository.pool.get().unwrap();
+        diesel::insert_into(users::table)
+            .values((
+                users::username.eq(username),
+                users::email.eq(format!("{}@example.com", username)),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(connection)
+            .unwrap()
+    }
+
+    fn new_key(scopes: &[&str]) -> NewApiKey {
+        NewApiKey {
+            name: "deploy".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires_at: None,
+        }
+    }
+
+    fn error_code(e: juniper::FieldError) -> String {
+        e.extensions().as_string_value().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn authenticates_a_key_until_it_is_revoked() {
+        let repository = ApiKeyRepository::new(test_pool());
+        let user_id = insert_user(&repository, "key-owner");
+
+        let created = repository.create(user_id, new_key(&[])).await.ok().unwrap();
+        assert!(created.key.starts_with(&created.api_key.prefix));
+        let (api_key, permissions) = repository.authenticate(&created.key).unwrap();
+        assert_eq!(api_key.user_id, user_id);
+        assert!(permissions.is_empty());
+
+        let wrong = format!("{}x", created.key);
+        assert_eq!(
+            repository.authenticate(&wrong).err(),
+            Some(AuthError::Invalid)
+        );
+
+        repository
+            .revoke(user_id, created.api_key.id)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(
+            repository.authenticate(&created.key).err(),
+            Some(AuthError::Revoked)
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn refuses_scopes_the_owner_does_not_hold() {
+        let repository = ApiKeyRepository::new(test_pool());
+        let user_id = insert_user(&repository, "key-scoped");
+
+        let e = repository
+            .create(user_id, new_key(&["users:read"]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "invalid_scope");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn only_the_owner_revokes_a_key() {
+        let repository = ApiKeyRepository::new(test_pool());
+        let owner = insert_user(&repository, "key-keeper");
+        let other = insert_user(&repository, "key-thief");
+        let created = repository.create(owner, new_key(&[])).await.ok().unwrap();
+
+        let e = repository
+            .revoke(other, created.api_key.id)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "api_key_not_found");
+        assert!(repository.authenticate(&created.key).is_ok());
+    }
+}
@@ -0,0 +1,59 @@
+use crate::models::audit_events::AuditEvent;
+use crate::schema::audit_events;
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use juniper::FieldError;
+use r2d2::Pool;
+use std::sync::Arc;
+
+pub const IMPERSONATION_STARTED: &str = "impersonation_started";
+pub const IMPERSONATION_STOPPED: &str = "impersonation_stopped";
+pub const ACCOUNT_DELETED: &str = "account_deleted";
+pub const ACCOUNT_RESTORED: &str = "account_restored";
+pub const ACCOUNT_PURGED: &str = "account_purged";
+
+pub struct AuditRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl AuditRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> AuditRepository {
+        AuditRepository { pool }
+    }
+
+    pub fn record(
+        connection: &mut PgConnection,
+        actor_id: Option<i32>,
+        user_id: Option<i32>,
+        action: &str,
+        details: Option<String>,
+        ip: Option<String>,
+    ) -> QueryResult<()> {
+        let sql = "INSERT INTO audit_events (actor_id, user_id, action, details, ip) VALUES ($1, $2, $3, $4, $5)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Integer>, _>(actor_id)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Integer>, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(action)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(details)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(ip)
+            .execute(connection)?;
+        Ok(())
+    }
+
+    // newest first, optionally only events done by or to one user
+    pub async fn events(&self, user_id: Option<i32>) -> Result<Vec<AuditEvent>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let mut query = audit_events::table
+            .order(audit_events::created_at.desc())
+            .limit(100)
+            .into_boxed();
+        if let Some(user_id) = user_id {
+            query = query.filter(
+                audit_events::user_id
+                    .eq(user_id)
+                    .or(audit_events::actor_id.eq(user_id)),
+            );
+        }
+        Ok(query.load::<AuditEvent>(connection)?)
+    }
+}
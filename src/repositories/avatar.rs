@@ -0,0 +1,225 @@
+use crate::handlers::upload::UploadedFile;
+use crate::models::users::User;
+use crate::schema::users;
+use actix_web::web;
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use drgz::storage::storage;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::{ImageFormat, ImageOutputFormat};
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use rand::RngCore;
+use std::io::Cursor;
+use std::sync::Arc;
+
+// square sizes stored for every avatar, the first is `avatar_url` and the
+// last `avatar_thumbnail_url`
+const AVATAR_SIZES: [u32; 2] = [256, 64];
+
+// larger images are refused before they are decoded
+const MAX_AVATAR_DIMENSION: u32 = 4096;
+
+fn avatar_invalid() -> FieldError {
+    FieldError::new(
+        "The file is not a PNG, JPEG or WebP image",
+        graphql_value!("avatar_invalid".to_string()),
+    )
+}
+
+fn storage_error(e: impl std::fmt::Display) -> FieldError {
+    println!("Error storing avatar: {}", e);
+    FieldError::new(
+        "Could not store the file",
+        graphql_value!("storage_error".to_string()),
+    )
+}
+
+fn avatar_file(key: &str, size: u32) -> String {
+    format!("{}-{}.png", key, size)
+}
+
+// decode the upload, which has to really be of the type it was sent as,
+// then crop it to a square PNG of each size
+fn resize_avatar(bytes: &[u8], content_type: &str) -> Result<Vec<Vec<u8>>, FieldError> {
+    let format = image::guess_format(bytes).map_err(|_e| avatar_invalid())?;
+    if !matches!(
+        format,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP
+    ) || ImageFormat::from_mime_type(content_type) != Some(format)
+    {
+        return Err(avatar_invalid());
+    }
+    let mut reader = Reader::with_format(Cursor::new(bytes), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| match e {
+        image::ImageError::Limits(_) => FieldError::new(
+            format!(
+                "Images can be at most {}x{} pixels",
+                MAX_AVATAR_DIMENSION, MAX_AVATAR_DIMENSION
+            ),
+            graphql_value!("avatar_too_large".to_string()),
+        ),
+        _ => avatar_invalid(),
+    })?;
+
+    AVATAR_SIZES
+        .iter()
+        .map(|size| {
+            let mut body = Cursor::new(Vec::new());
+            image
+                .resize_to_fill(*size, *size, FilterType::Lanczos3)
+                .write_to(&mut body, ImageOutputFormat::Png)
+                .map_err(storage_error)?;
+            Ok(body.into_inner())
+        })
+        .collect()
+}
+
+pub struct AvatarRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl AvatarRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> AvatarRepository {
+        AvatarRepository { pool }
+    }
+
+    // store the resized images under a fresh key, so cached copies of the
+    // previous avatar are never served for the new one
+    pub async fn upload(&self, user_id: i32, file: UploadedFile) -> Result<User, FieldError> {
+        let images = web::block(move || resize_avatar(&file.bytes, &file.content_type))
+            .await
+            .map_err(storage_error)??;
+
+        let mut random = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut random);
+        let key = format!("avatars/{}/{}", user_id, hex::encode(random));
+        for (size, body) in AVATAR_SIZES.iter().zip(images) {
+            storage()
+                .put(&avatar_file(&key, *size), body, "image/png")
+                .await
+                .map_err(storage_error)?;
+        }
+
+        let previous = self.set_avatar(user_id, Some(&key))?;
+        if let Some(previous) = previous {
+            Self::delete_files(&previous).await;
+        }
+        Ok(users::table
+            .find(user_id)
+            .first::<User>(&mut *self.pool.get()?)?)
+    }
+
+    pub async fn remove(&self, user_id: i32) -> Result<User, FieldError> {
+        let previous = self.set_avatar(user_id, None)?;
+        if let Some(previous) = previous {
+            Self::delete_files(&previous).await;
+        }
+        Ok(users::table
+            .find(user_id)
+            .first::<User>(&mut *self.pool.get()?)?)
+    }
+
+    // point the user at the images under `key`, returning the key they
+    // replace
+    fn set_avatar(&self, user_id: i32, key: Option<&str>) -> Result<Option<String>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let url = |size: u32| key.map(|key| storage().url(&avatar_file(key, size)));
+        connection
+            .transaction::<_, diesel::result::Error, _>(|connection| {
+                let previous = users::table
+                    .find(user_id)
+                    .select(users::avatar_key)
+                    .for_update()
+                    .first::<Option<String>>(connection)?;
+                diesel::sql_query(
+                    "UPDATE users SET avatar_key = $1, avatar_url = $2, avatar_thumbnail_url = $3, updated_at = NOW() WHERE id = $4",
+                )
+                .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(key)
+                .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(url(AVATAR_SIZES[0]))
+                .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(url(AVATAR_SIZES[1]))
+                .bind::<diesel::sql_types::Integer, _>(user_id)
+                .execute(connection)?;
+                Ok(previous)
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })
+    }
+
+    // remove the stored images of an avatar, failures are only logged since
+    // nothing points at the files anymore
+    pub async fn delete_files(key: &str) {
+        for size in AVATAR_SIZES {
+            let file = avatar_file(key, size);
+            if let Err(e) = storage().delete(&file).await {
+                println!("Error deleting {} from storage: {}", file, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resize_avatar, AVATAR_SIZES, MAX_AVATAR_DIMENSION};
+    use image::{ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn encode(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let mut body = Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut body, format)
+            .unwrap();
+        body.into_inner()
+    }
+
+    fn error_code(e: juniper::FieldError) -> String {
+        e.extensions().as_string_value().unwrap().to_string()
+    }
+
+    #[test]
+    fn crops_to_a_square_png_of_each_size() {
+        let images = resize_avatar(&encode(300, 200, ImageOutputFormat::Jpeg(90)), "image/jpeg")
+            .ok()
+            .unwrap();
+        assert_eq!(images.len(), AVATAR_SIZES.len());
+        for (image, size) in images.iter().zip(AVATAR_SIZES) {
+            let image =
+                image::load_from_memory_with_format(image, image::ImageFormat::Png).unwrap();
+            assert_eq!((image.width(), image.height()), (size, size));
+        }
+    }
+
+    #[test]
+    fn rejects_a_file_that_is_not_the_type_it_was_sent_as() {
+        let e = resize_avatar(&encode(10, 10, ImageOutputFormat::Png), "image/jpeg")
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "avatar_invalid");
+    }
+
+    #[test]
+    fn rejects_a_file_that_is_not_an_image() {
+        let e = resize_avatar(b"<svg></svg>", "image/png").err().unwrap();
+        assert_eq!(error_code(e), "avatar_invalid");
+    }
+
+    #[test]
+    fn rejects_images_over_the_dimension_limit() {
+        let e = resize_avatar(
+            &encode(MAX_AVATAR_DIMENSION + 1, 1, ImageOutputFormat::Png),
+            "image/png",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error_code(e), "avatar_too_large");
+    }
+}
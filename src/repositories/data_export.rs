@@ -0,0 +1,499 @@
+use crate::db::lower;
+use crate::models::api_keys::ApiKey;
+use crate::models::audit_events::AuditEvent;
+use crate::models::data_exports::{export_status, DataExport};
+use crate::models::identities::Identity;
+use crate::models::organizations::Invitation;
+use crate::models::refresh_tokens::RefreshToken;
+use crate::models::users::User;
+use crate::repositories::privacy::PrivacyRepository;
+use crate::schema::{
+    api_keys, audit_events, data_exports, identities, invitations, login_attempts, memberships,
+    organizations, refresh_tokens, roles, user_mfa, user_roles, users,
+};
+use crate::utils::{generate_refresh_token, hash_token};
+use actix_web::web;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{env, fs};
+use tera::Tera;
+
+// DATA_EXPORT_DIR, where finished exports are kept until they expire
+fn export_dir() -> PathBuf {
+    dotenv().ok();
+    PathBuf::from(env::var("DATA_EXPORT_DIR").unwrap_or_else(|_| "exports".to_string()))
+}
+
+// DATA_EXPORT_TTL, in seconds, how long the download link works
+fn export_ttl() -> Duration {
+    dotenv().ok();
+    let seconds = env::var("DATA_EXPORT_TTL")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(60 * 60 * 48);
+    Duration::seconds(seconds)
+}
+
+// DATA_EXPORT_PENDING_TIMEOUT, in seconds, after which an export still
+// pending is taken as lost (the server restarted while building it) and a
+// new request starts over
+fn export_pending_timeout() -> Duration {
+    dotenv().ok();
+    let seconds = env::var("DATA_EXPORT_PENDING_TIMEOUT")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(15 * 60);
+    Duration::seconds(seconds)
+}
+
+// DATA_EXPORT_BASE_URL, the public address of the /exports route
+fn export_base_url() -> String {
+    dotenv().ok();
+    env::var("DATA_EXPORT_BASE_URL")
+        .unwrap_or_else(|_| "http://localhost:8080/exports".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+pub struct DataExportRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl DataExportRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> DataExportRepository {
+        DataExportRepository { pool }
+    }
+
+    // start an export of `user_id`'s data, the link is emailed to the user
+    // once it is ready. An export still being built is returned instead of
+    // starting another one, unless it has been pending for too long
+    pub async fn request(
+        &self,
+        user_id: i32,
+        requested_by: i32,
+        zipped: bool,
+        tera: Arc<Tera>,
+    ) -> Result<DataExport, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "User not found",
+                    graphql_value!("user_not_found".to_string()),
+                )
+            })?;
+
+        diesel::sql_query(
+            "UPDATE data_exports SET status = $1, completed_at = NOW() WHERE user_id = $2 AND status = $3 AND created_at < $4",
+        )
+        .bind::<diesel::sql_types::Text, _>(export_status::FAILED)
+        .bind::<diesel::sql_types::Integer, _>(user.id)
+        .bind::<diesel::sql_types::Text, _>(export_status::PENDING)
+        .bind::<diesel::sql_types::Timestamp, _>(Utc::now().naive_utc() - export_pending_timeout())
+        .execute(connection)
+        .map_err(|_e| {
+            FieldError::new(
+                "Database error",
+                graphql_value!("internal_error".to_string()),
+            )
+        })?;
+
+        let pending = data_exports::table
+            .filter(data_exports::user_id.eq(user.id))
+            .filter(data_exports::status.eq(export_status::PENDING))
+            .first::<DataExport>(connection)
+            .optional()?;
+        if let Some(pending) = pending {
+            return Ok(pending);
+        }
+
+        let export = diesel::insert_into(data_exports::table)
+            .values((
+                data_exports::user_id.eq(user.id),
+                data_exports::requested_by.eq(requested_by),
+                data_exports::zipped.eq(zipped),
+            ))
+            .get_result::<DataExport>(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        let pool = self.pool.clone();
+        let export_id = export.id;
+        actix_web::rt::spawn(async move {
+            DataExportRepository::new(pool)
+                .build(export_id, zipped, user, tera)
+                .await;
+        });
+        Ok(export)
+    }
+
+    pub async fn exports(&self, user_id: i32) -> Result<Vec<DataExport>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Ok(data_exports::table
+            .filter(data_exports::user_id.eq(user_id))
+            .order(data_exports::created_at.desc())
+            .load::<DataExport>(connection)?)
+    }
+
+    // write the archive, then email the download link
+    async fn build(&self, export_id: i32, zipped: bool, user: User, tera: Arc<Tera>) {
+        let token = generate_refresh_token();
+        let pool = self.pool.clone();
+        let archive_user = user.clone();
+        let archive_token = token.clone();
+        // querying and writing the file block, keep them off the workers
+        let finished = web::block(move || {
+            Self::finish(pool, export_id, zipped, &archive_user, &archive_token)
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|finished| finished);
+        if let Err(e) = finished {
+            println!("Error building data export {}: {}", export_id, e);
+            return;
+        }
+
+        let mut mail_context = tera::Context::new();
+        mail_context.insert("username", &user.username);
+        mail_context.insert("email", &user.email);
+        mail_context.insert("domain", "http://localhost:3000");
+        mail_context.insert("logo", "https://www.elegal.ascendth.com/_next/image?url=https%3A%2F%2Felegal-ascend.s3.amazonaws.com%2Fpublic%2Flogo.png&w=256&q=75");
+        mail_context.insert("company", "drgz");
+        mail_context.insert("hours", &export_ttl().num_hours());
+        mail_context.insert("link", &format!("{}/{}", export_base_url(), token));
+        crate::mailer::send_html_email(
+            &user.email,
+            "info@ascendth.com",
+            "Your data export is ready",
+            "emails/data-export.html",
+            &mail_context,
+            tera,
+        )
+        .await;
+    }
+
+    // write the archive and mark the export ready, or failed when the
+    // archive could not be written
+    fn finish(
+        pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+        export_id: i32,
+        zipped: bool,
+        user: &User,
+        token: &str,
+    ) -> Result<(), String> {
+        let connection = &mut *pool.get().map_err(|e| e.to_string())?;
+        let (file_path, expires_at) = match Self::write_archive(connection, zipped, user, token) {
+            Ok(result) => result,
+            Err(e) => {
+                let _ = diesel::sql_query(
+                    "UPDATE data_exports SET status = $1, completed_at = NOW() WHERE id = $2",
+                )
+                .bind::<diesel::sql_types::Text, _>(export_status::FAILED)
+                .bind::<diesel::sql_types::Integer, _>(export_id)
+                .execute(connection);
+                return Err(e);
+            }
+        };
+        diesel::sql_query(
+            "UPDATE data_exports SET status = $1, file_path = $2, token_hash = $3, expires_at = $4, completed_at = NOW() WHERE id = $5",
+        )
+        .bind::<diesel::sql_types::Text, _>(export_status::READY)
+        .bind::<diesel::sql_types::Text, _>(&file_path)
+        .bind::<diesel::sql_types::Text, _>(hash_token(token))
+        .bind::<diesel::sql_types::Timestamp, _>(expires_at)
+        .bind::<diesel::sql_types::Integer, _>(export_id)
+        .execute(connection)
+        .map_err(|e| {
+            let _ = fs::remove_file(&file_path);
+            e.to_string()
+        })?;
+        Ok(())
+    }
+
+    fn write_archive(
+        connection: &mut PgConnection,
+        zipped: bool,
+        user: &User,
+        token: &str,
+    ) -> Result<(String, NaiveDateTime), String> {
+        let data = Self::collect(connection, user).map_err(|e| e.to_string())?;
+        let json = serde_json::to_vec_pretty(&data).map_err(|e| e.to_string())?;
+
+        let dir = export_dir();
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        // named after the token hash, nothing guessable ends up on disk
+        let name = &hash_token(token)[..32];
+        let file_path = if zipped {
+            let file_path = dir.join(format!("{}.zip", name));
+            let file = fs::File::create(&file_path).map_err(|e| e.to_string())?;
+            let mut zip = zip::ZipWriter::new(file);
+            let options = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated);
+            zip.start_file("data.json", options)
+                .map_err(|e| e.to_string())?;
+            zip.write_all(&json).map_err(|e| e.to_string())?;
+            zip.finish().map_err(|e| e.to_string())?;
+            file_path
+        } else {
+            let file_path = dir.join(format!("{}.json", name));
+            fs::write(&file_path, &json).map_err(|e| e.to_string())?;
+            file_path
+        };
+        Ok((
+            file_path.to_string_lossy().to_string(),
+            Utc::now().naive_utc() + export_ttl(),
+        ))
+    }
+
+    // everything stored about the user. Secrets (password, token and key
+    // hashes, the MFA secret) are left out, only that they exist is shown
+    fn collect(connection: &mut PgConnection, user: &User) -> QueryResult<Value> {
+        let privacy = PrivacyRepository::settings_for(connection, user.id)?;
+        let sessions = refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(user.id))
+            .order(refresh_tokens::created_at.desc())
+            .load::<RefreshToken>(connection)?;
+        let identities = identities::table
+            .filter(identities::user_id.eq(user.id))
+            .load::<Identity>(connection)?;
+        let api_keys = api_keys::table
+            .filter(api_keys::user_id.eq(user.id))
+            .load::<ApiKey>(connection)?;
+        let mfa = user_mfa::table
+            .filter(user_mfa::user_id.eq(user.id))
+            .select((user_mfa::enabled, user_mfa::confirmed_at))
+            .first::<(bool, Option<NaiveDateTime>)>(connection)
+            .optional()?;
+        let roles = user_roles::table
+            .inner_join(roles::table)
+            .filter(user_roles::user_id.eq(user.id))
+            .select(roles::name)
+            .load::<String>(connection)?;
+        let memberships = memberships::table
+            .inner_join(organizations::table)
+            .filter(memberships::user_id.eq(user.id))
+            .select((
+                organizations::id,
+                organizations::name,
+                memberships::role,
+                memberships::created_at,
+            ))
+            .load::<(i32, String, String, NaiveDateTime)>(connection)?;
+        let invitations = invitations::table
+            .filter(invitations::email.eq(user.email.to_lowercase()))
+            .load::<Invitation>(connection)?;
+        let audit_events = audit_events::table
+            .filter(
+                audit_events::user_id
+                    .eq(user.id)
+                    .or(audit_events::actor_id.eq(user.id)),
+            )
+            .order(audit_events::created_at.desc())
+            .load::<AuditEvent>(connection)?;
+        // attempts are recorded with the address as typed at sign in
+        let login_attempts = login_attempts::table
+            .filter(lower(login_attempts::email).eq(user.email.to_lowercase()))
+            .select((
+                login_attempts::ip,
+                login_attempts::succeeded,
+                login_attempts::created_at,
+            ))
+            .order(login_attempts::created_at.desc())
+            .load::<(Option<String>, bool, NaiveDateTime)>(connection)?;
+
+        Ok(json!({
+            "exported_at": Utc::now().naive_utc(),
+            "account": {
+                "id": user.id,
+                "username": user.username,
+                "email": user.email,
+                "email_verified": user.email_verified,
+                "phone": user.phone,
+                "phone_verified": user.phone_verified,
+                "first_name": user.first_name,
+                "last_name": user.last_name,
+                "city": user.city,
+                "state": user.state,
+                "country": user.country,
+                "avatar_url": user.avatar_url,
+                "avatar_thumbnail_url": user.avatar_thumbnail_url,
+                "has_password": user.password.is_some(),
+                "is_staff": user.is_staff,
+                "is_superuser": user.is_superuser,
+                "locked_until": user.locked_until,
+                "created_at": user.created_at,
+                "updated_at": user.updated_at,
+            },
+            "roles": roles,
+            "preferences": {
+                "privacy": privacy,
+            },
+            "mfa": mfa.map(|(enabled, confirmed_at)| json!({
+                "enabled": enabled,
+                "confirmed_at": confirmed_at,
+            })),
+            "sessions": sessions.iter().map(|session| json!({
+                "family_id": session.family_id,
+                "created_at": session.created_at,
+                "expires_at": session.expires_at,
+                "used_at": session.used_at,
+                "revoked_at": session.revoked_at,
+            })).collect::<Vec<Value>>(),
+            "identities": identities.iter().map(|identity| json!({
+                "provider": identity.provider,
+                "subject": identity.subject,
+                "email": identity.email,
+                "created_at": identity.created_at,
+            })).collect::<Vec<Value>>(),
+            "api_keys": api_keys.iter().map(|key| json!({
+                "name": key.name,
+                "prefix": key.prefix,
+                "scopes": key.scopes,
+                "expires_at": key.expires_at,
+                "last_used_at": key.last_used_at,
+                "revoked_at": key.revoked_at,
+                "created_at": key.created_at,
+            })).collect::<Vec<Value>>(),
+            "memberships": memberships.iter().map(|(organization_id, organization, role, joined_at)| json!({
+                "organization_id": organization_id,
+                "organization": organization,
+                "role": role,
+                "joined_at": joined_at,
+            })).collect::<Vec<Value>>(),
+            "invitations": invitations.iter().map(|invitation| json!({
+                "organization_id": invitation.organization_id,
+                "role": invitation.role,
+                "invited_by": invitation.invited_by,
+                "expires_at": invitation.expires_at,
+                "accepted_at": invitation.accepted_at,
+                "declined_at": invitation.declined_at,
+                "revoked_at": invitation.revoked_at,
+                "created_at": invitation.created_at,
+            })).collect::<Vec<Value>>(),
+            "audit_events": audit_events,
+            "login_attempts": login_attempts.iter().map(|(ip, succeeded, created_at)| json!({
+                "ip": ip,
+                "succeeded": succeeded,
+                "created_at": created_at,
+            })).collect::<Vec<Value>>(),
+        }))
+    }
+
+    // the export behind a download link, None once it expired
+    pub async fn download(&self, token: &str) -> Result<Option<DataExport>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Ok(data_exports::table
+            .filter(data_exports::token_hash.eq(hash_token(token)))
+            .filter(data_exports::status.eq(export_status::READY))
+            .filter(data_exports::expires_at.gt(Utc::now().naive_utc()))
+            .first::<DataExport>(connection)
+            .optional()?)
+    }
+
+    // delete the files of expired exports, the rows stay as a record
+    pub fn remove_expired(connection: &mut PgConnection) -> QueryResult<usize> {
+        let expired = data_exports::table
+            .filter(data_exports::file_path.is_not_null())
+            .filter(data_exports::expires_at.lt(Utc::now().naive_utc()))
+            .load::<DataExport>(connection)?;
+        for export in &expired {
+            if let Some(file_path) = &export.file_path {
+                let _ = fs::remove_file(file_path);
+            }
+            diesel::sql_query(
+                "UPDATE data_exports SET file_path = NULL, token_hash = NULL WHERE id = $1",
+            )
+            .bind::<diesel::sql_types::Integer, _>(export.id)
+            .execute(connection)?;
+        }
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataExportRepository;
+    use crate::db::test_pool;
+    use crate::models::data_exports::export_status;
+    use crate::models::users::User;
+    use crate::schema::{data_exports, login_attempts, users};
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use std::fs;
+
+    fn insert_user(connection: &mut PgConnection, username: &str, email: &str) -> User {
+        diesel::insert_into(users::table)
+            .values((users::username.eq(username), users::email.eq(email)))
+            .get_result::<User>(connection)
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    fn collects_login_attempts_whatever_case_they_were_typed_in() {
+        let pool = test_pool();
+        let connection = &mut *pool.get().unwrap();
+        let user = insert_user(connection, "exported", "Exported@example.com");
+        for email in ["exported@example.com", "EXPORTED@example.com"] {
+            diesel::insert_into(login_attempts::table)
+                .values((
+                    login_attempts::email.eq(email),
+                    login_attempts::succeeded.eq(false),
+                ))
+                .execute(connection)
+                .unwrap();
+        }
+
+        let data = DataExportRepository::collect(connection, &user).unwrap();
+        assert_eq!(data["account"]["id"], user.id);
+        assert_eq!(data["login_attempts"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    fn removes_the_files_of_expired_exports() {
+        let pool = test_pool();
+        let connection = &mut *pool.get().unwrap();
+        let user = insert_user(connection, "expired-export", "expired-export@example.com");
+        let file_path = std::env::temp_dir()
+            .join(format!("drgz-expired-export-{}.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        fs::write(&file_path, "{}").unwrap();
+        let export_id = diesel::insert_into(data_exports::table)
+            .values((
+                data_exports::user_id.eq(user.id),
+                data_exports::status.eq(export_status::READY),
+                data_exports::file_path.eq(&file_path),
+                data_exports::token_hash.eq("hash"),
+                data_exports::expires_at.eq(Utc::now().naive_utc() - Duration::hours(1)),
+            ))
+            .returning(data_exports::id)
+            .get_result::<i32>(connection)
+            .unwrap();
+
+        assert!(DataExportRepository::remove_expired(connection).unwrap() >= 1);
+        assert!(!std::path::Path::new(&file_path).exists());
+        let (file_path, token_hash) = data_exports::table
+            .find(export_id)
+            .select((data_exports::file_path, data_exports::token_hash))
+            .first::<(Option<String>, Option<String>)>(connection)
+            .unwrap();
+        assert_eq!((file_path, token_hash), (None, None));
+    }
+}
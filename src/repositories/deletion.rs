@@ -0,0 +1,440 @@
+use crate::handlers::graphql::forbidden;
+use crate::models::users::User;
+use crate::repositories::audit::{
+    AuditRepository, ACCOUNT_DELETED, ACCOUNT_PURGED, ACCOUNT_RESTORED,
+};
+use crate::repositories::token::TokenRepository;
+use crate::repositories::user::SuccessMessage;
+use crate::schema::users;
+use crate::utils::verify_password;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use std::env;
+use std::sync::Arc;
+use tera::Tera;
+
+// ACCOUNT_DELETION_GRACE_PERIOD, in seconds, how long a deleted account can
+// still be restored before its personal details are removed
+pub fn deletion_grace_period() -> Duration {
+    dotenv().ok();
+    let seconds = env::var("ACCOUNT_DELETION_GRACE_PERIOD")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(60 * 60 * 24 * 30);
+    Duration::seconds(seconds)
+}
+
+// ACCOUNT_PURGE_INTERVAL, in seconds, how often the server looks for
+// accounts to purge
+pub fn purge_interval() -> std::time::Duration {
+    dotenv().ok();
+    let seconds = env::var("ACCOUNT_PURGE_INTERVAL")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(60 * 60);
+    std::time::Duration::from_secs(seconds)
+}
+
+// accounts anonymized by one purge run
+pub struct Purged {
+    pub count: usize,
+    // avatars of the purged accounts, still to be removed from storage
+    pub avatar_keys: Vec<String>,
+}
+
+fn user_not_found() -> FieldError {
+    FieldError::new(
+        "User not found",
+        graphql_value!("user_not_found".to_string()),
+    )
+}
+
+pub struct DeletionRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl DeletionRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> DeletionRepository {
+        DeletionRepository { pool }
+    }
+
+    // the user deleting their own account, confirmed with their password
+    pub async fn delete_own(
+        &self,
+        user_id: i32,
+        password: String,
+        ip: Option<String>,
+        tera: Arc<Tera>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let user = {
+            let connection = &mut *self.pool.get()?;
+            users::table
+                .filter(users::id.eq(user_id))
+                .filter(users::deleted.eq(false))
+                .first::<User>(connection)
+                .map_err(|_e| user_not_found())?
+        };
+        if !verify_password(&password, &user.password) {
+            return Err(FieldError::new(
+                "invalid credentials",
+                graphql_value!("invalid_credentials".to_string()),
+            ));
+        }
+        self.delete_user(user, None, ip, tera).await
+    }
+
+    // staff deleting an account, the owner is told by email all the same.
+    // Staff delete their own account with their password like everyone
+    // else, and only a superuser can delete a superuser
+    pub async fn delete(
+        &self,
+        actor_id: i32,
+        actor_is_superuser: bool,
+        user_id: i32,
+        ip: Option<String>,
+        tera: Arc<Tera>,
+    ) -> Result<SuccessMessage, FieldError> {
+        if actor_id == user_id {
+            return Err(FieldError::new(
+                "Use deleteMyAccount to delete your own account",
+                graphql_value!("invalid_deletion".to_string()),
+            ));
+        }
+        let user = {
+            let connection = &mut *self.pool.get()?;
+            users::table
+                .filter(users::id.eq(user_id))
+                .filter(users::deleted.eq(false))
+                .first::<User>(connection)
+                .map_err(|_e| user_not_found())?
+        };
+        if user.is_superuser && !actor_is_superuser {
+            return Err(forbidden());
+        }
+        self.delete_user(user, Some(actor_id), ip, tera).await
+    }
+
+    async fn delete_user(
+        &self,
+        user: User,
+        actor_id: Option<i32>,
+        ip: Option<String>,
+        tera: Arc<Tera>,
+    ) -> Result<SuccessMessage, FieldError> {
+        {
+            let connection = &mut *self.pool.get()?;
+            connection
+                .transaction::<_, diesel::result::Error, _>(|connection| {
+                    diesel::sql_query(
+                        "UPDATE users SET deleted = true, deleted_at = NOW(), updated_at = NOW() WHERE id = $1",
+                    )
+                    .bind::<diesel::sql_types::Integer, _>(user.id)
+                    .execute(connection)?;
+                    AuditRepository::record(
+                        connection,
+                        actor_id,
+                        Some(user.id),
+                        ACCOUNT_DELETED,
+                        None,
+                        ip,
+                    )?;
+                    Ok(())
+                })
+                .map_err(|_e| {
+                    FieldError::new(
+                        "Database error",
+                        graphql_value!("internal_error".to_string()),
+                    )
+                })?;
+        }
+        TokenRepository::new(self.pool.clone())
+            .logout_all(user.id)
+            .await?;
+
+        let mut mail_context = tera::Context::new();
+        mail_context.insert("username", &user.username);
+        mail_context.insert("email", &user.email);
+        mail_context.insert("domain", "http://localhost:3000");
+        mail_context.insert("logo", "https://www.elegal.ascendth.com/_next/image?url=https%3A%2F%2Felegal-ascend.s3.amazonaws.com%2Fpublic%2Flogo.png&w=256&q=75");
+        mail_context.insert("company", "drgz");
+        mail_context.insert("days", &deletion_grace_period().num_days());
+        crate::mailer::send_html_email(
+            &user.email,
+            "info@ascendth.com",
+            "Your account was deleted",
+            "emails/account-deleted.html",
+            &mail_context,
+            tera,
+        )
+        .await;
+
+        Ok(SuccessMessage {
+            message: "Account deleted".to_string(),
+            success: true,
+        })
+    }
+
+    // undo a deletion during the grace period, unless someone else took the
+    // username or email in the meantime
+    pub async fn restore(
+        &self,
+        actor_id: i32,
+        user_id: i32,
+        ip: Option<String>,
+    ) -> Result<User, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::deleted.eq(true))
+            .first::<User>(connection)
+            .map_err(|_e| user_not_found())?;
+        if user.purged_at.is_some() {
+            return Err(FieldError::new(
+                "The account was already purged",
+                graphql_value!("account_purged".to_string()),
+            ));
+        }
+
+        let taken = users::table
+            .filter(users::deleted.eq(false))
+            .filter(
+                users::email
+                    .eq(&user.email)
+                    .or(users::username.eq(&user.username)),
+            )
+            .count()
+            .get_result::<i64>(connection)?;
+        if taken > 0 {
+            return Err(FieldError::new(
+                "The username or email is now used by another account",
+                graphql_value!("account_conflict".to_string()),
+            ));
+        }
+
+        connection
+            .transaction::<_, diesel::result::Error, _>(|connection| {
+                diesel::sql_query(
+                    "UPDATE users SET deleted = false, deleted_at = NULL, updated_at = NOW() WHERE id = $1",
+                )
+                .bind::<diesel::sql_types::Integer, _>(user.id)
+                .execute(connection)?;
+                AuditRepository::record(
+                    connection,
+                    Some(actor_id),
+                    Some(user.id),
+                    ACCOUNT_RESTORED,
+                    None,
+                    ip,
+                )?;
+                Ok(())
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(users::table.find(user.id).first::<User>(connection)?)
+    }
+
+    // deleted accounts that were not purged yet, newest first
+    pub async fn deleted_users(&self) -> Result<Vec<User>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Ok(users::table
+            .filter(users::deleted.eq(true))
+            .filter(users::purged_at.is_null())
+            .order(users::deleted_at.desc())
+            .load::<User>(connection)?)
+    }
+
+    // anonymize accounts deleted longer than the grace period ago. The row
+    // stays so audit events and organizations keep pointing somewhere, but
+    // everything identifying the person and every way to sign in is removed
+    pub fn purge_expired(connection: &mut PgConnection) -> QueryResult<Purged> {
+        let cutoff = Utc::now().naive_utc() - deletion_grace_period();
+        let expired = users::table
+            .filter(users::deleted.eq(true))
+            .filter(users::purged_at.is_null())
+            .filter(users::deleted_at.lt(cutoff))
+            .select((users::id, users::email, users::avatar_key))
+            .load::<(i32, String, Option<String>)>(connection)?;
+
+        for (user_id, email, _) in &expired {
+            connection.transaction::<_, diesel::result::Error, _>(|connection| {
+                for sql in [
+                    "DELETE FROM identities WHERE user_id = $1",
+                    "DELETE FROM api_keys WHERE user_id = $1",
+                    "DELETE FROM refresh_tokens WHERE user_id = $1",
+                    "DELETE FROM mfa_recovery_codes WHERE user_id = $1",
+                    "DELETE FROM user_mfa WHERE user_id = $1",
+                    "DELETE FROM sms_codes WHERE user_id = $1",
+                    "DELETE FROM privacy_settings WHERE user_id = $1",
+                ] {
+                    diesel::sql_query(sql)
+                        .bind::<diesel::sql_types::Integer, _>(user_id)
+                        .execute(connection)?;
+                }
+                // these are kept by address, which may belong to a new
+                // account by now; its history and invitations are left alone
+                for sql in [
+                    "DELETE FROM login_attempts WHERE lower(email) = lower($1) \
+                    AND NOT EXISTS (SELECT 1 FROM users WHERE deleted = false AND lower(email) = lower($1))",
+                    "DELETE FROM verification_emails WHERE lower(email) = lower($1) \
+                    AND NOT EXISTS (SELECT 1 FROM users WHERE deleted = false AND lower(email) = lower($1))",
+                    "DELETE FROM invitations WHERE lower(email) = lower($1) \
+                    AND accepted_at IS NULL AND declined_at IS NULL AND revoked_at IS NULL \
+                    AND NOT EXISTS (SELECT 1 FROM users WHERE deleted = false AND lower(email) = lower($1))",
+                ] {
+                    diesel::sql_query(sql)
+                        .bind::<diesel::sql_types::Text, _>(email)
+                        .execute(connection)?;
+                }
+                diesel::sql_query(
+                    "UPDATE users SET username = 'deleted-' || id, email = 'deleted-' || id || '@deleted.invalid', \
+                    phone = NULL, phone_verified = false, first_name = NULL, last_name = NULL, city = NULL, \
+                    state = NULL, country = NULL, password = NULL, avatar_key = NULL, avatar_url = NULL, \
+                    avatar_thumbnail_url = NULL, purged_at = NOW(), updated_at = NOW() WHERE id = $1",
+                )
+                .bind::<diesel::sql_types::Integer, _>(user_id)
+                .execute(connection)?;
+                AuditRepository::record(
+                    connection,
+                    None,
+                    Some(*user_id),
+                    ACCOUNT_PURGED,
+                    None,
+                    None,
+                )
+            })?;
+        }
+        Ok(Purged {
+            count: expired.len(),
+            avatar_keys: expired
+                .into_iter()
+                .filter_map(|(_, _, avatar_key)| avatar_key)
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeletionRepository;
+    use crate::db::test_pool;
+    use crate::schema::{login_attempts, users, verification_emails};
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use std::sync::Arc;
+    use tera::Tera;
+
+    fn insert_user(
+        connection: &mut PgConnection,
+        username: &str,
+        email: &str,
+        deleted: bool,
+    ) -> i32 {
+        diesel::insert_into(users::table)
+            .values((
+                users::username.eq(username),
+                users::email.eq(email),
+                users::deleted.eq(deleted),
+                users::deleted_at.eq(deleted.then(|| Utc::now().naive_utc() - Duration::days(365))),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(connection)
+            .unwrap()
+    }
+
+    fn history(connection: &mut PgConnection, email: &str) -> (i64, i64) {
+        let attempts = login_attempts::table
+            .filter(login_attempts::email.eq(email))
+            .count()
+            .get_result::<i64>(connection)
+            .unwrap();
+        let verifications = verification_emails::table
+            .filter(verification_emails::email.eq(email))
+            .count()
+            .get_result::<i64>(connection)
+            .unwrap();
+        (attempts, verifications)
+    }
+
+    fn add_history(connection: &mut PgConnection, email: &str) {
+        diesel::insert_into(login_attempts::table)
+            .values((
+                login_attempts::email.eq(email),
+                login_attempts::succeeded.eq(false),
+            ))
+            .execute(connection)
+            .unwrap();
+        diesel::insert_into(verification_emails::table)
+            .values(verification_emails::email.eq(email))
+            .execute(connection)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    fn purge_removes_the_history_of_the_address() {
+        let pool = test_pool();
+        let connection = &mut *pool.get().unwrap();
+        insert_user(connection, "gone", "gone@example.com", true);
+        add_history(connection, "gone@example.com");
+
+        let purged = DeletionRepository::purge_expired(connection).unwrap();
+        assert!(purged.count >= 1);
+        assert_eq!(history(connection, "gone@example.com"), (0, 0));
+    }
+
+    #[test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    fn purge_keeps_the_history_of_an_account_that_took_the_address() {
+        let pool = test_pool();
+        let connection = &mut *pool.get().unwrap();
+        insert_user(connection, "before", "again@example.com", true);
+        insert_user(connection, "after", "again@example.com", false);
+        add_history(connection, "again@example.com");
+
+        DeletionRepository::purge_expired(connection).unwrap();
+        assert_eq!(history(connection, "again@example.com"), (1, 1));
+    }
+
+    fn error_code(e: juniper::FieldError) -> String {
+        e.extensions().as_string_value().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn staff_cannot_delete_themselves_or_superusers() {
+        let repository = DeletionRepository::new(test_pool());
+        let (staff, superuser) = {
+            let connection = &mut *repository.pool.get().unwrap();
+            let staff = insert_user(connection, "staff", "staff@example.com", false);
+            let superuser = insert_user(connection, "root", "root@example.com", false);
+            diesel::update(users::table.find(superuser))
+                .set(users::is_superuser.eq(true))
+                .execute(connection)
+                .unwrap();
+            (staff, superuser)
+        };
+        let tera = Arc::new(Tera::default());
+
+        let e = repository
+            .delete(staff, false, staff, None, tera.clone())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "invalid_deletion");
+
+        let e = repository
+            .delete(staff, false, superuser, None, tera)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "forbidden");
+    }
+}
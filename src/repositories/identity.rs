@@ -0,0 +1,583 @@
+use crate::models::identities::{Identity, OAuthState};
+use crate::models::users::User;
+use crate::oauth::{self, ExternalIdentity};
+use crate::repositories::token::TokenRepository;
+use crate::repositories::user::{LoginResult, SuccessMessage, UserRepository};
+use crate::schema::{identities, oauth_states, users};
+use crate::utils::{hash_token, verify_action_token, TokenError, TokenPurpose};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use rand::Rng;
+use std::sync::Arc;
+
+// how long a started sign in or link stays valid, also the lifetime of the
+// nonce cookie
+pub const OAUTH_STATE_TTL_SECONDS: i64 = 10 * 60;
+
+pub struct IdentityRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl IdentityRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> IdentityRepository {
+        IdentityRepository { pool }
+    }
+
+    // authorization url for the provider, remembering the PKCE verifier,
+    // the hash of the browser's nonce cookie and, when linking, the user the
+    // identity should be attached to
+    pub async fn authorize_url(
+        &self,
+        provider: &str,
+        link_user_id: Option<i32>,
+        nonce: &str,
+    ) -> Result<String, FieldError> {
+        let oauth_provider = oauth::provider(provider).ok_or_else(unknown_provider)?;
+        let connection = &mut *self.pool.get()?;
+        let (code_verifier, code_challenge) = oauth::pkce_pair();
+        let (state, _) = oauth::pkce_pair();
+
+        let sql = "INSERT INTO oauth_states (state, provider, code_verifier, link_user_id, expires_at, nonce_hash) VALUES ($1, $2, $3, $4, $5, $6)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(&state)
+            .bind::<diesel::sql_types::Text, _>(provider)
+            .bind::<diesel::sql_types::Text, _>(&code_verifier)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Integer>, _>(link_user_id)
+            .bind::<diesel::sql_types::Timestamp, _>(
+                Utc::now().naive_utc() + Duration::seconds(OAUTH_STATE_TTL_SECONDS),
+            )
+            .bind::<diesel::sql_types::Text, _>(hash_token(nonce))
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        oauth_provider
+            .authorize_url(&state, &code_challenge, &oauth::redirect_uri(provider))
+            .await
+            .map_err(provider_error)
+    }
+
+    // finish the provider redirect, returning the user to sign in. `nonce`
+    // is the cookie of the browser landing here, which has to be the one
+    // that started the flow
+    pub async fn callback(
+        &self,
+        provider: &str,
+        code: &str,
+        state: &str,
+        nonce: Option<&str>,
+    ) -> Result<User, FieldError> {
+        let oauth_provider = oauth::provider(provider).ok_or_else(unknown_provider)?;
+        let oauth_state = self.take_state(provider, state, nonce)?;
+        let identity = oauth_provider
+            .fetch_identity(
+                code,
+                &oauth_state.code_verifier,
+                &oauth::redirect_uri(provider),
+            )
+            .await
+            .map_err(provider_error)?;
+        self.resolve_user(provider, identity, oauth_state.link_user_id)
+    }
+
+    // exchange the one-time code handed to the frontend for a session
+    pub async fn complete_login(&self, code: String) -> Result<LoginResult, FieldError> {
+        let claims = verify_action_token(&code, TokenPurpose::OAuthLogin)
+            .map_err(TokenError::into_field_error)?;
+        let user_id = claims
+            .uid
+            .ok_or_else(|| TokenError::Invalid.into_field_error())?;
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .map_err(|_e| TokenError::Invalid.into_field_error())?;
+        TokenRepository::consume_action_token(connection, &claims)?;
+        UserRepository::complete_login(connection, user)
+    }
+
+    pub async fn identities(&self, user_id: i32) -> Result<Vec<Identity>, FieldError> {
+        let conn = &mut *self.pool.get()?;
+        let identities = identities::table
+            .filter(identities::user_id.eq(user_id))
+            .order(identities::provider)
+            .load::<Identity>(conn)?;
+        Ok(identities)
+    }
+
+    pub async fn unlink(
+        &self,
+        user_id: i32,
+        provider: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .first::<User>(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "User not found",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        let linked = identities::table
+            .filter(identities::user_id.eq(user_id))
+            .filter(identities::provider.eq(&provider))
+            .count()
+            .get_result::<i64>(connection)?;
+        if linked == 0 {
+            return Err(FieldError::new(
+                "No account of this provider is linked",
+                graphql_value!("identity_not_found".to_string()),
+            ));
+        }
+        let other_providers = identities::table
+            .filter(identities::user_id.eq(user_id))
+            .filter(identities::provider.ne(&provider))
+            .count()
+            .get_result::<i64>(connection)?;
+
+        // never remove the last way to sign in
+        if user.password.is_none() && other_providers == 0 {
+            return Err(FieldError::new(
+                "Set a password before removing your last linked account",
+                graphql_value!("last_sign_in_method".to_string()),
+            ));
+        }
+
+        diesel::sql_query("DELETE FROM identities WHERE user_id = $1 AND provider = $2")
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(&provider)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(SuccessMessage {
+            message: "Account unlinked".to_string(),
+            success: true,
+        })
+    }
+
+    fn take_state(
+        &self,
+        provider: &str,
+        state: &str,
+        nonce: Option<&str>,
+    ) -> Result<OAuthState, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let invalid_state = || {
+            FieldError::new(
+                "Invalid or expired sign in attempt",
+                graphql_value!("invalid_oauth_state".to_string()),
+            )
+        };
+
+        let oauth_state = oauth_states::table
+            .filter(oauth_states::state.eq(state))
+            .first::<OAuthState>(connection)
+            .optional()?
+            .ok_or_else(invalid_state)?;
+
+        let sql = "DELETE FROM oauth_states WHERE state = $1 OR expires_at < $2";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(state)
+            .bind::<diesel::sql_types::Timestamp, _>(Utc::now().naive_utc())
+            .execute(connection)?;
+
+        if oauth_state.provider != provider
+            || oauth_state.expires_at < Utc::now().naive_utc()
+            || nonce.map(hash_token).as_deref() != Some(oauth_state.nonce_hash.as_str())
+        {
+            return Err(invalid_state());
+        }
+        Ok(oauth_state)
+    }
+
+    // find the user behind an external identity, linking it to an existing
+    // account or creating a new one the first time it is seen
+    fn resolve_user(
+        &self,
+        provider: &str,
+        identity: ExternalIdentity,
+        link_user_id: Option<i32>,
+    ) -> Result<User, FieldError> {
+        let connection = &mut *self.pool.get()?;
+
+        let linked = identities::table
+            .inner_join(users::table)
+            .filter(identities::provider.eq(provider))
+            .filter(identities::subject.eq(&identity.subject))
+            .filter(users::deleted.eq(false))
+            .select(users::all_columns)
+            .first::<User>(connection)
+            .optional()?;
+        // linked to an account deleted but not purged yet, which has to be
+        // restored before the identity signs in again
+        if linked.is_none() {
+            let taken = identities::table
+                .filter(identities::provider.eq(provider))
+                .filter(identities::subject.eq(&identity.subject))
+                .count()
+                .get_result::<i64>(connection)?;
+            if taken > 0 {
+                return Err(FieldError::new(
+                    "invalid credentials",
+                    graphql_value!("invalid_credentials".to_string()),
+                ));
+            }
+        }
+
+        if let Some(user) = linked {
+            if link_user_id.is_some() && link_user_id != Some(user.id) {
+                return Err(FieldError::new(
+                    "This account is already linked to another user",
+                    graphql_value!("identity_already_linked".to_string()),
+                ));
+            }
+            return Ok(user);
+        }
+
+        let user = match link_user_id {
+            Some(user_id) => users::table
+                .filter(users::id.eq(user_id))
+                .first::<User>(connection)?,
+            None => {
+                let email = identity.email.clone().ok_or_else(|| {
+                    FieldError::new(
+                        "The provider did not share an email address",
+                        graphql_value!("email_required".to_string()),
+                    )
+                })?;
+                let existing = users::table
+                    .filter(users::email.eq(&email))
+                    .filter(users::deleted.eq(false))
+                    .first::<User>(connection)
+                    .optional()?;
+
+                match existing {
+                    // only link when both sides verified the address. A local
+                    // account that never confirmed its email may have been
+                    // registered by someone else to wait for the owner
+                    Some(user) if identity.email_verified && user.email_verified => user,
+                    Some(_) if identity.email_verified => {
+                        return Err(FieldError::new(
+                            "An account with this email exists but its address is not verified, sign in with its password or reset it first",
+                            graphql_value!("account_not_verified".to_string()),
+                        ))
+                    }
+                    Some(_) => {
+                        return Err(FieldError::new(
+                            "user with email already exists",
+                            graphql_value!("email_already_exists".to_string()),
+                        ))
+                    }
+                    None => Self::create_user(connection, &identity, &email)?,
+                }
+            }
+        };
+
+        let sql =
+            "INSERT INTO identities (user_id, provider, subject, email) VALUES ($1, $2, $3, $4)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user.id)
+            .bind::<diesel::sql_types::Text, _>(provider)
+            .bind::<diesel::sql_types::Text, _>(&identity.subject)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(&identity.email)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(user)
+    }
+
+    fn create_user(
+        connection: &mut PgConnection,
+        identity: &ExternalIdentity,
+        email: &str,
+    ) -> Result<User, FieldError> {
+        let base: String = identity
+            .username
+            .as_deref()
+            .unwrap_or_else(|| email.split('@').next().unwrap_or(""))
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.' || *c == '-')
+            .collect();
+        let base = if base.len() < 3 {
+            format!("user{}", base)
+        } else {
+            base
+        };
+
+        let mut username = base.clone();
+        loop {
+            let taken = users::table
+                .filter(users::username.eq(&username))
+                .count()
+                .get_result::<i64>(connection)?;
+            if taken == 0 {
+                break;
+            }
+            username = format!("{}{}", base, rand::thread_rng().gen_range(1000..10000));
+        }
+
+        let sql = "INSERT INTO users (username, email, email_verified) VALUES ($1, $2, $3)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(&username)
+            .bind::<diesel::sql_types::Text, _>(email)
+            .bind::<diesel::sql_types::Bool, _>(identity.email_verified)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        let user = users::table
+            .filter(users::email.eq(email))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)?;
+        Ok(user)
+    }
+}
+
+fn unknown_provider() -> FieldError {
+    FieldError::new(
+        "Unknown sign in provider",
+        graphql_value!("unknown_provider".to_string()),
+    )
+}
+
+fn provider_error(e: oauth::OAuthError) -> FieldError {
+    println!("OAuth error: {}", e);
+    FieldError::new(
+        "Sign in with the provider failed",
+        graphql_value!("oauth_error".to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdentityRepository;
+    use crate::db::test_pool;
+    use crate::oauth::mock::start_issuer;
+    use crate::oauth::ExternalIdentity;
+    use crate::schema::{identities, oauth_states, users};
+    use crate::repositories::user::LoginResult;
+    use crate::utils::{generate_user_action_token, hash_token, TokenPurpose};
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use serde_json::json;
+
+    fn insert_user(
+        repository: &IdentityRepository,
+        email: &str,
+        password: Option<&str>,
+        email_verified: bool,
+    ) -> i32 {
+        let connection = &mut *repository.pool.get().unwrap();
+        diesel::insert_into(users::table)
+            .values((
+                users::username.eq(email.split('@').next().unwrap()),
+                users::email.eq(email),
+                users::password.eq(password.map(|password| bcrypt::hash(password, 4).unwrap())),
+                users::email_verified.eq(email_verified),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(connection)
+            .unwrap()
+    }
+
+    async fn identity_from_issuer(email: &str) -> ExternalIdentity {
+        let provider = start_issuer(json!({
+            "sub": format!("sub-{}", email),
+            "email": email,
+            "email_verified": true,
+            "preferred_username": "someone",
+        }));
+        provider
+            .fetch_identity("code", "verifier", "http://localhost/callback")
+            .await
+            .unwrap()
+    }
+
+    fn error_code(e: juniper::FieldError) -> String {
+        e.extensions().as_string_value().unwrap().to_string()
+    }
+
+    fn insert_state(repository: &IdentityRepository, state: &str, nonce: &str) {
+        let connection = &mut *repository.pool.get().unwrap();
+        diesel::insert_into(oauth_states::table)
+            .values((
+                oauth_states::state.eq(state),
+                oauth_states::provider.eq("mock"),
+                oauth_states::code_verifier.eq("verifier"),
+                oauth_states::expires_at.eq(Utc::now().naive_utc() + Duration::minutes(10)),
+                oauth_states::nonce_hash.eq(hash_token(nonce)),
+            ))
+            .execute(connection)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    fn only_the_browser_that_started_the_flow_can_finish_it() {
+        let repository = IdentityRepository::new(test_pool());
+
+        insert_state(&repository, "state-1", "nonce");
+        let e = repository
+            .take_state("mock", "state-1", None)
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "invalid_oauth_state");
+
+        insert_state(&repository, "state-2", "nonce");
+        let e = repository
+            .take_state("mock", "state-2", Some("another browser"))
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "invalid_oauth_state");
+        // a refused attempt still uses up the state
+        let e = repository
+            .take_state("mock", "state-2", Some("nonce"))
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "invalid_oauth_state");
+
+        insert_state(&repository, "state-3", "nonce");
+        let state = repository
+            .take_state("mock", "state-3", Some("nonce"))
+            .ok()
+            .unwrap();
+        assert_eq!(state.code_verifier, "verifier");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn links_a_verified_account_with_the_same_email() {
+        let repository = IdentityRepository::new(test_pool());
+        let user_id = insert_user(&repository, "owner@example.com", Some("pw"), true);
+
+        let identity = identity_from_issuer("owner@example.com").await;
+        let user = repository.resolve_user("mock", identity, None).unwrap();
+        assert_eq!(user.id, user_id);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn does_not_link_an_unverified_account_with_the_same_email() {
+        let repository = IdentityRepository::new(test_pool());
+        let user_id = insert_user(&repository, "victim@example.com", Some("squatter"), false);
+
+        let identity = identity_from_issuer("victim@example.com").await;
+        let e = repository
+            .resolve_user("mock", identity, None)
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "account_not_verified");
+
+        let connection = &mut *repository.pool.get().unwrap();
+        let linked = identities::table
+            .filter(identities::user_id.eq(user_id))
+            .count()
+            .get_result::<i64>(connection)
+            .unwrap();
+        assert_eq!(linked, 0);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn creates_an_account_for_a_new_email() {
+        let repository = IdentityRepository::new(test_pool());
+
+        let identity = identity_from_issuer("new@example.com").await;
+        let user = repository.resolve_user("mock", identity, None).unwrap();
+        assert_eq!(user.email, "new@example.com");
+        assert!(user.email_verified);
+        assert!(user.password.is_none());
+
+        // signing in again finds the same account through the identity
+        let identity = identity_from_issuer("new@example.com").await;
+        let again = repository.resolve_user("mock", identity, None).unwrap();
+        assert_eq!(again.id, user.id);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn does_not_sign_in_to_a_deleted_account() {
+        let repository = IdentityRepository::new(test_pool());
+        let identity = identity_from_issuer("deleted@example.com").await;
+        let user = repository.resolve_user("mock", identity, None).unwrap();
+        {
+            let connection = &mut *repository.pool.get().unwrap();
+            diesel::update(users::table.find(user.id))
+                .set(users::deleted.eq(true))
+                .execute(connection)
+                .unwrap();
+        }
+
+        let identity = identity_from_issuer("deleted@example.com").await;
+        let e = repository
+            .resolve_user("mock", identity, None)
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "invalid_credentials");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn refuses_to_unlink_the_last_way_to_sign_in() {
+        let repository = IdentityRepository::new(test_pool());
+        let identity = identity_from_issuer("only@example.com").await;
+        let user = repository.resolve_user("mock", identity, None).unwrap();
+
+        let e = repository
+            .unlink(user.id, "mock".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "last_sign_in_method");
+
+        let identity = identity_from_issuer("only@example.com").await;
+        repository
+            .resolve_user("other", identity, Some(user.id))
+            .unwrap();
+        assert!(repository.unlink(user.id, "mock".to_string()).await.is_ok());
+        let e = repository
+            .unlink(user.id, "other".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "last_sign_in_method");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn the_login_code_signs_in_once() {
+        let repository = IdentityRepository::new(test_pool());
+        let user_id = insert_user(&repository, "code@example.com", None, true);
+        let code =
+            generate_user_action_token(user_id, "code@example.com", TokenPurpose::OAuthLogin);
+
+        match repository.complete_login(code.clone()).await.ok().unwrap() {
+            LoginResult::Success(response) => assert_eq!(response.user.id, user_id),
+            LoginResult::MfaRequired(_) => panic!("no second factor is set up"),
+        }
+        let e = repository.complete_login(code).await.err().unwrap();
+        assert_eq!(error_code(e), "token_already_used");
+    }
+}
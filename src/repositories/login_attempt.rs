@@ -0,0 +1,334 @@
+use crate::models::users::User;
+use crate::repositories::mfa::MfaRepository;
+use crate::repositories::token::TokenRepository;
+use crate::repositories::user::SuccessMessage;
+use crate::schema::{login_attempts, users};
+use crate::utils::{
+    generate_action_token, password_fingerprint, verify_action_token, TokenError, TokenPurpose,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use std::env;
+use std::sync::Arc;
+use tera::Tera;
+
+// how hard failed logins are punished, every value can be set in the env
+pub struct LoginPolicy {
+    // consecutive failures before the account is locked
+    pub max_failures: i32,
+    pub lockout: Duration,
+    // longest wait between attempts while failures add up
+    pub max_delay: Duration,
+    // failures from one ip within ip_window before the ip is refused
+    pub max_ip_failures: i64,
+    pub ip_window: Duration,
+}
+
+impl LoginPolicy {
+    pub fn from_env() -> LoginPolicy {
+        dotenv().ok();
+        let var = |name: &str, default: i64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .unwrap_or(default)
+        };
+        LoginPolicy {
+            max_failures: var("LOGIN_MAX_FAILURES", 5) as i32,
+            lockout: Duration::seconds(var("LOGIN_LOCKOUT", 60 * 15)),
+            max_delay: Duration::seconds(var("LOGIN_MAX_DELAY", 30)),
+            max_ip_failures: var("LOGIN_MAX_IP_FAILURES", 50),
+            ip_window: Duration::seconds(var("LOGIN_IP_WINDOW", 60 * 15)),
+        }
+    }
+
+    // wait required after the nth consecutive failure, doubling each time
+    fn delay(&self, failures: i32) -> Duration {
+        if failures <= 0 {
+            return Duration::zero();
+        }
+        let seconds = 1i64 << (failures - 1).min(16);
+        Duration::seconds(seconds).min(self.max_delay)
+    }
+}
+
+pub struct LoginAttemptRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl LoginAttemptRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> LoginAttemptRepository {
+        LoginAttemptRepository { pool }
+    }
+
+    // refuse an ip that keeps failing, whichever accounts it tries
+    pub fn check_ip(
+        connection: &mut PgConnection,
+        policy: &LoginPolicy,
+        ip: &Option<String>,
+    ) -> Result<(), FieldError> {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        let failures = login_attempts::table
+            .filter(login_attempts::ip.eq(ip))
+            .filter(login_attempts::succeeded.eq(false))
+            .filter(login_attempts::created_at.gt(Utc::now().naive_utc() - policy.ip_window))
+            .count()
+            .get_result::<i64>(connection)?;
+        if failures >= policy.max_ip_failures {
+            return Err(FieldError::new(
+                "Too many failed login attempts, try again later",
+                graphql_value!("too_many_attempts".to_string()),
+            ));
+        }
+        Ok(())
+    }
+
+    // refuse a locked account, or one tried again before its delay is over
+    pub fn check_account(
+        connection: &mut PgConnection,
+        policy: &LoginPolicy,
+        user: &User,
+    ) -> Result<(), FieldError> {
+        let now = Utc::now().naive_utc();
+        if user
+            .locked_until
+            .is_some_and(|locked_until| locked_until > now)
+        {
+            return Err(FieldError::new(
+                "Account locked after too many failed login attempts",
+                graphql_value!("account_locked".to_string()),
+            ));
+        }
+        if user.failed_login_count == 0 {
+            return Ok(());
+        }
+
+        let last_failure = login_attempts::table
+            .filter(login_attempts::email.eq(&user.email))
+            .filter(login_attempts::succeeded.eq(false))
+            .select(login_attempts::created_at)
+            .order(login_attempts::created_at.desc())
+            .first::<NaiveDateTime>(connection)
+            .optional()?;
+        if let Some(last_failure) = last_failure {
+            let retry_at = last_failure + policy.delay(user.failed_login_count);
+            if retry_at > now {
+                return Err(FieldError::new(
+                    format!(
+                        "Too many failed login attempts, try again in {} seconds",
+                        (retry_at - now).num_seconds() + 1
+                    ),
+                    graphql_value!("login_throttled".to_string()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // record a failed attempt, returns true when it locked the account
+    pub fn record_failure(
+        connection: &mut PgConnection,
+        policy: &LoginPolicy,
+        email: &str,
+        ip: &Option<String>,
+        user: Option<&User>,
+    ) -> Result<bool, FieldError> {
+        let now = Utc::now().naive_utc();
+        Self::record(connection, email, ip, false)?;
+
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        // count in the database so concurrent attempts are not lost
+        let failures = diesel::update(users::table.filter(users::id.eq(user.id)))
+            .set(users::failed_login_count.eq(users::failed_login_count + 1))
+            .returning(users::failed_login_count)
+            .get_result::<i32>(connection)?;
+        let locked = failures >= policy.max_failures;
+        if locked {
+            let sql = "UPDATE users SET failed_login_count = 0, locked_until = $1 WHERE id = $2";
+            diesel::sql_query(sql)
+                .bind::<diesel::sql_types::Timestamp, _>(now + policy.lockout)
+                .bind::<diesel::sql_types::Integer, _>(user.id)
+                .execute(connection)
+                .map_err(|_e| {
+                    FieldError::new(
+                        "Database error",
+                        graphql_value!("internal_error".to_string()),
+                    )
+                })?;
+        }
+        Ok(locked)
+    }
+
+    pub fn record_success(
+        connection: &mut PgConnection,
+        user: &User,
+        ip: &Option<String>,
+    ) -> Result<(), FieldError> {
+        Self::record(connection, &user.email, ip, true)?;
+        // with two-factor authentication the sign in is only complete once
+        // the code is accepted, wrong codes stay counted until then
+        if (user.failed_login_count > 0 || user.locked_until.is_some())
+            && !MfaRepository::is_enabled(connection, user.id)?
+        {
+            Self::clear_lock(connection, user.id)?;
+        }
+        Ok(())
+    }
+
+    pub fn record_mfa_success(
+        connection: &mut PgConnection,
+        user: &User,
+        ip: &Option<String>,
+    ) -> Result<(), FieldError> {
+        Self::record(connection, &user.email, ip, true)?;
+        if user.failed_login_count > 0 || user.locked_until.is_some() {
+            Self::clear_lock(connection, user.id)?;
+        }
+        Ok(())
+    }
+
+    // email a link that lifts the lock, answering the same whether or not
+    // the account exists or is locked
+    pub async fn request_unlock(
+        &self,
+        email: String,
+        tera: Arc<Tera>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::email.eq(&email))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .optional()?;
+        if let Some(user) = user {
+            let now = Utc::now().naive_utc();
+            if user
+                .locked_until
+                .is_some_and(|locked_until| locked_until > now)
+            {
+                Self::send_unlock_email(&user, tera).await;
+            }
+        }
+        Ok(SuccessMessage {
+            message: "Unlock instructions sent".to_string(),
+            success: true,
+        })
+    }
+
+    pub async fn send_unlock_email(user: &User, tera: Arc<Tera>) {
+        let mut mail_context = tera::Context::new();
+        mail_context.insert("username", &user.username);
+        mail_context.insert("email", &user.email);
+        mail_context.insert("domain", "http://localhost:3000");
+        mail_context.insert("logo", "https://www.elegal.ascendth.com/_next/image?url=https%3A%2F%2Felegal-ascend.s3.amazonaws.com%2Fpublic%2Flogo.png&w=256&q=75");
+        mail_context.insert("company", "drgz");
+        mail_context.insert(
+            "link",
+            &format!(
+                "http://localhost:3000/unlock-account/{}",
+                generate_action_token(
+                    &user.email,
+                    TokenPurpose::UnlockAccount,
+                    Some(password_fingerprint(&user.password))
+                )
+            ),
+        );
+        crate::mailer::send_html_email(
+            &user.email,
+            "info@ascendth.com",
+            "Account locked",
+            "emails/account-locked.html",
+            &mail_context,
+            tera,
+        )
+        .await;
+    }
+
+    pub async fn unlock_with_token(&self, token: String) -> Result<SuccessMessage, FieldError> {
+        let claims = verify_action_token(&token, TokenPurpose::UnlockAccount)
+            .map_err(TokenError::into_field_error)?;
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::email.eq(&claims.email))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .map_err(|_e| TokenError::Invalid.into_field_error())?;
+        if claims.fp.as_deref() != Some(password_fingerprint(&user.password).as_str()) {
+            return Err(TokenError::Invalid.into_field_error());
+        }
+        TokenRepository::consume_action_token(connection, &claims)?;
+        Self::clear_lock(connection, user.id)?;
+        Ok(SuccessMessage {
+            message: "Account unlocked".to_string(),
+            success: true,
+        })
+    }
+
+    pub async fn unlock_user(&self, user_id: i32) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Self::clear_lock(connection, user_id)?;
+        Ok(SuccessMessage {
+            message: "Account unlocked".to_string(),
+            success: true,
+        })
+    }
+
+    fn record(
+        connection: &mut PgConnection,
+        email: &str,
+        ip: &Option<String>,
+        succeeded: bool,
+    ) -> Result<(), FieldError> {
+        let now = Utc::now().naive_utc();
+        let sql =
+            "INSERT INTO login_attempts (email, ip, succeeded, created_at) VALUES ($1, $2, $3, $4)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(email)
+            .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(ip)
+            .bind::<diesel::sql_types::Bool, _>(succeeded)
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        // attempts only matter for a short while, keep a day for inspection
+        diesel::sql_query("DELETE FROM login_attempts WHERE created_at < $1")
+            .bind::<diesel::sql_types::Timestamp, _>(now - Duration::days(1))
+            .execute(connection)?;
+        Ok(())
+    }
+
+    fn clear_lock(connection: &mut PgConnection, user_id: i32) -> Result<(), FieldError> {
+        let sql = "UPDATE users SET failed_login_count = 0, locked_until = NULL WHERE id = $1";
+        let updated = diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        if updated == 0 {
+            return Err(FieldError::new(
+                "User not found",
+                graphql_value!("internal_error".to_string()),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use crate::models::mfa::{MfaEnrollment, MfaRecoveryCodes, UserMfa};
+use crate::models::users::User;
+use crate::repositories::login_attempt::{LoginAttemptRepository, LoginPolicy};
+use crate::repositories::token::TokenRepository;
+use crate::repositories::user::{LoginResponse, SuccessMessage};
+use crate::schema::{consumed_tokens, mfa_challenge_failures, user_mfa, users};
+use crate::totp;
+use crate::utils::{
+    hash_token, password_fingerprint, verify_action_token, verify_password, TokenError,
+    TokenPurpose,
+};
+use chrono::{NaiveDateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use rand::RngCore;
+use std::env;
+use std::sync::Arc;
+use tera::Tera;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// wrong codes before a challenge stops working and the user has to sign in
+// with their password again
+const MAX_CHALLENGE_FAILURES: i32 = 5;
+
+pub struct MfaRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl MfaRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> MfaRepository {
+        MfaRepository { pool }
+    }
+
+    pub fn is_enabled(connection: &mut PgConnection, user_id: i32) -> QueryResult<bool> {
+        user_mfa::table
+            .filter(user_mfa::user_id.eq(user_id))
+            .filter(user_mfa::enabled.eq(true))
+            .count()
+            .get_result::<i64>(connection)
+            .map(|count| count > 0)
+    }
+
+    // start enrollment, mfa stays disabled until confirmed with a first code
+    pub async fn enroll(&self, user_id: i32) -> Result<MfaEnrollment, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        if Self::is_enabled(connection, user_id)? {
+            return Err(FieldError::new(
+                "Two-factor authentication is already enabled",
+                graphql_value!("mfa_already_enabled".to_string()),
+            ));
+        }
+        let user = Self::user(connection, user_id)?;
+
+        let secret = totp::generate_secret();
+        let sql = "INSERT INTO user_mfa (user_id, secret) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, enabled = FALSE, last_used_step = NULL, confirmed_at = NULL";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(&secret)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        dotenv().ok();
+        let issuer = env::var("MFA_ISSUER").unwrap_or_else(|_| "drgz".to_string());
+        Ok(MfaEnrollment {
+            otpauth_uri: totp::otpauth_uri(&issuer, &user.email, &secret),
+            secret,
+        })
+    }
+
+    pub async fn confirm(
+        &self,
+        user_id: i32,
+        code: String,
+    ) -> Result<MfaRecoveryCodes, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let mfa = user_mfa::table
+            .filter(user_mfa::user_id.eq(user_id))
+            .first::<UserMfa>(connection)
+            .optional()?
+            .ok_or_else(|| {
+                FieldError::new(
+                    "Two-factor authentication enrollment not started",
+                    graphql_value!("mfa_not_enrolled".to_string()),
+                )
+            })?;
+        if mfa.enabled {
+            return Err(FieldError::new(
+                "Two-factor authentication is already enabled",
+                graphql_value!("mfa_already_enabled".to_string()),
+            ));
+        }
+        let step = totp::verify(&mfa.secret, &code, None).ok_or_else(invalid_code)?;
+
+        let codes = connection
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let sql = "UPDATE user_mfa SET enabled = TRUE, last_used_step = $1, confirmed_at = $2 WHERE user_id = $3";
+                diesel::sql_query(sql)
+                    .bind::<diesel::sql_types::BigInt, _>(step)
+                    .bind::<diesel::sql_types::Timestamp, _>(Utc::now().naive_utc())
+                    .bind::<diesel::sql_types::Integer, _>(user_id)
+                    .execute(conn)?;
+                Self::replace_recovery_codes(conn, user_id)
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(MfaRecoveryCodes { codes })
+    }
+
+    pub async fn disable(
+        &self,
+        user_id: i32,
+        password: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Self::check_password(connection, user_id, &password)?;
+
+        connection
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                diesel::sql_query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+                    .bind::<diesel::sql_types::Integer, _>(user_id)
+                    .execute(conn)?;
+                diesel::sql_query("DELETE FROM user_mfa WHERE user_id = $1")
+                    .bind::<diesel::sql_types::Integer, _>(user_id)
+                    .execute(conn)
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(SuccessMessage {
+            message: "Two-factor authentication disabled".to_string(),
+            success: true,
+        })
+    }
+
+    pub async fn regenerate_recovery_codes(
+        &self,
+        user_id: i32,
+        password: String,
+    ) -> Result<MfaRecoveryCodes, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Self::check_password(connection, user_id, &password)?;
+        if !Self::is_enabled(connection, user_id)? {
+            return Err(FieldError::new(
+                "Two-factor authentication is not enabled",
+                graphql_value!("mfa_not_enabled".to_string()),
+            ));
+        }
+
+        let codes = connection
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                Self::replace_recovery_codes(conn, user_id)
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(MfaRecoveryCodes { codes })
+    }
+
+    // second step of login, accepts a TOTP code or an unused recovery code
+    // wrong codes count as failed logins of the account and burn the
+    // challenge after MAX_CHALLENGE_FAILURES of them
+    pub async fn verify_login(
+        &self,
+        challenge_token: String,
+        code: String,
+        ip: Option<String>,
+        tera: Arc<Tera>,
+    ) -> Result<LoginResponse, FieldError> {
+        let claims = verify_action_token(&challenge_token, TokenPurpose::MfaChallenge)
+            .map_err(TokenError::into_field_error)?;
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::email.eq(&claims.email))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .map_err(|_e| TokenError::Invalid.into_field_error())?;
+        if claims.fp.as_deref() != Some(password_fingerprint(&user.password).as_str()) {
+            return Err(TokenError::Invalid.into_field_error());
+        }
+        let consumed = consumed_tokens::table
+            .find(&claims.jti)
+            .count()
+            .get_result::<i64>(connection)?;
+        if consumed > 0 {
+            return Err(TokenError::AlreadyUsed.into_field_error());
+        }
+        let policy = LoginPolicy::from_env();
+        LoginAttemptRepository::check_ip(connection, &policy, &ip)?;
+        LoginAttemptRepository::check_account(connection, &policy, &user)?;
+
+        let mfa = user_mfa::table
+            .filter(user_mfa::user_id.eq(user.id))
+            .filter(user_mfa::enabled.eq(true))
+            .first::<UserMfa>(connection)
+            .optional()?
+            .ok_or_else(|| TokenError::Invalid.into_field_error())?;
+
+        if let Some(step) = totp::verify(&mfa.secret, &code, mfa.last_used_step) {
+            diesel::sql_query("UPDATE user_mfa SET last_used_step = $1 WHERE user_id = $2")
+                .bind::<diesel::sql_types::BigInt, _>(step)
+                .bind::<diesel::sql_types::Integer, _>(user.id)
+                .execute(connection)?;
+        } else {
+            let sql = "UPDATE mfa_recovery_codes SET used_at = $1 WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL";
+            let used = diesel::sql_query(sql)
+                .bind::<diesel::sql_types::Timestamp, _>(Utc::now().naive_utc())
+                .bind::<diesel::sql_types::Integer, _>(user.id)
+                .bind::<diesel::sql_types::Text, _>(hash_recovery_code(&code))
+                .execute(connection)?;
+            if used == 0 {
+                let locked = LoginAttemptRepository::record_failure(
+                    connection,
+                    &policy,
+                    &user.email,
+                    &ip,
+                    Some(&user),
+                )?;
+                let expires_at = NaiveDateTime::from_timestamp_opt(claims.exp as i64, 0)
+                    .unwrap_or_else(|| Utc::now().naive_utc());
+                if Self::record_challenge_failure(connection, &claims.jti, expires_at)?
+                    >= MAX_CHALLENGE_FAILURES
+                {
+                    TokenRepository::consume_action_token(connection, &claims)?;
+                }
+                if locked {
+                    LoginAttemptRepository::send_unlock_email(&user, tera).await;
+                }
+                return Err(invalid_code());
+            }
+        }
+
+        LoginAttemptRepository::record_mfa_success(connection, &user, &ip)?;
+        TokenRepository::consume_action_token(connection, &claims)?;
+        TokenRepository::issue(connection, user)
+    }
+
+    // count a wrong code against the challenge, returns its failures so far
+    fn record_challenge_failure(
+        connection: &mut PgConnection,
+        jti: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<i32, FieldError> {
+        let now = Utc::now().naive_utc();
+        diesel::sql_query(
+            "INSERT INTO mfa_challenge_failures (jti, attempts, expires_at) VALUES ($1, 1, $2) \
+            ON CONFLICT (jti) DO UPDATE SET attempts = mfa_challenge_failures.attempts + 1",
+        )
+        .bind::<diesel::sql_types::Text, _>(jti)
+        .bind::<diesel::sql_types::Timestamp, _>(expires_at)
+        .execute(connection)?;
+        diesel::sql_query("DELETE FROM mfa_challenge_failures WHERE expires_at < $1")
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .execute(connection)?;
+        Ok(mfa_challenge_failures::table
+            .find(jti)
+            .select(mfa_challenge_failures::attempts)
+            .first::<i32>(connection)?)
+    }
+
+    fn replace_recovery_codes(
+        connection: &mut PgConnection,
+        user_id: i32,
+    ) -> Result<Vec<String>, diesel::result::Error> {
+        diesel::sql_query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .execute(connection)?;
+
+        let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+        for _ in 0..RECOVERY_CODE_COUNT {
+            let code = generate_recovery_code();
+            diesel::sql_query(
+                "INSERT INTO mfa_recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+            )
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(hash_recovery_code(&code))
+            .execute(connection)?;
+            codes.push(code);
+        }
+        Ok(codes)
+    }
+
+    fn user(connection: &mut PgConnection, user_id: i32) -> Result<User, FieldError> {
+        users::table
+            .filter(users::id.eq(user_id))
+            .first::<User>(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "User not found",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })
+    }
+
+    fn check_password(
+        connection: &mut PgConnection,
+        user_id: i32,
+        password: &str,
+    ) -> Result<User, FieldError> {
+        let user = Self::user(connection, user_id)?;
+        if !verify_password(password, &user.password) {
+            return Err(FieldError::new(
+                "invalid credentials",
+                graphql_value!("invalid_credentials".to_string()),
+            ));
+        }
+        Ok(user)
+    }
+}
+
+fn invalid_code() -> FieldError {
+    FieldError::new(
+        "Invalid verification code",
+        graphql_value!("invalid_mfa_code".to_string()),
+    )
+}
+
+// xxxx-xxxx-xxxx-xxxx, 80 random bits
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = BASE32_NOPAD.encode(&bytes).to_lowercase();
+    code.as_bytes()
+        .chunks(4)
+        .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
@@ -1 +1,14 @@
+pub mod api_key;
+pub mod audit;
+pub mod avatar;
+pub mod data_export;
+pub mod deletion;
+pub mod identity;
+pub mod login_attempt;
+pub mod mfa;
+pub mod organization;
+pub mod phone;
+pub mod privacy;
+pub mod role;
+pub mod token;
 pub mod user;
@@ -0,0 +1,473 @@
+use crate::handlers::graphql::forbidden;
+use crate::models::organizations::{
+    org_roles, Invitation, Member, Membership, Organization, OrganizationMembership,
+};
+use crate::models::users::{validate_email_format, User};
+use crate::repositories::user::SuccessMessage;
+use crate::schema::{invitations, memberships, organizations, users};
+use crate::utils::{generate_refresh_token, hash_token};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use std::env;
+use std::sync::Arc;
+use tera::Tera;
+
+pub struct OrganizationRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+// lifetime of invitations in seconds, defaults to 7 days
+fn invitation_ttl() -> Duration {
+    dotenv().ok();
+    let seconds = env::var("INVITATION_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse::<i64>().ok())
+        .unwrap_or(60 * 60 * 24 * 7);
+    Duration::seconds(seconds)
+}
+
+fn invalid_role() -> FieldError {
+    FieldError::new(
+        "Role must be owner, admin or member",
+        graphql_value!("invalid_role".to_string()),
+    )
+}
+
+fn invalid_invitation() -> FieldError {
+    FieldError::new(
+        "Invitation is invalid or has expired",
+        graphql_value!("invalid_invitation".to_string()),
+    )
+}
+
+fn last_owner() -> FieldError {
+    FieldError::new(
+        "An organization needs at least one owner",
+        graphql_value!("last_owner".to_string()),
+    )
+}
+
+impl OrganizationRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> OrganizationRepository {
+        OrganizationRepository { pool }
+    }
+
+    pub async fn membership(
+        &self,
+        organization_id: i32,
+        user_id: i32,
+    ) -> Result<Option<Membership>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Ok(memberships::table
+            .filter(memberships::organization_id.eq(organization_id))
+            .filter(memberships::user_id.eq(user_id))
+            .first::<Membership>(connection)
+            .optional()?)
+    }
+
+    // the creator becomes the first owner
+    pub async fn create(
+        &self,
+        user_id: i32,
+        name: String,
+    ) -> Result<OrganizationMembership, FieldError> {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err(FieldError::new(
+                "Name is required",
+                graphql_value!("invalid_name".to_string()),
+            ));
+        }
+        let connection = &mut *self.pool.get()?;
+        let organization = connection.transaction::<_, diesel::result::Error, _>(|conn| {
+            let organization = diesel::insert_into(organizations::table)
+                .values((
+                    organizations::name.eq(&name),
+                    organizations::created_by.eq(user_id),
+                ))
+                .get_result::<Organization>(conn)?;
+            diesel::insert_into(memberships::table)
+                .values((
+                    memberships::organization_id.eq(organization.id),
+                    memberships::user_id.eq(user_id),
+                    memberships::role.eq(org_roles::OWNER),
+                ))
+                .execute(conn)?;
+            Ok(organization)
+        })?;
+        Ok(OrganizationMembership {
+            organization,
+            role: org_roles::OWNER.to_string(),
+        })
+    }
+
+    pub async fn my_organizations(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<OrganizationMembership>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let rows = memberships::table
+            .inner_join(organizations::table)
+            .filter(memberships::user_id.eq(user_id))
+            .order(organizations::name)
+            .select((organizations::all_columns, memberships::role))
+            .load::<(Organization, String)>(connection)?;
+        Ok(rows
+            .into_iter()
+            .map(|(organization, role)| OrganizationMembership { organization, role })
+            .collect())
+    }
+
+    pub async fn get(&self, organization_id: i32) -> Result<Organization, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Ok(organizations::table
+            .filter(organizations::id.eq(organization_id))
+            .first::<Organization>(connection)?)
+    }
+
+    // everyone in the organization, as seen by `viewer`
+    pub async fn members(&self, viewer: &Membership) -> Result<Vec<Member>, FieldError> {
+        let organization_id = viewer.organization_id;
+        let is_admin = org_roles::at_least(&viewer.role, org_roles::ADMIN);
+        let connection = &mut *self.pool.get()?;
+        let rows = memberships::table
+            .inner_join(users::table)
+            .filter(memberships::organization_id.eq(organization_id))
+            .order(users::username)
+            .select((
+                users::id,
+                users::username,
+                users::email,
+                memberships::role,
+                memberships::created_at,
+            ))
+            .load::<(i32, String, String, String, NaiveDateTime)>(connection)?;
+        Ok(rows
+            .into_iter()
+            .map(|(user_id, username, email, role, joined_at)| Member {
+                user_id,
+                username,
+                email: (is_admin || user_id == viewer.user_id).then_some(email),
+                role,
+                joined_at,
+            })
+            .collect())
+    }
+
+    // invite an email address, a pending invitation for the same address is
+    // replaced. Nobody can hand out a role above their own
+    pub async fn invite(
+        &self,
+        inviter: &Membership,
+        email: String,
+        role: String,
+        tera: Arc<Tera>,
+    ) -> Result<Invitation, FieldError> {
+        let email = email.trim().to_lowercase();
+        validate_email_format(&email)?;
+        if org_roles::rank(&role).is_none() {
+            return Err(invalid_role());
+        }
+        if !org_roles::at_least(&inviter.role, &role) {
+            return Err(forbidden());
+        }
+
+        let connection = &mut *self.pool.get()?;
+        let already_member = memberships::table
+            .inner_join(users::table)
+            .filter(memberships::organization_id.eq(inviter.organization_id))
+            .filter(users::email.eq(&email))
+            .count()
+            .get_result::<i64>(connection)?
+            > 0;
+        if already_member {
+            return Err(FieldError::new(
+                "User is already a member",
+                graphql_value!("already_member".to_string()),
+            ));
+        }
+
+        let now = Utc::now().naive_utc();
+        let token = generate_refresh_token();
+        let invitation = connection.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::update(
+                invitations::table
+                    .filter(invitations::organization_id.eq(inviter.organization_id))
+                    .filter(invitations::email.eq(&email))
+                    .filter(invitations::accepted_at.is_null())
+                    .filter(invitations::declined_at.is_null())
+                    .filter(invitations::revoked_at.is_null()),
+            )
+            .set(invitations::revoked_at.eq(now))
+            .execute(conn)?;
+            diesel::insert_into(invitations::table)
+                .values((
+                    invitations::organization_id.eq(inviter.organization_id),
+                    invitations::email.eq(&email),
+                    invitations::role.eq(&role),
+                    invitations::invited_by.eq(inviter.user_id),
+                    invitations::token_hash.eq(hash_token(&token)),
+                    invitations::expires_at.eq(now + invitation_ttl()),
+                ))
+                .get_result::<Invitation>(conn)
+        })?;
+
+        let organization = organizations::table
+            .filter(organizations::id.eq(inviter.organization_id))
+            .first::<Organization>(connection)?;
+        let inviter_name = users::table
+            .filter(users::id.eq(inviter.user_id))
+            .select(users::username)
+            .first::<String>(connection)?;
+
+        let mut mail_context = tera::Context::new();
+        mail_context.insert("email", &email);
+        mail_context.insert("inviter", &inviter_name);
+        mail_context.insert("organization", &organization.name);
+        mail_context.insert("role", &role);
+        mail_context.insert("days", &invitation_ttl().num_days());
+        mail_context.insert("domain", "http://localhost:3000");
+        mail_context.insert("logo", "https://www.elegal.ascendth.com/_next/image?url=https%3A%2F%2Felegal-ascend.s3.amazonaws.com%2Fpublic%2Flogo.png&w=256&q=75");
+        mail_context.insert("company", "drgz");
+        mail_context.insert(
+            "link",
+            &format!("http://localhost:3000/invitations/{}", token),
+        );
+        crate::mailer::send_html_email(
+            &email,
+            "info@ascendth.com",
+            &format!("Join {} on drgz", organization.name),
+            "emails/organization-invitation.html",
+            &mail_context,
+            tera,
+        )
+        .await;
+
+        Ok(invitation)
+    }
+
+    pub async fn invitations(&self, organization_id: i32) -> Result<Vec<Invitation>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let now = Utc::now().naive_utc();
+        let invitations = invitations::table
+            .filter(invitations::organization_id.eq(organization_id))
+            .order(invitations::created_at.desc())
+            .load::<Invitation>(connection)?;
+        Ok(invitations
+            .into_iter()
+            .filter(|invitation| invitation.is_pending(now))
+            .collect())
+    }
+
+    // pending invitations sent to the user's email address
+    pub async fn my_invitations(&self, user_id: i32) -> Result<Vec<Invitation>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let now = Utc::now().naive_utc();
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .first::<User>(connection)?;
+        let invitations = invitations::table
+            .filter(invitations::email.eq(user.email.to_lowercase()))
+            .order(invitations::created_at.desc())
+            .load::<Invitation>(connection)?;
+        Ok(invitations
+            .into_iter()
+            .filter(|invitation| invitation.is_pending(now))
+            .collect())
+    }
+
+    // the pending invitation behind a token, it must have been sent to the
+    // email address of the user answering it
+    fn pending_invitation(
+        connection: &mut PgConnection,
+        user_id: i32,
+        token: &str,
+    ) -> Result<Invitation, FieldError> {
+        let invitation = invitations::table
+            .filter(invitations::token_hash.eq(hash_token(token)))
+            .first::<Invitation>(connection)
+            .optional()?
+            .filter(|invitation| invitation.is_pending(Utc::now().naive_utc()))
+            .ok_or_else(invalid_invitation)?;
+        let email = users::table
+            .filter(users::id.eq(user_id))
+            .select(users::email)
+            .first::<String>(connection)?;
+        if email.to_lowercase() != invitation.email {
+            return Err(FieldError::new(
+                "Invitation was sent to another email address",
+                graphql_value!("invitation_email_mismatch".to_string()),
+            ));
+        }
+        Ok(invitation)
+    }
+
+    pub async fn accept(
+        &self,
+        user_id: i32,
+        token: String,
+    ) -> Result<OrganizationMembership, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let invitation = Self::pending_invitation(connection, user_id, &token)?;
+        let now = Utc::now().naive_utc();
+        connection.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::update(invitations::table.filter(invitations::id.eq(invitation.id)))
+                .set(invitations::accepted_at.eq(now))
+                .execute(conn)?;
+            diesel::insert_into(memberships::table)
+                .values((
+                    memberships::organization_id.eq(invitation.organization_id),
+                    memberships::user_id.eq(user_id),
+                    memberships::role.eq(&invitation.role),
+                ))
+                .on_conflict((memberships::organization_id, memberships::user_id))
+                .do_nothing()
+                .execute(conn)?;
+            Ok(())
+        })?;
+        let organization = organizations::table
+            .filter(organizations::id.eq(invitation.organization_id))
+            .first::<Organization>(connection)?;
+        let role = memberships::table
+            .filter(memberships::organization_id.eq(organization.id))
+            .filter(memberships::user_id.eq(user_id))
+            .select(memberships::role)
+            .first::<String>(connection)?;
+        Ok(OrganizationMembership { organization, role })
+    }
+
+    pub async fn decline(&self, user_id: i32, token: String) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let invitation = Self::pending_invitation(connection, user_id, &token)?;
+        diesel::update(invitations::table.filter(invitations::id.eq(invitation.id)))
+            .set(invitations::declined_at.eq(Utc::now().naive_utc()))
+            .execute(connection)?;
+        Ok(SuccessMessage {
+            message: "Invitation declined".to_string(),
+            success: true,
+        })
+    }
+
+    pub async fn revoke_invitation(
+        &self,
+        organization_id: i32,
+        id: i32,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let updated = diesel::update(
+            invitations::table
+                .filter(invitations::id.eq(id))
+                .filter(invitations::organization_id.eq(organization_id))
+                .filter(invitations::accepted_at.is_null())
+                .filter(invitations::declined_at.is_null())
+                .filter(invitations::revoked_at.is_null()),
+        )
+        .set(invitations::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(connection)?;
+        if updated == 0 {
+            return Err(FieldError::new(
+                "Invitation not found",
+                graphql_value!("invitation_not_found".to_string()),
+            ));
+        }
+        Ok(SuccessMessage {
+            message: "Invitation revoked".to_string(),
+            success: true,
+        })
+    }
+
+    // only owners change roles, and an organization always keeps one owner
+    pub async fn update_member_role(
+        &self,
+        actor: &Membership,
+        user_id: i32,
+        role: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        if org_roles::rank(&role).is_none() {
+            return Err(invalid_role());
+        }
+        if actor.role != org_roles::OWNER {
+            return Err(forbidden());
+        }
+        let connection = &mut *self.pool.get()?;
+        connection.transaction::<_, FieldError, _>(|connection| {
+            let owners = Self::lock_owners(connection, actor.organization_id)?;
+            let member = Self::find_member(connection, actor.organization_id, user_id)?;
+            if member.role == org_roles::OWNER && role != org_roles::OWNER && owners <= 1 {
+                return Err(last_owner());
+            }
+            diesel::update(memberships::table.filter(memberships::id.eq(member.id)))
+                .set(memberships::role.eq(&role))
+                .execute(connection)?;
+            Ok(())
+        })?;
+        Ok(SuccessMessage {
+            message: "Role updated".to_string(),
+            success: true,
+        })
+    }
+
+    // members can always leave, removing someone else takes a role above theirs
+    pub async fn remove_member(
+        &self,
+        actor: &Membership,
+        user_id: i32,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        connection.transaction::<_, FieldError, _>(|connection| {
+            let owners = Self::lock_owners(connection, actor.organization_id)?;
+            let member = Self::find_member(connection, actor.organization_id, user_id)?;
+            let allowed = member.user_id == actor.user_id
+                || actor.role == org_roles::OWNER
+                || (org_roles::at_least(&actor.role, org_roles::ADMIN)
+                    && org_roles::rank(&actor.role) > org_roles::rank(&member.role));
+            if !allowed {
+                return Err(forbidden());
+            }
+            if member.role == org_roles::OWNER && owners <= 1 {
+                return Err(last_owner());
+            }
+            diesel::delete(memberships::table.filter(memberships::id.eq(member.id)))
+                .execute(connection)?;
+            Ok(())
+        })?;
+        Ok(SuccessMessage {
+            message: "Member removed".to_string(),
+            success: true,
+        })
+    }
+
+    fn find_member(
+        connection: &mut PgConnection,
+        organization_id: i32,
+        user_id: i32,
+    ) -> Result<Membership, FieldError> {
+        memberships::table
+            .filter(memberships::organization_id.eq(organization_id))
+            .filter(memberships::user_id.eq(user_id))
+            .first::<Membership>(connection)
+            .optional()?
+            .ok_or_else(|| {
+                FieldError::new(
+                    "Member not found",
+                    graphql_value!("member_not_found".to_string()),
+                )
+            })
+    }
+
+    // lock the owner rows for the rest of the transaction and count them, so
+    // two owners demoting or removing each other cannot both pass the check
+    fn lock_owners(connection: &mut PgConnection, organization_id: i32) -> QueryResult<usize> {
+        memberships::table
+            .filter(memberships::organization_id.eq(organization_id))
+            .filter(memberships::role.eq(org_roles::OWNER))
+            .select(memberships::id)
+            .for_update()
+            .load::<i32>(connection)
+            .map(|owners| owners.len())
+    }
+}
@@ -0,0 +1,580 @@
+use crate::models::sms_codes::SmsCode;
+use crate::models::users::User;
+use crate::repositories::login_attempt::{LoginAttemptRepository, LoginPolicy};
+use crate::repositories::user::{LoginResult, SuccessMessage, UserRepository};
+use crate::schema::{sms_codes, sms_sends, users};
+use crate::utils::hash_token;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use dotenvy::dotenv;
+use drgz::sms::{normalize_phone, send_sms};
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use rand::Rng;
+use std::env;
+use std::sync::Arc;
+use tera::Tera;
+
+const VERIFY_PHONE: &str = "verify_phone";
+const PHONE_LOGIN: &str = "login";
+
+// wrong guesses before a code stops working
+const MAX_CODE_ATTEMPTS: i32 = 5;
+
+// SMS_CODE_TTL, in seconds
+fn sms_code_ttl() -> Duration {
+    dotenv().ok();
+    let seconds = env::var("SMS_CODE_TTL")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(60 * 10);
+    Duration::seconds(seconds)
+}
+
+// SMS_COOLDOWN seconds between texts to one number and at most
+// SMS_DAILY_LIMIT of them a day
+fn sms_limits() -> (Duration, i64) {
+    dotenv().ok();
+    let var = |name: &str, default: i64| {
+        env::var(name)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(default)
+    };
+    (
+        Duration::seconds(var("SMS_COOLDOWN", 60)),
+        var("SMS_DAILY_LIMIT", 5),
+    )
+}
+
+// PHONE_LOGIN=true lets users with a verified number sign in with a texted code
+fn phone_login_enabled() -> bool {
+    dotenv().ok();
+    env::var("PHONE_LOGIN").is_ok_and(|enabled| enabled == "true")
+}
+
+// PHONE_DEFAULT_COUNTRY_CODE is used for numbers entered without one
+fn normalize(phone: &str) -> Result<String, FieldError> {
+    dotenv().ok();
+    let country_code = env::var("PHONE_DEFAULT_COUNTRY_CODE").ok();
+    normalize_phone(phone, country_code.as_deref()).ok_or_else(|| {
+        FieldError::new(
+            "Phone number is invalid",
+            graphql_value!("phone_invalid".to_string()),
+        )
+    })
+}
+
+fn invalid_code() -> FieldError {
+    FieldError::new(
+        "Invalid or expired code",
+        graphql_value!("invalid_code".to_string()),
+    )
+}
+
+pub struct PhoneRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl PhoneRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> PhoneRepository {
+        PhoneRepository { pool }
+    }
+
+    // refuse a number another account already verified
+    fn check_available(
+        connection: &mut PgConnection,
+        user_id: i32,
+        phone: &str,
+    ) -> Result<(), FieldError> {
+        let taken = users::table
+            .filter(users::phone.eq(phone))
+            .filter(users::phone_verified.eq(true))
+            .filter(users::id.ne(user_id))
+            .count()
+            .get_result::<i64>(connection)?;
+        if taken > 0 {
+            return Err(FieldError::new(
+                "Phone number is already in use",
+                graphql_value!("phone_taken".to_string()),
+            ));
+        }
+        Ok(())
+    }
+
+    // refuse to text a number again too soon or too often. Counted by
+    // number so the answer is the same whether or not an account uses it
+    fn check_sms_limit(connection: &mut PgConnection, phone: &str) -> Result<(), FieldError> {
+        let (cooldown, daily_limit) = sms_limits();
+        let now = Utc::now().naive_utc();
+        let sent = sms_sends::table
+            .filter(sms_sends::phone.eq(phone))
+            .filter(sms_sends::created_at.gt(now - Duration::days(1)))
+            .select(sms_sends::created_at)
+            .order(sms_sends::created_at.desc())
+            .load::<NaiveDateTime>(connection)?;
+        if let Some(last) = sent.first() {
+            let retry_at = *last + cooldown;
+            if retry_at > now {
+                return Err(FieldError::new(
+                    format!(
+                        "Please wait {} seconds before asking for another code",
+                        (retry_at - now).num_seconds() + 1
+                    ),
+                    graphql_value!("sms_cooldown".to_string()),
+                ));
+            }
+        }
+        if sent.len() as i64 >= daily_limit {
+            return Err(FieldError::new(
+                "Too many codes sent to this number today, try again tomorrow",
+                graphql_value!("sms_limit_reached".to_string()),
+            ));
+        }
+        Ok(())
+    }
+
+    // count a text against its number, rows older than a day no longer count
+    fn record_sms(connection: &mut PgConnection, phone: &str) -> Result<(), FieldError> {
+        let now = Utc::now().naive_utc();
+        connection
+            .transaction::<_, diesel::result::Error, _>(|connection| {
+                diesel::sql_query("DELETE FROM sms_sends WHERE created_at < $1")
+                    .bind::<diesel::sql_types::Timestamp, _>(now - Duration::days(1))
+                    .execute(connection)?;
+                diesel::sql_query("INSERT INTO sms_sends (phone, created_at) VALUES ($1, $2)")
+                    .bind::<diesel::sql_types::Text, _>(phone)
+                    .bind::<diesel::sql_types::Timestamp, _>(now)
+                    .execute(connection)?;
+                Ok(())
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })
+    }
+
+    // store a fresh code for the user, replacing the unused ones for the
+    // same purpose, and return it in clear to be texted
+    fn issue_code(
+        connection: &mut PgConnection,
+        user_id: i32,
+        phone: &str,
+        purpose: &str,
+    ) -> Result<String, FieldError> {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let expires_at = Utc::now().naive_utc() + sms_code_ttl();
+        connection
+            .transaction::<_, diesel::result::Error, _>(|connection| {
+                diesel::sql_query(
+                    "DELETE FROM sms_codes WHERE user_id = $1 AND purpose = $2 AND consumed_at IS NULL",
+                )
+                .bind::<diesel::sql_types::Integer, _>(user_id)
+                .bind::<diesel::sql_types::Text, _>(purpose)
+                .execute(connection)?;
+                diesel::sql_query(
+                    "INSERT INTO sms_codes (user_id, phone, purpose, code_hash, expires_at) VALUES ($1, $2, $3, $4, $5)",
+                )
+                .bind::<diesel::sql_types::Integer, _>(user_id)
+                .bind::<diesel::sql_types::Text, _>(phone)
+                .bind::<diesel::sql_types::Text, _>(purpose)
+                .bind::<diesel::sql_types::Text, _>(hash_token(&format!("{}:{}", phone, code)))
+                .bind::<diesel::sql_types::Timestamp, _>(expires_at)
+                .execute(connection)?;
+                Ok(())
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(code)
+    }
+
+    // consume the latest code sent to the user for `purpose`, returning it.
+    // Every wrong guess counts against the code
+    fn check_code(
+        connection: &mut PgConnection,
+        user_id: i32,
+        purpose: &str,
+        code: &str,
+    ) -> Result<SmsCode, FieldError> {
+        let now = Utc::now().naive_utc();
+        let sms_code = sms_codes::table
+            .filter(sms_codes::user_id.eq(user_id))
+            .filter(sms_codes::purpose.eq(purpose))
+            .filter(sms_codes::consumed_at.is_null())
+            .order(sms_codes::created_at.desc())
+            .first::<SmsCode>(connection)
+            .optional()?
+            .ok_or_else(invalid_code)?;
+        if sms_code.expires_at <= now || sms_code.attempts >= MAX_CODE_ATTEMPTS {
+            return Err(invalid_code());
+        }
+
+        if hash_token(&format!("{}:{}", sms_code.phone, code.trim())) != sms_code.code_hash {
+            diesel::sql_query("UPDATE sms_codes SET attempts = attempts + 1 WHERE id = $1")
+                .bind::<diesel::sql_types::Integer, _>(sms_code.id)
+                .execute(connection)?;
+            return Err(invalid_code());
+        }
+
+        // only one request gets to use the code
+        let consumed = diesel::sql_query(
+            "UPDATE sms_codes SET consumed_at = $1 WHERE id = $2 AND consumed_at IS NULL",
+        )
+        .bind::<diesel::sql_types::Timestamp, _>(now)
+        .bind::<diesel::sql_types::Integer, _>(sms_code.id)
+        .execute(connection)?;
+        if consumed == 0 {
+            return Err(invalid_code());
+        }
+        Ok(sms_code)
+    }
+
+    // text a verification code to a new number, the account keeps its
+    // current number until the code is confirmed
+    pub async fn set_phone(
+        &self,
+        user_id: i32,
+        phone: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let phone = normalize(&phone)?;
+        let connection = &mut *self.pool.get()?;
+        let user = users::table.find(user_id).first::<User>(connection)?;
+        if user.phone_verified && user.phone.as_deref() == Some(phone.as_str()) {
+            return Err(FieldError::new(
+                "This is already your phone number",
+                graphql_value!("phone_unchanged".to_string()),
+            ));
+        }
+        Self::check_available(connection, user_id, &phone)?;
+        Self::check_sms_limit(connection, &phone)?;
+        Self::record_sms(connection, &phone)?;
+
+        let code = Self::issue_code(connection, user_id, &phone, VERIFY_PHONE)?;
+        send_sms(&phone, &format!("Your drgz verification code is {}", code)).await;
+        Ok(SuccessMessage {
+            message: "Verification code sent".to_string(),
+            success: true,
+        })
+    }
+
+    // confirm the number the last code was sent to and make it the user's
+    pub async fn verify_phone(&self, user_id: i32, code: String) -> Result<User, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let sms_code = Self::check_code(connection, user_id, VERIFY_PHONE, &code)?;
+        Self::check_available(connection, user_id, &sms_code.phone)?;
+
+        diesel::sql_query(
+            "UPDATE users SET phone = $1, phone_verified = true, updated_at = NOW() WHERE id = $2",
+        )
+        .bind::<diesel::sql_types::Text, _>(&sms_code.phone)
+        .bind::<diesel::sql_types::Integer, _>(user_id)
+        .execute(connection)
+        .map_err(|_e| {
+            FieldError::new(
+                "Database error",
+                graphql_value!("internal_error".to_string()),
+            )
+        })?;
+        Ok(users::table.find(user_id).first::<User>(connection)?)
+    }
+
+    pub async fn remove_phone(&self, user_id: i32) -> Result<User, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        connection
+            .transaction::<_, diesel::result::Error, _>(|connection| {
+                diesel::sql_query(
+                    "UPDATE users SET phone = NULL, phone_verified = false, updated_at = NOW() WHERE id = $1",
+                )
+                .bind::<diesel::sql_types::Integer, _>(user_id)
+                .execute(connection)?;
+                diesel::sql_query("DELETE FROM sms_codes WHERE user_id = $1")
+                    .bind::<diesel::sql_types::Integer, _>(user_id)
+                    .execute(connection)?;
+                Ok(())
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(users::table.find(user_id).first::<User>(connection)?)
+    }
+
+    fn require_phone_login() -> Result<(), FieldError> {
+        if !phone_login_enabled() {
+            return Err(FieldError::new(
+                "Sign in by phone is disabled",
+                graphql_value!("phone_login_disabled".to_string()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn find_by_phone(connection: &mut PgConnection, phone: &str) -> QueryResult<Option<User>> {
+        users::table
+            .filter(users::phone.eq(phone))
+            .filter(users::phone_verified.eq(true))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .optional()
+    }
+
+    // text a sign in code, answering the same whether or not the number
+    // belongs to an account
+    pub async fn request_login_code(&self, phone: String) -> Result<SuccessMessage, FieldError> {
+        Self::require_phone_login()?;
+        let phone = normalize(&phone)?;
+        let connection = &mut *self.pool.get()?;
+        let message = SuccessMessage {
+            message: "Sign in code sent".to_string(),
+            success: true,
+        };
+        Self::check_sms_limit(connection, &phone)?;
+        Self::record_sms(connection, &phone)?;
+
+        let user = match Self::find_by_phone(connection, &phone)? {
+            Some(user) => user,
+            None => return Ok(message),
+        };
+        let code = Self::issue_code(connection, user.id, &phone, PHONE_LOGIN)?;
+        send_sms(&phone, &format!("Your drgz sign in code is {}", code)).await;
+        Ok(message)
+    }
+
+    // sign in with a texted code, wrong codes count as failed logins
+    pub async fn login_with_code(
+        &self,
+        phone: String,
+        code: String,
+        ip: Option<String>,
+        tera: Arc<Tera>,
+    ) -> Result<LoginResult, FieldError> {
+        Self::require_phone_login()?;
+        let phone = normalize(&phone)?;
+        let connection = &mut *self.pool.get()?;
+        let policy = LoginPolicy::from_env();
+        LoginAttemptRepository::check_ip(connection, &policy, &ip)?;
+
+        let user = match Self::find_by_phone(connection, &phone)? {
+            Some(user) => user,
+            None => {
+                LoginAttemptRepository::record_failure(connection, &policy, &phone, &ip, None)?;
+                return Err(invalid_code());
+            }
+        };
+        LoginAttemptRepository::check_account(connection, &policy, &user)?;
+
+        if let Err(e) = Self::check_code(connection, user.id, PHONE_LOGIN, &code) {
+            let locked = LoginAttemptRepository::record_failure(
+                connection,
+                &policy,
+                &user.email,
+                &ip,
+                Some(&user),
+            )?;
+            if locked {
+                LoginAttemptRepository::send_unlock_email(&user, tera).await;
+            }
+            return Err(e);
+        }
+        LoginAttemptRepository::record_success(connection, &user, &ip)?;
+        UserRepository::complete_login(connection, user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PhoneRepository;
+    use crate::db::test_pool;
+    use crate::schema::{sms_sends, users};
+    use diesel::prelude::*;
+    use drgz::sms::{set_sms_sender, MemorySender};
+    use std::sync::{Arc, OnceLock};
+
+    // the sender is global, every test shares one and reads back the
+    // messages to its own numbers
+    fn sent() -> &'static Arc<MemorySender> {
+        static SENT: OnceLock<Arc<MemorySender>> = OnceLock::new();
+        SENT.get_or_init(|| {
+            let sender = Arc::new(MemorySender::new());
+            if set_sms_sender(sender.clone()).is_err() {
+                panic!("another SMS sender is already installed");
+            }
+            sender
+        })
+    }
+
+    fn last_code_to(phone: &str) -> String {
+        let body = sent().last_message_to(phone).unwrap();
+        body.rsplit(' ').next().unwrap().to_string()
+    }
+
+    fn insert_user(repository: &PhoneRepository, username: &str) -> i32 {
+        let connection = &mut *repository.pool.get().unwrap();
+        diesel::insert_into(users::table)
+            .values((
+                users::username.eq(username),
+                users::email.eq(format!("{}@example.com", username)),
+                users::email_verified.eq(true),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(connection)
+            .unwrap()
+    }
+
+    fn error_code(e: juniper::FieldError) -> String {
+        e.extensions().as_string_value().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn verifies_the_number_with_the_texted_code() {
+        let repository = PhoneRepository::new(test_pool());
+        let user_id = insert_user(&repository, "phone-texted");
+        sent();
+
+        repository
+            .set_phone(user_id, "+1 (555) 010-0001".to_string())
+            .await
+            .ok()
+            .unwrap();
+        let code = last_code_to("+15550100001");
+        assert_eq!(code.len(), 6);
+
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        let e = repository
+            .verify_phone(user_id, wrong.to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "invalid_code");
+
+        let user = repository
+            .verify_phone(user_id, code.clone())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(user.phone.as_deref(), Some("+15550100001"));
+        assert!(user.phone_verified);
+
+        // a code works once
+        let e = repository.verify_phone(user_id, code).await.err().unwrap();
+        assert_eq!(error_code(e), "invalid_code");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn refuses_a_number_another_account_verified() {
+        let repository = PhoneRepository::new(test_pool());
+        let owner = insert_user(&repository, "phone-owner");
+        let other = insert_user(&repository, "phone-other");
+        sent();
+
+        repository
+            .set_phone(owner, "+15550100002".to_string())
+            .await
+            .ok()
+            .unwrap();
+        repository
+            .verify_phone(owner, last_code_to("+15550100002"))
+            .await
+            .ok()
+            .unwrap();
+
+        let e = repository
+            .set_phone(other, "+1 555 010 0002".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "phone_taken");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn stops_accepting_a_code_after_too_many_wrong_guesses() {
+        let repository = PhoneRepository::new(test_pool());
+        let user_id = insert_user(&repository, "phone-guesser");
+        sent();
+
+        repository
+            .set_phone(user_id, "+15550100003".to_string())
+            .await
+            .ok()
+            .unwrap();
+        let code = last_code_to("+15550100003");
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        for _ in 0..super::MAX_CODE_ATTEMPTS {
+            assert!(repository
+                .verify_phone(user_id, wrong.to_string())
+                .await
+                .is_err());
+        }
+        let e = repository.verify_phone(user_id, code).await.err().unwrap();
+        assert_eq!(error_code(e), "invalid_code");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn waits_before_texting_a_number_again() {
+        let repository = PhoneRepository::new(test_pool());
+        let user_id = insert_user(&repository, "phone-impatient");
+        let other = insert_user(&repository, "phone-impatient-other");
+        sent();
+
+        repository
+            .set_phone(user_id, "+15550100004".to_string())
+            .await
+            .ok()
+            .unwrap();
+        let e = repository
+            .set_phone(user_id, "+1 555 010 0004".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "sms_cooldown");
+
+        // the cooldown belongs to the number, not the account asking
+        let e = repository
+            .set_phone(other, "+15550100004".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "sms_cooldown");
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn stops_texting_a_number_after_the_daily_limit() {
+        let repository = PhoneRepository::new(test_pool());
+        let user_id = insert_user(&repository, "phone-daily");
+        sent();
+        {
+            let connection = &mut *repository.pool.get().unwrap();
+            let earlier = chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+            for _ in 0..5 {
+                diesel::insert_into(sms_sends::table)
+                    .values((
+                        sms_sends::phone.eq("+15550100005"),
+                        sms_sends::created_at.eq(earlier),
+                    ))
+                    .execute(connection)
+                    .unwrap();
+            }
+        }
+
+        let e = repository
+            .set_phone(user_id, "+15550100005".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_code(e), "sms_limit_reached");
+    }
+}
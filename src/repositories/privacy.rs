@@ -0,0 +1,102 @@
+use crate::models::privacy::{PrivacySettings, UpdatePrivacySettings};
+use crate::models::users::{User, UserProfile};
+use crate::schema::privacy_settings;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub struct PrivacyRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+impl PrivacyRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> PrivacyRepository {
+        PrivacyRepository { pool }
+    }
+
+    pub fn settings_for(
+        connection: &mut PgConnection,
+        user_id: i32,
+    ) -> QueryResult<PrivacySettings> {
+        let settings = privacy_settings::table
+            .filter(privacy_settings::user_id.eq(user_id))
+            .first::<PrivacySettings>(connection)
+            .optional()?;
+        Ok(settings.unwrap_or_else(|| PrivacySettings::defaults(user_id)))
+    }
+
+    pub async fn settings(&self, user_id: i32) -> Result<PrivacySettings, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        Ok(Self::settings_for(connection, user_id)?)
+    }
+
+    pub async fn update(
+        &self,
+        user_id: i32,
+        input: UpdatePrivacySettings,
+    ) -> Result<PrivacySettings, FieldError> {
+        let current = self.settings(user_id).await?;
+        let settings = PrivacySettings {
+            user_id,
+            show_name: input.show_name.unwrap_or(current.show_name),
+            show_city: input.show_city.unwrap_or(current.show_city),
+            show_state: input.show_state.unwrap_or(current.show_state),
+            show_country: input.show_country.unwrap_or(current.show_country),
+            updated_at: Utc::now().naive_utc(),
+        };
+
+        let connection = &mut *self.pool.get()?;
+        let sql = "INSERT INTO privacy_settings (user_id, show_name, show_city, show_state, show_country, updated_at) VALUES ($1, $2, $3, $4, $5, $6) \
+            ON CONFLICT (user_id) DO UPDATE SET show_name = $2, show_city = $3, show_state = $4, show_country = $5, updated_at = $6";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Bool, _>(settings.show_name)
+            .bind::<diesel::sql_types::Bool, _>(settings.show_city)
+            .bind::<diesel::sql_types::Bool, _>(settings.show_state)
+            .bind::<diesel::sql_types::Bool, _>(settings.show_country)
+            .bind::<diesel::sql_types::Timestamp, _>(settings.updated_at)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(settings)
+    }
+
+    // the profiles `viewer` may see, staff see every account in full while
+    // others only see the public side of accounts that are not deleted
+    pub async fn profiles(
+        &self,
+        users: Vec<User>,
+        viewer: i32,
+        staff: bool,
+    ) -> Result<Vec<UserProfile>, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let ids: Vec<i32> = users.iter().map(|user| user.id).collect();
+        let mut settings: HashMap<i32, PrivacySettings> = privacy_settings::table
+            .filter(privacy_settings::user_id.eq_any(&ids))
+            .load::<PrivacySettings>(connection)?
+            .into_iter()
+            .map(|settings| (settings.user_id, settings))
+            .collect();
+
+        let profiles = users
+            .into_iter()
+            .filter(|user| staff || !user.deleted)
+            .map(|user| {
+                let user_settings = settings
+                    .remove(&user.id)
+                    .unwrap_or_else(|| PrivacySettings::defaults(user.id));
+                let full_access = staff || user.id == viewer;
+                UserProfile::new(user, &user_settings, full_access)
+            })
+            .collect();
+        Ok(profiles)
+    }
+}
@@ -0,0 +1,127 @@
+use crate::models::roles::{Role, STAFF_ROLE, SUPERUSER_ROLE};
+use crate::models::users::User;
+use crate::repositories::user::SuccessMessage;
+use crate::schema::{permissions, role_permissions, roles, user_roles};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use juniper::{graphql_value, FieldError};
+use r2d2::Pool;
+use std::sync::Arc;
+
+pub struct RoleRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+// roles and permissions embedded in the access token of a user
+pub struct Grants {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+impl RoleRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> RoleRepository {
+        RoleRepository { pool }
+    }
+
+    pub fn grants(connection: &mut PgConnection, user: &User) -> QueryResult<Grants> {
+        let mut roles = user_roles::table
+            .inner_join(roles::table)
+            .filter(user_roles::user_id.eq(user.id))
+            .select(roles::name)
+            .order(roles::name)
+            .load::<String>(connection)?;
+        if user.is_staff {
+            roles.push(STAFF_ROLE.to_string());
+        }
+        if user.is_superuser {
+            roles.push(SUPERUSER_ROLE.to_string());
+        }
+        roles.sort();
+        roles.dedup();
+
+        let permissions = role_permissions::table
+            .inner_join(roles::table)
+            .inner_join(permissions::table)
+            .filter(roles::name.eq_any(&roles))
+            .select(permissions::name)
+            .distinct()
+            .order(permissions::name)
+            .load::<String>(connection)?;
+
+        Ok(Grants { roles, permissions })
+    }
+
+    pub async fn all_roles(&self) -> Result<Vec<Role>, FieldError> {
+        let conn = &mut *self.pool.get()?;
+        let roles = roles::table.order(roles::name).load::<Role>(conn)?;
+        Ok(roles)
+    }
+
+    pub async fn user_roles(&self, user_id: i32) -> Result<Vec<Role>, FieldError> {
+        let conn = &mut *self.pool.get()?;
+        let roles = user_roles::table
+            .inner_join(roles::table)
+            .filter(user_roles::user_id.eq(user_id))
+            .select(roles::all_columns)
+            .order(roles::name)
+            .load::<Role>(conn)?;
+        Ok(roles)
+    }
+
+    pub async fn assign_role(
+        &self,
+        user_id: i32,
+        role: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        roles::table
+            .filter(roles::name.eq(&role))
+            .select(roles::id)
+            .first::<i32>(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Role not found",
+                    graphql_value!("role_not_found".to_string()),
+                )
+            })?;
+
+        let sql = "INSERT INTO user_roles (user_id, role_id) SELECT $1, id FROM roles WHERE name = $2 ON CONFLICT DO NOTHING";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(&role)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "User not found",
+                    graphql_value!("user_not_found".to_string()),
+                )
+            })?;
+        Ok(SuccessMessage {
+            message: "Role assigned".to_string(),
+            success: true,
+        })
+    }
+
+    pub async fn remove_role(
+        &self,
+        user_id: i32,
+        role: String,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let sql = "DELETE FROM user_roles WHERE user_id = $1 AND role_id IN (SELECT id FROM roles WHERE name = $2)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(&role)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(SuccessMessage {
+            message: "Role removed".to_string(),
+            success: true,
+        })
+    }
+}
@@ -0,0 +1,466 @@
+use crate::middlewares::auth::AuthError;
+use crate::models::refresh_tokens::RefreshToken;
+use crate::models::users::User;
+use crate::repositories::audit::{AuditRepository, IMPERSONATION_STARTED, IMPERSONATION_STOPPED};
+use crate::repositories::role::RoleRepository;
+use crate::repositories::user::{LoginResponse, SuccessMessage};
+use crate::schema::{refresh_tokens, revoked_tokens, session_revocations, users};
+use crate::utils::{
+    generate_impersonation_jwt, generate_jwt, generate_refresh_token, hash_token,
+    impersonation_ttl, refresh_token_ttl, ActionClaims, Claims, TokenError,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::{r2d2::ConnectionManager, PgConnection};
+use juniper::{graphql_value, FieldError, GraphQLObject};
+use r2d2::Pool;
+use std::sync::Arc;
+
+// a token acting as another user, there is no refresh token to extend it
+#[derive(GraphQLObject)]
+pub struct ImpersonationResponse {
+    pub token: String,
+    pub user: User,
+    pub expires_at: NaiveDateTime,
+}
+
+pub struct TokenRepository {
+    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+}
+
+// outcome of looking up a presented refresh token inside the rotation transaction
+enum Rotation {
+    Rotated { user_id: i32, refresh_token: String },
+    Reused,
+    Expired,
+    Unknown,
+}
+
+impl TokenRepository {
+    pub fn new(pool: Arc<Pool<ConnectionManager<PgConnection>>>) -> TokenRepository {
+        TokenRepository { pool }
+    }
+
+    // issue an access token and a refresh token starting a new token family
+    pub fn issue(
+        connection: &mut PgConnection,
+        user: User,
+    ) -> Result<LoginResponse, FieldError> {
+        let family_id = generate_refresh_token();
+        let refresh_token = Self::create_refresh_token(connection, user.id, &family_id)?;
+        let grants = RoleRepository::grants(connection, &user)?;
+        Ok(LoginResponse {
+            token: generate_jwt(&user.id, grants),
+            user,
+            refresh_token,
+        })
+    }
+
+    // exchange a refresh token for a new pair, presenting an already used
+    // token revokes every token of its family
+    pub async fn rotate(&self, refresh_token: String) -> Result<LoginResponse, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let token_hash = hash_token(&refresh_token);
+
+        let rotation = connection
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let result = refresh_tokens::table
+                    .filter(refresh_tokens::token_hash.eq(&token_hash))
+                    .for_update()
+                    .first::<RefreshToken>(conn)
+                    .optional()?;
+
+                let token = match result {
+                    Some(token) => token,
+                    None => return Ok(Rotation::Unknown),
+                };
+
+                if token.used_at.is_some() || token.revoked_at.is_some() {
+                    Self::revoke_family(conn, &token.family_id)?;
+                    return Ok(Rotation::Reused);
+                }
+                if token.expires_at < Utc::now().naive_utc() {
+                    return Ok(Rotation::Expired);
+                }
+
+                let sql = "UPDATE refresh_tokens SET used_at = $1 WHERE id = $2";
+                diesel::sql_query(sql)
+                    .bind::<diesel::sql_types::Timestamp, _>(Utc::now().naive_utc())
+                    .bind::<diesel::sql_types::Integer, _>(token.id)
+                    .execute(conn)?;
+
+                let refresh_token =
+                    Self::insert_refresh_token(conn, token.user_id, &token.family_id)?;
+                Ok(Rotation::Rotated {
+                    user_id: token.user_id,
+                    refresh_token,
+                })
+            })
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        match rotation {
+            Rotation::Rotated {
+                user_id,
+                refresh_token,
+            } => {
+                let user = users::table
+                    .filter(users::id.eq(user_id))
+                    .first::<User>(connection)
+                    .map_err(|_e| {
+                        FieldError::new(
+                            "User not found",
+                            graphql_value!("internal_error".to_string()),
+                        )
+                    })?;
+                let grants = RoleRepository::grants(connection, &user)?;
+                Ok(LoginResponse {
+                    token: generate_jwt(&user.id, grants),
+                    user,
+                    refresh_token,
+                })
+            }
+            Rotation::Reused => Err(FieldError::new(
+                "Refresh token reuse detected",
+                graphql_value!("refresh_token_reused".to_string()),
+            )),
+            Rotation::Expired => Err(FieldError::new(
+                "Refresh token expired",
+                graphql_value!("refresh_token_expired".to_string()),
+            )),
+            Rotation::Unknown => Err(FieldError::new(
+                "Invalid refresh token",
+                graphql_value!("invalid_refresh_token".to_string()),
+            )),
+        }
+    }
+
+    // true when the access token was logged out or issued before a
+    // "log out everywhere" of its user. iat only has whole seconds, so a
+    // token issued in the same second as the logout counts as before it
+    pub fn is_revoked(&self, claims: &Claims) -> Result<bool, AuthError> {
+        let connection = &mut *self.pool.get().map_err(|_e| AuthError::Internal)?;
+
+        let revoked = revoked_tokens::table
+            .filter(revoked_tokens::jti.eq(&claims.jti))
+            .count()
+            .get_result::<i64>(connection)
+            .map_err(|_e| AuthError::Internal)?;
+        if revoked > 0 {
+            return Ok(true);
+        }
+
+        // impersonation tokens also end when the staff member behind them
+        // logs out everywhere
+        let mut user_ids = vec![claims.user_id()];
+        user_ids.extend(claims.actor);
+        let revoked_before = session_revocations::table
+            .filter(session_revocations::user_id.eq_any(user_ids))
+            .select(diesel::dsl::max(session_revocations::revoked_before))
+            .first::<Option<NaiveDateTime>>(connection)
+            .map_err(|_e| AuthError::Internal)?;
+        Ok(revoked_before.is_some_and(|revoked_before| {
+            (claims.iat as i64) <= revoked_before.timestamp()
+        }))
+    }
+
+    // revoke the access token in use and, when given, the refresh token family
+    // it was issued with
+    pub async fn logout(
+        &self,
+        user_id: i32,
+        jti: String,
+        expires_at: usize,
+        refresh_token: Option<String>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let now = Utc::now().naive_utc();
+        let expires_at =
+            NaiveDateTime::from_timestamp_opt(expires_at as i64, 0).unwrap_or(now);
+
+        let sql = "DELETE FROM revoked_tokens WHERE expires_at < $1";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        let sql = "INSERT INTO revoked_tokens (jti, user_id, expires_at) VALUES ($1, $2, $3) ON CONFLICT (jti) DO NOTHING";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(&jti)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Timestamp, _>(expires_at)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        if let Some(refresh_token) = refresh_token {
+            let family_id = refresh_tokens::table
+                .filter(refresh_tokens::token_hash.eq(hash_token(&refresh_token)))
+                .filter(refresh_tokens::user_id.eq(user_id))
+                .select(refresh_tokens::family_id)
+                .first::<String>(connection)
+                .optional()?;
+            if let Some(family_id) = family_id {
+                Self::revoke_family(connection, &family_id)?;
+            }
+        }
+
+        Ok(SuccessMessage {
+            message: "Logged out".to_string(),
+            success: true,
+        })
+    }
+
+    // invalidate every access and refresh token issued to the user so far
+    pub async fn logout_all(&self, user_id: i32) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let now = Utc::now().naive_utc();
+
+        let sql = "INSERT INTO session_revocations (user_id, revoked_before) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET revoked_before = EXCLUDED.revoked_before";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        let sql = "UPDATE refresh_tokens SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+
+        Ok(SuccessMessage {
+            message: "Logged out of all sessions".to_string(),
+            success: true,
+        })
+    }
+
+    // let a staff member act as the user until the token expires or the
+    // impersonation is stopped, both ends are written to the audit log
+    pub async fn impersonate(
+        &self,
+        actor_id: i32,
+        user_id: i32,
+        ip: Option<String>,
+    ) -> Result<ImpersonationResponse, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        if actor_id == user_id {
+            return Err(FieldError::new(
+                "You cannot impersonate yourself",
+                graphql_value!("invalid_impersonation".to_string()),
+            ));
+        }
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .optional()?
+            .ok_or_else(|| {
+                FieldError::new(
+                    "User not found",
+                    graphql_value!("user_not_found".to_string()),
+                )
+            })?;
+        let grants = RoleRepository::grants(connection, &user)?;
+        let expires_at = Utc::now().naive_utc() + impersonation_ttl();
+        let token = generate_impersonation_jwt(&user.id, actor_id, grants);
+        AuditRepository::record(
+            connection,
+            Some(actor_id),
+            Some(user.id),
+            IMPERSONATION_STARTED,
+            Some(format!("until {}", expires_at)),
+            ip,
+        )?;
+        Ok(ImpersonationResponse {
+            token,
+            user,
+            expires_at,
+        })
+    }
+
+    // revoke the impersonation token in use
+    pub async fn stop_impersonation(
+        &self,
+        actor_id: i32,
+        user_id: i32,
+        jti: String,
+        expires_at: usize,
+        ip: Option<String>,
+    ) -> Result<SuccessMessage, FieldError> {
+        self.logout(user_id, jti, expires_at, None).await?;
+        let connection = &mut *self.pool.get()?;
+        AuditRepository::record(
+            connection,
+            Some(actor_id),
+            Some(user_id),
+            IMPERSONATION_STOPPED,
+            None,
+            ip,
+        )?;
+        Ok(SuccessMessage {
+            message: "Impersonation stopped".to_string(),
+            success: true,
+        })
+    }
+
+    // mark a single-use action token as spent, failing if it already was
+    pub fn consume_action_token(
+        connection: &mut PgConnection,
+        claims: &ActionClaims,
+    ) -> Result<(), FieldError> {
+        let now = Utc::now().naive_utc();
+        let expires_at =
+            NaiveDateTime::from_timestamp_opt(claims.exp as i64, 0).unwrap_or(now);
+
+        let sql = "INSERT INTO consumed_tokens (jti, purpose, expires_at) VALUES ($1, $2, $3) ON CONFLICT (jti) DO NOTHING";
+        let inserted = diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(&claims.jti)
+            .bind::<diesel::sql_types::Text, _>(claims.purpose.as_str())
+            .bind::<diesel::sql_types::Timestamp, _>(expires_at)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        if inserted == 0 {
+            return Err(TokenError::AlreadyUsed.into_field_error());
+        }
+
+        let sql = "DELETE FROM consumed_tokens WHERE expires_at < $1";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Timestamp, _>(now)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(())
+    }
+
+    fn create_refresh_token(
+        connection: &mut PgConnection,
+        user_id: i32,
+        family_id: &str,
+    ) -> Result<String, FieldError> {
+        Self::insert_refresh_token(connection, user_id, family_id).map_err(|_e| {
+            FieldError::new(
+                "Database error",
+                graphql_value!("internal_error".to_string()),
+            )
+        })
+    }
+
+    fn insert_refresh_token(
+        connection: &mut PgConnection,
+        user_id: i32,
+        family_id: &str,
+    ) -> Result<String, diesel::result::Error> {
+        let refresh_token = generate_refresh_token();
+        let expires_at = Utc::now().naive_utc() + refresh_token_ttl();
+        let sql = "INSERT INTO refresh_tokens (user_id, token_hash, family_id, expires_at) VALUES ($1, $2, $3, $4)";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Text, _>(hash_token(&refresh_token))
+            .bind::<diesel::sql_types::Text, _>(family_id)
+            .bind::<diesel::sql_types::Timestamp, _>(expires_at)
+            .execute(connection)?;
+        Ok(refresh_token)
+    }
+
+    fn revoke_family(
+        connection: &mut PgConnection,
+        family_id: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        let sql = "UPDATE refresh_tokens SET revoked_at = $1 WHERE family_id = $2 AND revoked_at IS NULL";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Timestamp, _>(Utc::now().naive_utc())
+            .bind::<diesel::sql_types::Text, _>(family_id)
+            .execute(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenRepository;
+    use crate::db::test_pool;
+    use crate::schema::users;
+    use crate::utils::Claims;
+    use chrono::{NaiveDateTime, Utc};
+    use diesel::prelude::*;
+
+    fn claims(user_id: i32, iat: i64) -> Claims {
+        Claims {
+            id: user_id.to_string(),
+            exp: (iat + 60) as usize,
+            iat: iat as usize,
+            jti: format!("jti-{}-{}", user_id, iat),
+            roles: Vec::new(),
+            permissions: Vec::new(),
+            actor: None,
+        }
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a migrated database in DATABASE_URL"]
+    async fn logout_all_revokes_tokens_issued_before_it() {
+        let repository = TokenRepository::new(test_pool());
+        let user_id = {
+            let connection = &mut *repository.pool.get().unwrap();
+            diesel::insert_into(users::table)
+                .values((
+                    users::username.eq("revoked"),
+                    users::email.eq("revoked@example.com"),
+                ))
+                .returning(users::id)
+                .get_result::<i32>(connection)
+                .unwrap()
+        };
+        let now = Utc::now().timestamp();
+        assert!(!repository.is_revoked(&claims(user_id, now - 10)).unwrap());
+
+        repository.logout_all(user_id).await.ok().unwrap();
+        let revoked_before = {
+            let connection = &mut *repository.pool.get().unwrap();
+            crate::schema::session_revocations::table
+                .find(user_id)
+                .select(crate::schema::session_revocations::revoked_before)
+                .first::<NaiveDateTime>(connection)
+                .unwrap()
+                .timestamp()
+        };
+        assert!(repository.is_revoked(&claims(user_id, revoked_before - 1)).unwrap());
+        // a token from the second of the logout may have been issued just
+        // before it
+        assert!(repository.is_revoked(&claims(user_id, revoked_before)).unwrap());
+        assert!(!repository.is_revoked(&claims(user_id, revoked_before + 1)).unwrap());
+    }
+}
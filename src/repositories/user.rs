@@ -1,11 +1,24 @@
+use crate::db::lower;
+use crate::models::mfa::MfaChallenge;
 use crate::models::users::User;
-use crate::models::users::{ChangePassword, UserLogin, UserRegister};
-use crate::schema::users;
-use crate::utils::{extract_email, generate_jwt, verify_token};
+use crate::models::users::{
+    validate_email, ChangePassword, UpdateProfile, UserLogin, UserRegister,
+};
+use crate::repositories::login_attempt::{LoginAttemptRepository, LoginPolicy};
+use crate::repositories::mfa::MfaRepository;
+use crate::repositories::token::TokenRepository;
+use crate::schema::{users, verification_emails};
+use crate::utils::{
+    generate_action_token, hash_password, hash_token, password_fingerprint, password_needs_rehash,
+    verify_action_token, verify_password, TokenError, TokenPurpose,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::prelude::*;
 use diesel::{r2d2::ConnectionManager, PgConnection};
-use juniper::{graphql_value, FieldError, GraphQLObject};
+use dotenvy::dotenv;
+use juniper::{graphql_value, FieldError, GraphQLObject, GraphQLUnion};
 use r2d2::Pool;
+use std::env;
 use std::sync::Arc;
 use tera::Tera;
 
@@ -20,6 +33,14 @@ pub struct LoginResponse {
     pub refresh_token: String,
 }
 
+// login either completes or asks for a second factor
+#[derive(GraphQLUnion)]
+#[allow(clippy::large_enum_variant)]
+pub enum LoginResult {
+    Success(LoginResponse),
+    MfaRequired(MfaChallenge),
+}
+
 #[derive(GraphQLObject)]
 pub struct SuccessMessage {
     pub message: String,
@@ -35,6 +56,7 @@ impl UserRepository {
         let conn = &mut *self.pool.get()?;
         let result = users::table
             .filter(users::id.eq(id))
+            .filter(users::deleted.eq(false))
             .first::<User>(conn)
             .map_err(|_e| {
                 FieldError::new(
@@ -44,9 +66,35 @@ impl UserRepository {
             })?;
         Ok(result)
     }
+    pub async fn update_profile(
+        &self,
+        user_id: i32,
+        input: UpdateProfile,
+    ) -> Result<User, FieldError> {
+        let changes = input.validate(Utc::now().naive_utc())?;
+        let connection = &mut *self.pool.get()?;
+        diesel::update(users::table)
+            .filter(users::id.eq(user_id))
+            .filter(users::deleted.eq(false))
+            .set(&changes)
+            .get_result::<User>(connection)
+            .map_err(|e| match e {
+                diesel::result::Error::NotFound => FieldError::new(
+                    "User not found",
+                    graphql_value!("user_not_found".to_string()),
+                ),
+                _ => FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                ),
+            })
+    }
+
     pub async fn all_users(&self) -> Result<Vec<User>, FieldError> {
         let conn = &mut *self.pool.get()?;
-        let users = users::table.load::<User>(conn)?;
+        let users = users::table
+            .filter(users::deleted.eq(false))
+            .load::<User>(conn)?;
         Ok(users)
     }
 
@@ -54,7 +102,7 @@ impl UserRepository {
         let mut connection = &mut *self.pool.get()?;
         user.validate(&mut connection)?;
 
-        let password = bcrypt::hash(&user.password1, 10)?;
+        let password = hash_password(&user.password1)?;
         let sql = "INSERT INTO users (username, email, password) VALUES ($1, $2, $3)";
 
         diesel::sql_query(sql)
@@ -69,18 +117,36 @@ impl UserRepository {
                 )
             })?;
 
+        Self::record_verification_email(connection, &user.email)?;
+        Self::send_verification_email(&user.username, &user.email, tera).await;
+        let result = users::table
+            .filter(users::email.eq(&user.email))
+            .first::<User>(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "User not found",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(result)
+    }
+
+    async fn send_verification_email(username: &str, email: &str, tera: Arc<Tera>) {
         let mut mail_context = tera::Context::new();
-        mail_context.insert("username", &user.username);
-        mail_context.insert("email", &user.email);
+        mail_context.insert("username", username);
+        mail_context.insert("email", email);
         mail_context.insert("domain", "http://localhost:3000");
         mail_context.insert("logo", "https://www.elegal.ascendth.com/_next/image?url=https%3A%2F%2Felegal-ascend.s3.amazonaws.com%2Fpublic%2Flogo.png&w=256&q=75");
         mail_context.insert("company", "drgz");
         mail_context.insert(
             "link",
-            &format!("http://localhost:3000/verify/{}", verify_token(&user.email)),
+            &format!(
+                "http://localhost:3000/verify/{}",
+                generate_action_token(email, TokenPurpose::VerifyEmail, None)
+            ),
         );
         crate::mailer::send_html_email(
-            &user.email,
+            email,
             "info@ascendth.com",
             "Account Activation",
             "emails/register.html",
@@ -88,8 +154,104 @@ impl UserRepository {
             tera,
         )
         .await;
+    }
+
+    fn record_verification_email(
+        connection: &mut PgConnection,
+        email: &str,
+    ) -> Result<(), FieldError> {
+        diesel::sql_query("INSERT INTO verification_emails (email) VALUES ($1)")
+            .bind::<diesel::sql_types::Text, _>(email.trim().to_lowercase())
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(())
+    }
+
+    // VERIFICATION_EMAIL_COOLDOWN seconds between emails to one address and
+    // at most VERIFICATION_EMAIL_DAILY_LIMIT of them a day. Counted by the
+    // normalized address so the answer is the same whether or not an account
+    // uses it
+    fn check_verification_email_limit(
+        connection: &mut PgConnection,
+        email: &str,
+    ) -> Result<(), FieldError> {
+        dotenv().ok();
+        let var = |name: &str, default: i64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .unwrap_or(default)
+        };
+        let cooldown = Duration::seconds(var("VERIFICATION_EMAIL_COOLDOWN", 60));
+        let daily_limit = var("VERIFICATION_EMAIL_DAILY_LIMIT", 5);
+
+        let now = Utc::now().naive_utc();
+        let sent = verification_emails::table
+            .filter(verification_emails::email.eq(email))
+            .filter(verification_emails::created_at.gt(now - Duration::days(1)))
+            .select(verification_emails::created_at)
+            .order(verification_emails::created_at.desc())
+            .load::<NaiveDateTime>(connection)?;
+        if let Some(last) = sent.first() {
+            let retry_at = *last + cooldown;
+            if retry_at > now {
+                return Err(FieldError::new(
+                    format!(
+                        "Please wait {} seconds before asking for another email",
+                        (retry_at - now).num_seconds() + 1
+                    ),
+                    graphql_value!("resend_cooldown".to_string()),
+                ));
+            }
+        }
+        if sent.len() as i64 >= daily_limit {
+            return Err(FieldError::new(
+                "Too many verification emails today, try again tomorrow",
+                graphql_value!("resend_limit_reached".to_string()),
+            ));
+        }
+        Ok(())
+    }
+
+    // send a new verification link, answering the same whether or not the
+    // address belongs to an account waiting for verification
+    pub async fn resend_verification_email(
+        &self,
+        email: String,
+        tera: Arc<Tera>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let email = email.trim().to_lowercase();
+        let connection = &mut *self.pool.get()?;
+        Self::check_verification_email_limit(connection, &email)?;
+        Self::record_verification_email(connection, &email)?;
+
+        let user = users::table
+            .filter(lower(users::email).eq(&email))
+            .filter(users::deleted.eq(false))
+            .filter(users::email_verified.eq(false))
+            .first::<User>(connection)
+            .optional()?;
+        if let Some(user) = user {
+            Self::send_verification_email(&user.username, &user.email, tera).await;
+        }
+        Ok(SuccessMessage {
+            message: "Verification email sent".to_string(),
+            success: true,
+        })
+    }
+
+    pub async fn verify_email(&self, token: String) -> Result<SuccessMessage, FieldError> {
+        let claims = verify_action_token(&token, TokenPurpose::VerifyEmail)
+            .map_err(TokenError::into_field_error)?;
+        let connection = &mut *self.pool.get()?;
         let result = users::table
-            .filter(users::email.eq(&user.email))
+            .filter(users::email.eq(&claims.email))
+            .filter(users::deleted.eq(false))
             .first::<User>(connection)
             .map_err(|_e| {
                 FieldError::new(
@@ -97,14 +259,11 @@ impl UserRepository {
                     graphql_value!("internal_error".to_string()),
                 )
             })?;
-        Ok(result)
-    }
+        TokenRepository::consume_action_token(connection, &claims)?;
 
-    pub async fn verify_email(&self, email: String) -> Result<SuccessMessage, FieldError> {
-        let connection = &mut *self.pool.get()?;
-        let sql = "UPDATE users SET email_verified = true WHERE email = $1";
+        let sql = "UPDATE users SET email_verified = true WHERE id = $1";
         diesel::sql_query(sql)
-            .bind::<diesel::sql_types::Text, _>(&email)
+            .bind::<diesel::sql_types::Integer, _>(&result.id)
             .execute(connection)
             .map_err(|_e| {
                 FieldError::new(
@@ -128,6 +287,7 @@ impl UserRepository {
         // check if user exists
         let result = users::table
             .filter(users::email.eq(&email))
+            .filter(users::deleted.eq(false))
             .first::<User>(connection)
             .optional()
             .map_err(|_e| {
@@ -154,7 +314,11 @@ impl UserRepository {
                 "link",
                 &format!(
                     "http://localhost:3000/reset-password/{}",
-                    verify_token(&user.email)
+                    generate_action_token(
+                        &user.email,
+                        TokenPurpose::ResetPassword,
+                        Some(password_fingerprint(&user.password))
+                    )
                 ),
             );
             crate::mailer::send_html_email(
@@ -171,46 +335,207 @@ impl UserRepository {
         }
     }
     // login
-    pub async fn login(&self, user: UserLogin) -> Result<LoginResponse, FieldError> {
+    pub async fn login(
+        &self,
+        user: UserLogin,
+        ip: Option<String>,
+        tera: Arc<Tera>,
+    ) -> Result<LoginResult, FieldError> {
         let connection = &mut *self.pool.get()?;
+        let policy = LoginPolicy::from_env();
+        LoginAttemptRepository::check_ip(connection, &policy, &ip)?;
+        let invalid_credentials = || {
+            FieldError::new(
+                "invalid credentials",
+                graphql_value!("internal_error".to_string()),
+            )
+        };
 
         let result = users::table
             .filter(users::email.eq(&user.email))
+            .filter(users::deleted.eq(false))
             .first::<User>(connection)
-            .map_err(|_e| {
-                FieldError::new(
-                    "invalid credentials",
-                    graphql_value!("internal_error".to_string()),
-                )
-            })?;
+            .optional()?;
+        let result = match result {
+            Some(result) => result,
+            None => {
+                LoginAttemptRepository::record_failure(
+                    connection,
+                    &policy,
+                    &user.email,
+                    &ip,
+                    None,
+                )?;
+                return Err(invalid_credentials());
+            }
+        };
+        LoginAttemptRepository::check_account(connection, &policy, &result)?;
+
+        let is_valid = verify_password(&user.password, &result.password);
+        if is_valid {
+            // only told after the password checks out, clients can offer
+            // resendVerificationEmail on this code
+            if !result.email_verified {
+                return Err(FieldError::new(
+                    "Email not verified",
+                    graphql_value!("email_not_verified".to_string()),
+                ));
+            }
+            LoginAttemptRepository::record_success(connection, &result, &ip)?;
+            let result = Self::rehash_password(connection, result, &user.password);
+            Self::complete_login(connection, result)
+        } else {
+            let locked = LoginAttemptRepository::record_failure(
+                connection,
+                &policy,
+                &user.email,
+                &ip,
+                Some(&result),
+            )?;
+            if locked {
+                LoginAttemptRepository::send_unlock_email(&result, tera).await;
+            }
+            Err(invalid_credentials())
+        }
+    }
+
+    // email a one-click sign in link, answering the same whether or not the
+    // account exists
+    pub async fn request_login_link(
+        &self,
+        email: String,
+        browser_binding: Option<String>,
+        tera: Arc<Tera>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let message = SuccessMessage {
+            message: "Sign in link sent".to_string(),
+            success: true,
+        };
+
+        let result = users::table
+            .filter(users::email.eq(&email))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .optional()?;
+        let user = match result {
+            Some(user) => user,
+            None => return Ok(message),
+        };
 
-        // check that email is verified
-        if !result.email_verified {
+        let mut mail_context = tera::Context::new();
+        mail_context.insert("username", &user.username);
+        mail_context.insert("email", &user.email);
+        mail_context.insert("domain", "http://localhost:3000");
+        mail_context.insert("logo", "https://www.elegal.ascendth.com/_next/image?url=https%3A%2F%2Felegal-ascend.s3.amazonaws.com%2Fpublic%2Flogo.png&w=256&q=75");
+        mail_context.insert("company", "drgz");
+        mail_context.insert("minutes", &TokenPurpose::LoginLink.ttl().num_minutes());
+        mail_context.insert(
+            "link",
+            &format!(
+                "http://localhost:3000/login-link/{}",
+                generate_action_token(&user.email, TokenPurpose::LoginLink, browser_binding)
+            ),
+        );
+        crate::mailer::send_html_email(
+            &user.email,
+            "info@ascendth.com",
+            "Sign in link",
+            "emails/login-link.html",
+            &mail_context,
+            tera,
+        )
+        .await;
+        Ok(message)
+    }
+
+    // sign in with a login link, a link bound to a browser only works in the
+    // browser that asked for it
+    pub async fn consume_login_link(
+        &self,
+        token: String,
+        browser_nonce: Option<String>,
+    ) -> Result<LoginResult, FieldError> {
+        let claims = verify_action_token(&token, TokenPurpose::LoginLink)
+            .map_err(TokenError::into_field_error)?;
+        if let Some(binding) = &claims.fp {
+            if browser_nonce.map(|nonce| hash_token(&nonce)).as_ref() != Some(binding) {
+                return Err(FieldError::new(
+                    "Open the sign in link in the browser it was requested from",
+                    graphql_value!("login_link_browser_mismatch".to_string()),
+                ));
+            }
+        }
+
+        let connection = &mut *self.pool.get()?;
+        let mut user = users::table
+            .filter(users::email.eq(&claims.email))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .map_err(|_e| TokenError::Invalid.into_field_error())?;
+        TokenRepository::consume_action_token(connection, &claims)?;
+
+        // following the link proves the user owns the address
+        if !user.email_verified {
+            diesel::sql_query("UPDATE users SET email_verified = true WHERE id = $1")
+                .bind::<diesel::sql_types::Integer, _>(user.id)
+                .execute(connection)
+                .map_err(|_e| {
+                    FieldError::new(
+                        "Database error",
+                        graphql_value!("internal_error".to_string()),
+                    )
+                })?;
+            user.email_verified = true;
+        }
+        Self::complete_login(connection, user)
+    }
+
+    // issue tokens for an authenticated user, or a challenge when the user
+    // has two-factor authentication enabled
+    pub fn complete_login(
+        connection: &mut PgConnection,
+        user: User,
+    ) -> Result<LoginResult, FieldError> {
+        if user.deleted {
             return Err(FieldError::new(
-                "Email not verified",
-                graphql_value!("email_error".to_string()),
+                "invalid credentials",
+                graphql_value!("invalid_credentials".to_string()),
             ));
         }
+        if MfaRepository::is_enabled(connection, user.id)? {
+            let challenge_token = generate_action_token(
+                &user.email,
+                TokenPurpose::MfaChallenge,
+                Some(password_fingerprint(&user.password)),
+            );
+            return Ok(LoginResult::MfaRequired(MfaChallenge {
+                challenge_token,
+                expires_in: TokenPurpose::MfaChallenge.ttl().num_seconds() as i32,
+            }));
+        }
+        let response = TokenRepository::issue(connection, user)?;
+        Ok(LoginResult::Success(response))
+    }
 
-        let password_str = if let Some(password) = &result.password {
-            password.clone()
-        } else {
-            "".to_string()
+    // move a verified password to the current hashing settings, keeping the
+    // old hash if anything goes wrong
+    fn rehash_password(connection: &mut PgConnection, mut user: User, password: &str) -> User {
+        if !password_needs_rehash(&user.password) {
+            return user;
+        }
+        let password_hash = match hash_password(password) {
+            Ok(password_hash) => password_hash,
+            Err(_e) => return user,
         };
-        let is_valid = bcrypt::verify(&user.password, &password_str)?;
-        if is_valid {
-            let token = generate_jwt(&result.id);
-            Ok(LoginResponse {
-                token,
-                user: result,
-                refresh_token: "".to_string(),
-            })
-        } else {
-            Err(FieldError::new(
-                "invalid credentials",
-                graphql_value!("internal_error".to_string()),
-            ))
+        let updated = diesel::sql_query("UPDATE users SET password = $1 WHERE id = $2")
+            .bind::<diesel::sql_types::Text, _>(&password_hash)
+            .bind::<diesel::sql_types::Integer, _>(user.id)
+            .execute(connection);
+        if updated.is_ok() {
+            user.password = Some(password_hash);
         }
+        user
     }
 
     pub async fn change_password(
@@ -218,10 +543,11 @@ impl UserRepository {
         input: ChangePassword,
     ) -> Result<SuccessMessage, FieldError> {
         let connection = &mut *self.pool.get()?;
-        input.validate()?;
-        let email = extract_email(&input.token);
+        let claims = verify_action_token(&input.token, TokenPurpose::ResetPassword)
+            .map_err(TokenError::into_field_error)?;
         let result = users::table
-            .filter(users::email.eq(email))
+            .filter(users::email.eq(&claims.email))
+            .filter(users::deleted.eq(false))
             .first::<User>(connection)
             .map_err(|_e| {
                 FieldError::new(
@@ -230,7 +556,14 @@ impl UserRepository {
                 )
             })?;
 
-        let password = bcrypt::hash(&input.password1, 10)?;
+        // a reset link stops working once the password it was issued for changes
+        if claims.fp.as_deref() != Some(password_fingerprint(&result.password).as_str()) {
+            return Err(TokenError::AlreadyUsed.into_field_error());
+        }
+        input.validate(&result)?;
+        TokenRepository::consume_action_token(connection, &claims)?;
+
+        let password = hash_password(&input.password1)?;
         let sql = "UPDATE users SET password = $1 WHERE id = $2";
         diesel::sql_query(sql)
             .bind::<diesel::sql_types::Text, _>(&password)
@@ -247,4 +580,115 @@ impl UserRepository {
             success: true,
         })
     }
+
+    // send a confirmation link to the new address and a notice to the current
+    // one, nothing changes until the link is used
+    pub async fn request_email_change(
+        &self,
+        user_id: i32,
+        new_email: String,
+        current_password: String,
+        tera: Arc<Tera>,
+    ) -> Result<SuccessMessage, FieldError> {
+        let connection = &mut *self.pool.get()?;
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .first::<User>(connection)?;
+        if !verify_password(&current_password, &user.password) {
+            return Err(FieldError::new(
+                "invalid credentials",
+                graphql_value!("invalid_credentials".to_string()),
+            ));
+        }
+        let new_email = new_email.trim().to_string();
+        if new_email.eq_ignore_ascii_case(&user.email) {
+            return Err(FieldError::new(
+                "New email is the same as the current one",
+                graphql_value!("email_unchanged".to_string()),
+            ));
+        }
+        validate_email(connection, &new_email)?;
+
+        // the token carries the new address, the fingerprint ties it to the
+        // user and stops it working once the password changes
+        let token = generate_action_token(
+            &new_email,
+            TokenPurpose::ChangeEmail,
+            Some(format!("{}:{}", user.id, password_fingerprint(&user.password))),
+        );
+        let mut mail_context = tera::Context::new();
+        mail_context.insert("username", &user.username);
+        mail_context.insert("email", &user.email);
+        mail_context.insert("new_email", &new_email);
+        mail_context.insert("domain", "http://localhost:3000");
+        mail_context.insert("logo", "https://www.elegal.ascendth.com/_next/image?url=https%3A%2F%2Felegal-ascend.s3.amazonaws.com%2Fpublic%2Flogo.png&w=256&q=75");
+        mail_context.insert("company", "drgz");
+        mail_context.insert(
+            "link",
+            &format!("http://localhost:3000/confirm-email-change/{}", token),
+        );
+        crate::mailer::send_html_email(
+            &new_email,
+            "info@ascendth.com",
+            "Confirm your new email address",
+            "emails/email-change.html",
+            &mail_context,
+            tera.clone(),
+        )
+        .await;
+        crate::mailer::send_html_email(
+            &user.email,
+            "info@ascendth.com",
+            "Email change requested",
+            "emails/email-change-notice.html",
+            &mail_context,
+            tera,
+        )
+        .await;
+
+        Ok(SuccessMessage {
+            message: "Confirmation sent to the new email address".to_string(),
+            success: true,
+        })
+    }
+
+    // switch to the confirmed address, which the link just proved the user
+    // controls, so it counts as verified
+    pub async fn confirm_email_change(&self, token: String) -> Result<User, FieldError> {
+        let claims = verify_action_token(&token, TokenPurpose::ChangeEmail)
+            .map_err(TokenError::into_field_error)?;
+        let connection = &mut *self.pool.get()?;
+        let (user_id, fingerprint) = claims
+            .fp
+            .as_deref()
+            .and_then(|fp| fp.split_once(':'))
+            .and_then(|(id, fingerprint)| Some((id.parse::<i32>().ok()?, fingerprint)))
+            .ok_or_else(|| TokenError::Invalid.into_field_error())?;
+        let user = users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::deleted.eq(false))
+            .first::<User>(connection)
+            .map_err(|_e| TokenError::Invalid.into_field_error())?;
+        if fingerprint != password_fingerprint(&user.password) {
+            return Err(TokenError::Invalid.into_field_error());
+        }
+        // the address may have been taken since the link was sent
+        validate_email(connection, &claims.email)?;
+        TokenRepository::consume_action_token(connection, &claims)?;
+
+        let sql = "UPDATE users SET email = $1, email_verified = true, updated_at = NOW() WHERE id = $2";
+        diesel::sql_query(sql)
+            .bind::<diesel::sql_types::Text, _>(&claims.email)
+            .bind::<diesel::sql_types::Integer, _>(user.id)
+            .execute(connection)
+            .map_err(|_e| {
+                FieldError::new(
+                    "Database error",
+                    graphql_value!("internal_error".to_string()),
+                )
+            })?;
+        Ok(users::table
+            .filter(users::id.eq(user.id))
+            .first::<User>(connection)?)
+    }
 }
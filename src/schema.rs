@@ -1,5 +1,256 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        prefix -> Varchar,
+        key_hash -> Varchar,
+        scopes -> Array<Text>,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Int4,
+        actor_id -> Nullable<Int4>,
+        user_id -> Nullable<Int4>,
+        action -> Varchar,
+        details -> Nullable<Text>,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    consumed_tokens (jti) {
+        jti -> Varchar,
+        purpose -> Varchar,
+        expires_at -> Timestamp,
+        consumed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    data_exports (id) {
+        id -> Int4,
+        user_id -> Int4,
+        requested_by -> Nullable<Int4>,
+        zipped -> Bool,
+        status -> Varchar,
+        file_path -> Nullable<Varchar>,
+        token_hash -> Nullable<Varchar>,
+        expires_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    invitations (id) {
+        id -> Int4,
+        organization_id -> Int4,
+        email -> Varchar,
+        role -> Varchar,
+        invited_by -> Nullable<Int4>,
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        accepted_at -> Nullable<Timestamp>,
+        declined_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_attempts (id) {
+        id -> Int4,
+        email -> Varchar,
+        ip -> Nullable<Varchar>,
+        succeeded -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    memberships (id) {
+        id -> Int4,
+        organization_id -> Int4,
+        user_id -> Int4,
+        role -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    mfa_challenge_failures (jti) {
+        jti -> Varchar,
+        attempts -> Int4,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    mfa_recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    oauth_states (state) {
+        state -> Varchar,
+        provider -> Varchar,
+        code_verifier -> Varchar,
+        link_user_id -> Nullable<Int4>,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        nonce_hash -> Varchar,
+    }
+}
+
+diesel::table! {
+    organizations (id) {
+        id -> Int4,
+        name -> Varchar,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    permissions (id) {
+        id -> Int4,
+        name -> Varchar,
+        description -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    privacy_settings (user_id) {
+        user_id -> Int4,
+        show_name -> Bool,
+        show_city -> Bool,
+        show_state -> Bool,
+        show_country -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    rate_limit_buckets (key) {
+        key -> Varchar,
+        tokens -> Float8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        family_id -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    revoked_tokens (jti) {
+        jti -> Varchar,
+        user_id -> Int4,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Int4,
+        permission_id -> Int4,
+    }
+}
+
+diesel::table! {
+    roles (id) {
+        id -> Int4,
+        name -> Varchar,
+        description -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    session_revocations (user_id) {
+        user_id -> Int4,
+        revoked_before -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sms_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        phone -> Varchar,
+        purpose -> Varchar,
+        code_hash -> Varchar,
+        attempts -> Int4,
+        expires_at -> Timestamp,
+        consumed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sms_sends (id) {
+        id -> Int4,
+        phone -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_mfa (user_id) {
+        user_id -> Int4,
+        secret -> Varchar,
+        enabled -> Bool,
+        last_used_step -> Nullable<Int8>,
+        confirmed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_roles (user_id, role_id) {
+        user_id -> Int4,
+        role_id -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
@@ -19,5 +270,69 @@ diesel::table! {
         is_superuser -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        failed_login_count -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        purged_at -> Nullable<Timestamp>,
+        avatar_key -> Nullable<Varchar>,
+        avatar_url -> Nullable<Varchar>,
+        avatar_thumbnail_url -> Nullable<Varchar>,
     }
 }
+
+diesel::table! {
+    verification_emails (id) {
+        id -> Int4,
+        email -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(api_keys -> users (user_id));
+diesel::joinable!(identities -> users (user_id));
+diesel::joinable!(invitations -> organizations (organization_id));
+diesel::joinable!(invitations -> users (invited_by));
+diesel::joinable!(memberships -> organizations (organization_id));
+diesel::joinable!(memberships -> users (user_id));
+diesel::joinable!(mfa_recovery_codes -> users (user_id));
+diesel::joinable!(oauth_states -> users (link_user_id));
+diesel::joinable!(organizations -> users (created_by));
+diesel::joinable!(privacy_settings -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(revoked_tokens -> users (user_id));
+diesel::joinable!(role_permissions -> permissions (permission_id));
+diesel::joinable!(role_permissions -> roles (role_id));
+diesel::joinable!(session_revocations -> users (user_id));
+diesel::joinable!(sms_codes -> users (user_id));
+diesel::joinable!(user_mfa -> users (user_id));
+diesel::joinable!(user_roles -> roles (role_id));
+diesel::joinable!(user_roles -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    api_keys,
+    audit_events,
+    consumed_tokens,
+    data_exports,
+    identities,
+    invitations,
+    login_attempts,
+    memberships,
+    mfa_challenge_failures,
+    mfa_recovery_codes,
+    oauth_states,
+    organizations,
+    permissions,
+    privacy_settings,
+    rate_limit_buckets,
+    refresh_tokens,
+    revoked_tokens,
+    role_permissions,
+    roles,
+    session_revocations,
+    sms_codes,
+    sms_sends,
+    user_mfa,
+    user_roles,
+    users,
+    verification_emails,
+);
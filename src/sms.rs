@@ -0,0 +1,165 @@
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// delivers text messages. Without a configured sender messages are only
+// printed, which is enough for development. A real gateway is plugged in by
+// calling `set_sms_sender` at startup, before anything is sent.
+#[async_trait]
+pub trait SmsSender: Send + Sync {
+    async fn send(&self, to: &str, body: &str) -> Result<(), String>;
+}
+
+// prints every message instead of sending it
+pub struct LogSender;
+
+#[async_trait]
+impl SmsSender for LogSender {
+    async fn send(&self, to: &str, body: &str) -> Result<(), String> {
+        println!("SMS to {}: {}", to, body);
+        Ok(())
+    }
+}
+
+// keeps every message so tests can read the codes back
+#[derive(Default)]
+pub struct MemorySender {
+    messages: Mutex<Vec<(String, String)>>,
+}
+
+impl MemorySender {
+    pub fn new() -> MemorySender {
+        MemorySender::default()
+    }
+
+    // (to, body) pairs, oldest first
+    pub fn messages(&self) -> Vec<(String, String)> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    pub fn last_message_to(&self, to: &str) -> Option<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(recipient, _)| recipient == to)
+            .map(|(_, body)| body.clone())
+    }
+}
+
+#[async_trait]
+impl SmsSender for MemorySender {
+    async fn send(&self, to: &str, body: &str) -> Result<(), String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .push((to.to_string(), body.to_string()));
+        Ok(())
+    }
+}
+
+static SENDER: OnceLock<Arc<dyn SmsSender>> = OnceLock::new();
+
+pub fn sms_sender() -> &'static Arc<dyn SmsSender> {
+    SENDER.get_or_init(|| Arc::new(LogSender))
+}
+
+// fails with the given sender when one is already in use
+pub fn set_sms_sender(sender: Arc<dyn SmsSender>) -> Result<(), Arc<dyn SmsSender>> {
+    SENDER.set(sender)
+}
+
+pub async fn send_sms(to: &str, body: &str) {
+    let res = sms_sender().send(to, body).await;
+
+    if res.is_err() {
+        println!("Error sending sms: {:?}", res);
+    }
+}
+
+// bring a phone number to E.164 (+ and up to 15 digits). Spaces, dashes,
+// dots and parentheses are dropped, a leading 00 is read as +, and numbers
+// without a country code get `default_country_code` in place of their trunk 0
+pub fn normalize_phone(input: &str, default_country_code: Option<&str>) -> Option<String> {
+    let stripped: String = input
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect();
+
+    let number = if let Some(rest) = stripped.strip_prefix('+') {
+        format!("+{}", rest)
+    } else if let Some(rest) = stripped.strip_prefix("00") {
+        format!("+{}", rest)
+    } else {
+        let country_code = default_country_code?.trim_start_matches('+');
+        format!("+{}{}", country_code, stripped.trim_start_matches('0'))
+    };
+
+    let digits = &number[1..];
+    let valid = (8..=15).contains(&digits.len())
+        && digits.chars().all(|c| c.is_ascii_digit())
+        && !digits.starts_with('0');
+    if valid {
+        Some(number)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_phone;
+
+    #[test]
+    fn keeps_international_numbers_and_drops_punctuation() {
+        assert_eq!(
+            normalize_phone(" +44 (20) 7946-0958 ", None).as_deref(),
+            Some("+442079460958")
+        );
+        assert_eq!(
+            normalize_phone("+1.555.010.0001", Some("44")).as_deref(),
+            Some("+15550100001")
+        );
+    }
+
+    #[test]
+    fn reads_a_leading_00_as_plus() {
+        assert_eq!(
+            normalize_phone("0044 20 7946 0958", None).as_deref(),
+            Some("+442079460958")
+        );
+    }
+
+    #[test]
+    fn replaces_the_trunk_0_with_the_default_country_code() {
+        assert_eq!(
+            normalize_phone("020 7946 0958", Some("44")).as_deref(),
+            Some("+442079460958")
+        );
+        assert_eq!(
+            normalize_phone("081 234 5678", Some("+66")).as_deref(),
+            Some("+66812345678")
+        );
+        // no country code to assume
+        assert_eq!(normalize_phone("020 7946 0958", None), None);
+    }
+
+    #[test]
+    fn checks_the_length_and_digits() {
+        // 8 to 15 digits after the +
+        assert_eq!(normalize_phone("+1234567", None), None);
+        assert_eq!(
+            normalize_phone("+12345678", None).as_deref(),
+            Some("+12345678")
+        );
+        assert_eq!(
+            normalize_phone("+123456789012345", None).as_deref(),
+            Some("+123456789012345")
+        );
+        assert_eq!(normalize_phone("+1234567890123456", None), None);
+        assert_eq!(normalize_phone("+44 20 7946 095x", None), None);
+        assert_eq!(normalize_phone("+0442079460958", None), None);
+        assert_eq!(normalize_phone("", Some("44")), None);
+    }
+}
@@ -0,0 +1,123 @@
+use super::{valid_key, Storage, StorageError};
+use actix_web::web;
+use async_trait::async_trait;
+use dotenvy::dotenv;
+use std::path::PathBuf;
+use std::{env, fs, io};
+
+// files under a directory on this server, served back by the /media route
+pub struct LocalStorage {
+    dir: PathBuf,
+    base_url: String,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>, base_url: &str) -> LocalStorage {
+        LocalStorage {
+            dir: dir.into(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    // STORAGE_DIR, default "media", and STORAGE_BASE_URL, the public address
+    // of the /media route
+    pub fn from_env() -> LocalStorage {
+        dotenv().ok();
+        let dir = env::var("STORAGE_DIR").unwrap_or_else(|_| "media".to_string());
+        let base_url = env::var("STORAGE_BASE_URL")
+            .unwrap_or_else(|_| "http://localhost:8080/media".to_string());
+        LocalStorage::new(dir, &base_url)
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        if !valid_key(key) {
+            return Err(StorageError::Io(format!("invalid key {}", key)));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+// filesystem calls block, they run on the blocking thread pool
+async fn blocking<T, F>(f: F) -> Result<T, StorageError>
+where
+    F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f)
+        .await
+        .map_err(|e| StorageError::Io(e.to_string()))?
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, body: Vec<u8>, _content_type: &str) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        blocking(move || {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, body)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let path = self.path(key)?;
+        blocking(move || match fs::read(path) {
+            Ok(body) => Ok(Some(body)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        blocking(move || match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalStorage;
+    use crate::storage::Storage;
+
+    #[actix_web::test]
+    async fn stores_reads_and_deletes_files() {
+        let dir = std::env::temp_dir().join(format!("drgz-storage-{}", std::process::id()));
+        let storage = LocalStorage::new(&dir, "http://localhost:8080/media/");
+
+        storage
+            .put("avatars/1/abc-256.png", b"png".to_vec(), "image/png")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get("avatars/1/abc-256.png").await.unwrap(),
+            Some(b"png".to_vec())
+        );
+        assert_eq!(
+            storage.url("avatars/1/abc-256.png"),
+            "http://localhost:8080/media/avatars/1/abc-256.png"
+        );
+
+        storage.delete("avatars/1/abc-256.png").await.unwrap();
+        assert_eq!(storage.get("avatars/1/abc-256.png").await.unwrap(), None);
+        // deleting again is fine
+        storage.delete("avatars/1/abc-256.png").await.unwrap();
+        assert!(storage
+            .put("../escape.png", Vec::new(), "image/png")
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
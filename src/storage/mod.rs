@@ -0,0 +1,168 @@
+use async_trait::async_trait;
+use dotenvy::dotenv;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{env, fmt};
+
+mod local;
+mod s3;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(String),
+    Request(String),
+    Status(u16, String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage io failed: {}", e),
+            StorageError::Request(e) => write!(f, "storage request failed: {}", e),
+            StorageError::Status(status, body) => {
+                write!(f, "storage answered {}: {}", status, body)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(e: reqwest::Error) -> Self {
+        StorageError::Request(e.to_string())
+    }
+}
+
+// where uploaded files are kept. Keys are relative paths like
+// "avatars/1/abc-256.png", `url` is what clients are given to fetch them
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), StorageError>;
+
+    // None when nothing is stored under the key
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    // removing a missing key is not an error
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    fn url(&self, key: &str) -> String;
+}
+
+// keeps every file in memory so tests can read uploads back
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.files.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, body: Vec<u8>, _content_type: &str) -> Result<(), StorageError> {
+        self.files.lock().unwrap().insert(key.to_string(), body);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.files.lock().unwrap().get(key).cloned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.files.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("memory://{}", key)
+    }
+}
+
+static STORAGE: OnceLock<Arc<dyn Storage>> = OnceLock::new();
+
+// STORAGE_BACKEND=local (the default) or s3, set up when the server starts.
+// Another backend is plugged in by calling `set_storage` before that
+pub fn storage() -> &'static Arc<dyn Storage> {
+    STORAGE.get_or_init(|| {
+        dotenv().ok();
+        match env::var("STORAGE_BACKEND").as_deref() {
+            Ok("s3") => Arc::new(S3Storage::from_env()),
+            Ok("local") | Err(_) => Arc::new(LocalStorage::from_env()),
+            Ok(backend) => panic!("Unknown STORAGE_BACKEND {}", backend),
+        }
+    })
+}
+
+// fails with the given storage when one is already in use
+pub fn set_storage(storage: Arc<dyn Storage>) -> Result<(), Arc<dyn Storage>> {
+    STORAGE.set(storage)
+}
+
+// keys come from our own code, but the local backend maps them onto the
+// filesystem and the media route takes them from the url
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('/')
+        && key.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+}
+
+// content type served for a stored key, from its extension
+pub fn content_type_for(key: &str) -> &'static str {
+    match key.rsplit('.').next() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::valid_key;
+
+    #[test]
+    fn keys_are_relative_paths_without_traversal() {
+        for key in ["avatars/1/abc-256.png", "a", "a/b_c.d-e"] {
+            assert!(valid_key(key), "{} should be valid", key);
+        }
+        for key in [
+            "",
+            "/etc/passwd",
+            "avatars/../../etc/passwd",
+            "avatars/./a.png",
+            "avatars//a.png",
+            "avatars/",
+            "avatars\\a.png",
+            "avatars/a b.png",
+            "avatars/a%2f.png",
+            "avatars/é.png",
+        ] {
+            assert!(!valid_key(key), "{} should be invalid", key);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+// RFC 6238 defaults understood by every authenticator app
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+// accept codes from one step before and after to allow for clock drift
+const SKEW: i64 = 1;
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let label: String =
+        url::form_urlencoded::byte_serialize(format!("{}:{}", issuer, account).as_bytes())
+            .collect();
+    let issuer: String = url::form_urlencoded::byte_serialize(issuer.as_bytes()).collect();
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label, secret, issuer, DIGITS, PERIOD
+    )
+}
+
+// returns the time step the code belongs to, callers store it so the same
+// code cannot be replayed
+pub fn verify(secret: &str, code: &str, last_used_step: Option<i64>) -> Option<i64> {
+    verify_at(secret, code, last_used_step, Utc::now().timestamp())
+}
+
+// verify against the clock at `now`, in unix seconds
+fn verify_at(secret: &str, code: &str, last_used_step: Option<i64>, now: i64) -> Option<i64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / PERIOD;
+    (current - SKEW..=current + SKEW)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| generate(&key, *step) == code)
+}
+
+fn generate(key: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, verify_at, PERIOD};
+    use data_encoding::BASE32_NOPAD;
+
+    // the SHA-1 seed of RFC 6238 appendix B
+    const KEY: &[u8] = b"12345678901234567890";
+
+    // RFC 6238 appendix B SHA-1 vectors, the last 6 of their 8 digits
+    const VECTORS: [(i64, &str); 6] = [
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1111111111, "050471"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+        (20000000000, "353130"),
+    ];
+
+    #[test]
+    fn generates_the_rfc_6238_codes() {
+        for (time, code) in VECTORS {
+            assert_eq!(generate(KEY, time / PERIOD), code, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn verifies_the_rfc_6238_codes() {
+        let secret = BASE32_NOPAD.encode(KEY);
+        for (time, code) in VECTORS {
+            assert_eq!(verify_at(&secret, code, None, time), Some(time / PERIOD));
+        }
+        // a step either side is still accepted, two are not
+        assert_eq!(
+            verify_at(&secret, "005924", None, 1234567890 + PERIOD),
+            Some(41152263)
+        );
+        assert_eq!(
+            verify_at(&secret, "005924", None, 1234567890 + 2 * PERIOD),
+            None
+        );
+        assert_eq!(verify_at(&secret, "005925", None, 1234567890), None);
+    }
+
+    #[test]
+    fn refuses_a_code_from_a_step_already_used() {
+        let secret = BASE32_NOPAD.encode(KEY);
+        let step = verify_at(&secret, "005924", None, 1234567890).unwrap();
+        assert_eq!(verify_at(&secret, "005924", Some(step), 1234567890), None);
+        // nor an older one that is still inside the skew window
+        let next = generate(KEY, step + 1);
+        let now = (step + 1) * PERIOD;
+        assert_eq!(verify_at(&secret, &next, Some(step), now), Some(step + 1));
+        assert_eq!(verify_at(&secret, "005924", Some(step + 1), now), None);
+    }
+}
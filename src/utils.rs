@@ -1,87 +1,392 @@
 use chrono::{Duration, Utc};
 use dotenvy::dotenv;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use crate::keyring::keyring;
+use crate::repositories::role::Grants;
+use jsonwebtoken::errors::ErrorKind;
+use juniper::{graphql_value, FieldError};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::env;
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    id: String, // Optional. Audience
-    exp: usize, // Required (validate_exp defaults to true in validation). Expiration time (as UTC timestamp)
+pub struct Claims {
+    pub id: String,  // Optional. Audience
+    pub exp: usize,  // Required (validate_exp defaults to true in validation). Expiration time (as UTC timestamp)
+    pub iat: usize,  // Issued at (as UTC timestamp)
+    pub jti: String, // Unique token id, used to revoke a single token
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    // staff member acting as the user, only set on impersonation tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<i32>,
 }
 
-pub fn generate_jwt(id: &i32) -> String {
+impl Claims {
+    pub fn user_id(&self) -> i32 {
+        self.id.parse::<i32>().unwrap_or(0)
+    }
+}
+
+// lifetime of access tokens in seconds, defaults to 30 minutes
+pub fn access_token_ttl() -> Duration {
+    dotenv().ok();
+    let seconds = env::var("ACCESS_TOKEN_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse::<i64>().ok())
+        .unwrap_or(60 * 30);
+    Duration::seconds(seconds)
+}
+
+// lifetime of refresh tokens in seconds, defaults to 30 days
+pub fn refresh_token_ttl() -> Duration {
+    dotenv().ok();
+    let seconds = env::var("REFRESH_TOKEN_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse::<i64>().ok())
+        .unwrap_or(60 * 60 * 24 * 30);
+    Duration::seconds(seconds)
+}
+
+// lifetime of impersonation tokens in seconds, defaults to 15 minutes
+pub fn impersonation_ttl() -> Duration {
     dotenv().ok();
-    let secret: String = env::var("SECRET_KEY").expect("JWT_SECRET must be set");
-    let issued_at = (Utc::now() + Duration::seconds(60 * 30)).timestamp() as usize;
+    let seconds = env::var("IMPERSONATION_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse::<i64>().ok())
+        .unwrap_or(60 * 15);
+    Duration::seconds(seconds)
+}
+
+pub fn generate_jwt(id: &i32, grants: Grants) -> String {
+    encode_jwt(id, None, grants, access_token_ttl())
+}
+
+// token letting the staff member `actor` act as the user, it comes without a
+// refresh token so it cannot outlive IMPERSONATION_TTL
+pub fn generate_impersonation_jwt(id: &i32, actor: i32, grants: Grants) -> String {
+    encode_jwt(id, Some(actor), grants, impersonation_ttl())
+}
+
+fn encode_jwt(id: &i32, actor: Option<i32>, grants: Grants, ttl: Duration) -> String {
+    let now = Utc::now();
     let my_claims = Claims {
-        exp: issued_at + 1800,
+        exp: (now + ttl).timestamp() as usize,
+        iat: now.timestamp() as usize,
         id: id.to_string(),
+        jti: generate_token_id(),
+        roles: grants.roles,
+        permissions: grants.permissions,
+        actor,
     };
-    let token = encode(
-        &Header::default(),
-        &my_claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    );
-    token.unwrap()
+    keyring().encode(&my_claims).unwrap()
 }
 
-pub fn get_user_id(token: &str) -> i32 {
-    dotenv().ok();
-    let secret = env::var("SECRET_KEY").expect("JWT_SECRET must be set");
-
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    );
+pub fn decode_jwt(token: &str) -> Result<Claims, TokenError> {
+    let token_data = keyring()
+        .decode::<Claims>(token)
+        .map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            _ => TokenError::Invalid,
+        })?;
 
-    match token_data {
-        Ok(data) => data.claims.id.parse::<i32>().unwrap(),
-        Err(_e) => 0,
+    if token_data.claims.id.parse::<i32>().is_err() {
+        return Err(TokenError::Invalid);
     }
+    Ok(token_data.claims)
 }
 
 // GENERATE TOKEN from email
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+    ChangeEmail,
+    MfaChallenge,
+    OAuthLogin,
+    LoginLink,
+    UnlockAccount,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::VerifyEmail => "verify_email",
+            TokenPurpose::ResetPassword => "reset_password",
+            TokenPurpose::ChangeEmail => "change_email",
+            TokenPurpose::MfaChallenge => "mfa_challenge",
+            TokenPurpose::OAuthLogin => "oauth_login",
+            TokenPurpose::LoginLink => "login_link",
+            TokenPurpose::UnlockAccount => "unlock_account",
+        }
+    }
+
+    // lifetime in seconds, overridable with e.g. RESET_PASSWORD_TOKEN_TTL
+    pub fn ttl(&self) -> Duration {
+        dotenv().ok();
+        let default = match self {
+            TokenPurpose::VerifyEmail => 60 * 60 * 24 * 7,
+            TokenPurpose::ResetPassword => 60 * 60 * 24,
+            TokenPurpose::ChangeEmail => 60 * 60 * 24,
+            TokenPurpose::MfaChallenge => 60 * 5,
+            TokenPurpose::OAuthLogin => 60 * 2,
+            TokenPurpose::LoginLink => 60 * 15,
+            TokenPurpose::UnlockAccount => 60 * 60 * 24,
+        };
+        let seconds = env::var(format!("{}_TOKEN_TTL", self.as_str().to_uppercase()))
+            .ok()
+            .and_then(|ttl| ttl.parse::<i64>().ok())
+            .unwrap_or(default);
+        Duration::seconds(seconds)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-struct VerificationToken {
-    email: String,
-    exp: usize,
+pub struct ActionClaims {
+    pub email: String,
+    pub purpose: TokenPurpose,
+    pub exp: usize,
+    pub jti: String,
+    // fingerprint the token is bound to, the password hash it was issued
+    // against or the browser that asked for a login link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fp: Option<String>,
+    // the account the token was issued to, for tokens that must not follow
+    // the email address to another account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<i32>,
 }
 
-pub fn verify_token(email: &str) -> String {
-    dotenv().ok();
-    let secret: String = env::var("SECRET_KEY").expect("JWT_SECRET must be set");
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenError {
+    Expired,
+    Invalid,
+    WrongPurpose,
+    AlreadyUsed,
+}
 
-    let issued_at = (Utc::now() + Duration::seconds(60 * 30)).timestamp() as usize;
-    // expores after 1 week
-    let my_claims = VerificationToken {
-        exp: issued_at + 604800,
+impl TokenError {
+    pub fn into_field_error(self) -> FieldError {
+        match self {
+            TokenError::Expired => FieldError::new(
+                "Token expired",
+                graphql_value!("token_expired".to_string()),
+            ),
+            TokenError::Invalid => FieldError::new(
+                "Invalid token",
+                graphql_value!("invalid_token".to_string()),
+            ),
+            TokenError::WrongPurpose => FieldError::new(
+                "Token is not valid for this action",
+                graphql_value!("invalid_token_purpose".to_string()),
+            ),
+            TokenError::AlreadyUsed => FieldError::new(
+                "Token has already been used",
+                graphql_value!("token_already_used".to_string()),
+            ),
+        }
+    }
+}
+
+pub fn generate_action_token(
+    email: &str,
+    purpose: TokenPurpose,
+    fingerprint: Option<String>,
+) -> String {
+    let my_claims = ActionClaims {
+        exp: (Utc::now() + purpose.ttl()).timestamp() as usize,
         email: email.to_string(),
+        purpose,
+        jti: generate_token_id(),
+        fp: fingerprint,
+        uid: None,
     };
 
-    let token = encode(
-        &Header::default(),
-        &my_claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    );
-    token.unwrap().to_string()
+    keyring().encode(&my_claims).unwrap()
 }
 
-// extract email from token
-pub fn extract_email(token: &str) -> String {
-    dotenv().ok();
-    let secret = env::var("SECRET_KEY").expect("JWT_SECRET must be set");
+// an action token only good for the account `user_id`
+pub fn generate_user_action_token(user_id: i32, email: &str, purpose: TokenPurpose) -> String {
+    let my_claims = ActionClaims {
+        exp: (Utc::now() + purpose.ttl()).timestamp() as usize,
+        email: email.to_string(),
+        purpose,
+        jti: generate_token_id(),
+        fp: None,
+        uid: Some(user_id),
+    };
+
+    keyring().encode(&my_claims).unwrap()
+}
 
-    let token_data = decode::<VerificationToken>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    );
+// decode an action token, rejecting tokens minted for another purpose
+pub fn verify_action_token(token: &str, purpose: TokenPurpose) -> Result<ActionClaims, TokenError> {
+    let token_data = keyring()
+        .decode::<ActionClaims>(token)
+        .map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => TokenError::Expired,
+        _ => TokenError::Invalid,
+    })?;
 
-    match token_data {
-        Ok(data) => data.claims.email.to_string(),
-        Err(_e) => "".to_string(),
+    if token_data.claims.purpose != purpose {
+        return Err(TokenError::WrongPurpose);
     }
+    Ok(token_data.claims)
 }
 
+// Argon2id cost, defaults are 16 MiB memory, 2 iterations and 1 lane.
+// argonautica only takes a power of two for the memory. PASSWORD_PEPPER is mixed into every hash as the
+// argon2 secret key and must never change once set
+struct PasswordHashing {
+    memory_kib: u32,
+    iterations: u32,
+    lanes: u32,
+    pepper: Option<String>,
+}
+
+impl PasswordHashing {
+    fn from_env() -> PasswordHashing {
+        dotenv().ok();
+        let var = |name: &str, default: u32| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u32>().ok())
+                .unwrap_or(default)
+        };
+        let config = PasswordHashing {
+            memory_kib: var("ARGON2_MEMORY_KIB", 16 * 1024),
+            iterations: var("ARGON2_ITERATIONS", 2),
+            lanes: var("ARGON2_LANES", 1),
+            pepper: env::var("PASSWORD_PEPPER")
+                .ok()
+                .filter(|pepper| !pepper.is_empty()),
+        };
+        if let Err(e) = config.validate() {
+            panic!("{}", e);
+        }
+        config
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.iterations == 0 || self.lanes == 0 {
+            return Err("ARGON2_ITERATIONS and ARGON2_LANES must be at least 1".to_string());
+        }
+        if !self.memory_kib.is_power_of_two() || self.memory_kib < 8 * self.lanes {
+            return Err(format!(
+                "ARGON2_MEMORY_KIB must be a power of two of at least 8 per lane, got {}",
+                self.memory_kib
+            ));
+        }
+        Ok(())
+    }
+
+    // the m=..,t=..,p=.. segment of hashes made with the current settings
+    fn params(&self) -> String {
+        format!(
+            "m={},t={},p={}",
+            self.memory_kib, self.iterations, self.lanes
+        )
+    }
+}
+
+// read the Argon2id settings once at startup so a bad value is reported
+// there instead of failing every registration
+pub fn check_password_hashing() {
+    PasswordHashing::from_env();
+}
+
+pub fn hash_password(password: &str) -> Result<String, argonautica::Error> {
+    let config = PasswordHashing::from_env();
+    let mut hasher = argonautica::Hasher::default();
+    hasher
+        .configure_variant(argonautica::config::Variant::Argon2id)
+        .configure_memory_size(config.memory_kib)
+        .configure_iterations(config.iterations)
+        .configure_lanes(config.lanes)
+        .configure_threads(config.lanes)
+        .with_password(password);
+    match &config.pepper {
+        Some(pepper) => hasher.with_secret_key(pepper),
+        None => hasher.opt_out_of_secret_key(true),
+    };
+    hasher.hash()
+}
+
+// accepts Argon2id hashes and the bcrypt hashes of accounts created before
+pub fn verify_password(password: &str, password_hash: &Option<String>) -> bool {
+    match password_hash {
+        Some(password_hash) if password_hash.starts_with("$argon2") => {
+            let config = PasswordHashing::from_env();
+            let mut verifier = argonautica::Verifier::default();
+            verifier.with_hash(password_hash).with_password(password);
+            if let Some(pepper) = &config.pepper {
+                verifier.with_secret_key(pepper);
+            }
+            verifier.verify().unwrap_or(false)
+        }
+        Some(password_hash) => bcrypt::verify(password, password_hash).unwrap_or(false),
+        None => false,
+    }
+}
+
+// true for legacy bcrypt hashes and Argon2id hashes made with other costs,
+// callers rehash once they know the password
+pub fn password_needs_rehash(password_hash: &Option<String>) -> bool {
+    match password_hash {
+        Some(password_hash) => {
+            let params = PasswordHashing::from_env().params();
+            !(password_hash.starts_with("$argon2id$")
+                && password_hash.split('$').any(|segment| segment == params))
+        }
+        None => false,
+    }
+}
+
+// short digest of a stored password hash, changes whenever the password does
+pub fn password_fingerprint(password_hash: &Option<String>) -> String {
+    let password_hash = password_hash.as_deref().unwrap_or("");
+    hash_token(password_hash)[..16].to_string()
+}
+
+// opaque random token handed to clients, only its hash is stored
+pub fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn generate_token_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PasswordHashing;
+
+    fn hashing(memory_kib: u32, lanes: u32) -> PasswordHashing {
+        PasswordHashing {
+            memory_kib,
+            iterations: 2,
+            lanes,
+            pepper: None,
+        }
+    }
+
+    #[test]
+    fn argon2_memory_must_be_a_power_of_two() {
+        assert!(hashing(16 * 1024, 1).validate().is_ok());
+        assert!(hashing(32 * 1024, 4).validate().is_ok());
+        assert!(hashing(19 * 1024, 1).validate().is_err());
+        assert!(hashing(16, 4).validate().is_err());
+        assert!(hashing(16 * 1024, 0).validate().is_err());
+    }
+}